@@ -0,0 +1,2122 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::auth::Auth;
+use crate::proxy_common::{check_prefix_dir, hostname, unix_ts, ProxyErr};
+use crate::proxywireprotocol::{CounterSnapshot, CounterType, JobDesc};
+
+/***************
+ * ALARM RULES *
+ ***************/
+
+/// Window in seconds used by rate() and deriv() when none is given
+const DEFAULT_ALARM_WINDOW: f64 = 60.0;
+
+/// Parse a duration such as 500ms, 30s, 5m, 2h or 1d
+/// a number without unit is a duration in seconds
+pub(crate) fn parse_duration(sdur: &str) -> Result<f64, ProxyErr> {
+    let sdur = sdur.trim();
+    let split = sdur
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(sdur.len());
+    let (value, unit) = sdur.split_at(split);
+
+    let value = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| ProxyErr::new(format!("Failed to parse duration '{}' : {}", sdur, e)))?;
+
+    let scale = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => {
+            return Err(ProxyErr::new(format!(
+                "Bad duration unit '{}' in '{}' only has ms, s, m, h and d",
+                unit, sdur
+            )));
+        }
+    };
+
+    Ok(value * scale)
+}
+
+/// Durations may be given in JSON either as a number of seconds or as a string (eg "30s")
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Duration {
+        Seconds(f64),
+        Text(String),
+    }
+
+    match Duration::deserialize(deserializer)? {
+        Duration::Seconds(v) => Ok(v),
+        Duration::Text(s) => parse_duration(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Severities are ordered from the least to the most severe
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AlarmSeverity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for AlarmSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Warning => write!(f, "warning"),
+            Self::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// State of an alarm, an alarm is pending when its rule holds
+/// but for less than its 'for' duration
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AlarmState {
+    Inactive,
+    Pending,
+    Firing,
+}
+
+impl fmt::Display for AlarmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inactive => write!(f, "INACTIVE"),
+            Self::Pending => write!(f, "PENDING"),
+            Self::Firing => write!(f, "FIRING"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) enum AlarmOperator {
+    Equal(f64),
+    NotEqual(f64),
+    Less(f64),
+    LessEqual(f64),
+    More(f64),
+    MoreEqual(f64),
+}
+
+impl AlarmOperator {
+    fn new(op: &str, val: f64) -> Result<AlarmOperator, ProxyErr> {
+        match op {
+            "=" | "==" => Ok(Self::Equal(val)),
+            "!=" => Ok(Self::NotEqual(val)),
+            "<" => Ok(Self::Less(val)),
+            "<=" => Ok(Self::LessEqual(val)),
+            ">" => Ok(Self::More(val)),
+            ">=" => Ok(Self::MoreEqual(val)),
+            _ => Err(ProxyErr::new(format!(
+                "No operator for {} only has =, !=, <, <=, > and >=",
+                op
+            ))),
+        }
+    }
+
+    fn apply(&self, value: f64) -> bool {
+        match self {
+            Self::Equal(v) => *v == value,
+            Self::NotEqual(v) => *v != value,
+            Self::Less(v) => value < *v,
+            Self::LessEqual(v) => value <= *v,
+            Self::More(v) => value > *v,
+            Self::MoreEqual(v) => value >= *v,
+        }
+    }
+}
+
+impl fmt::Display for AlarmOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self {
+            Self::Equal(v) => write!(f, "= {}", *v),
+            Self::NotEqual(v) => write!(f, "!= {}", *v),
+            Self::Less(v) => write!(f, "< {}", *v),
+            Self::LessEqual(v) => write!(f, "<= {}", *v),
+            Self::More(v) => write!(f, "> {}", *v),
+            Self::MoreEqual(v) => write!(f, ">= {}", *v),
+        }
+    }
+}
+
+/// Sliding window of samples of a metric referenced by an alarm
+struct AlarmMetric {
+    counter: Arc<RwLock<CounterSnapshot>>,
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl AlarmMetric {
+    fn new(counter: Arc<RwLock<CounterSnapshot>>) -> AlarmMetric {
+        AlarmMetric {
+            counter,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn sample(&mut self, now: f64, horizon: f64) {
+        let value = self.counter.read().unwrap().ctype.value();
+
+        /* Gauges without hits are NaN do not record them */
+        if value.is_finite() {
+            self.samples.push_back((now, value));
+        }
+
+        while self.samples.len() > 1 {
+            match self.samples.front() {
+                Some((ts, _)) if *ts < now - horizon => {
+                    self.samples.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn window(&self, window: f64) -> Vec<(f64, f64)> {
+        let last = match self.samples.back() {
+            Some((ts, _)) => *ts,
+            None => return Vec::new(),
+        };
+
+        self.samples
+            .iter()
+            .filter(|(ts, _)| *ts >= last - window)
+            .cloned()
+            .collect()
+    }
+
+    fn last(&self) -> Option<f64> {
+        self.samples.back().map(|(_, v)| *v)
+    }
+
+    /// Counters accumulate over the run of a job unlike gauges
+    fn is_counter(&self) -> bool {
+        matches!(
+            self.counter.read().unwrap().ctype,
+            CounterType::Counter { .. }
+        )
+    }
+
+    /// Per-second increase over the window, a decrease is seen as a counter reset
+    fn rate(&self, window: f64) -> Option<f64> {
+        let samples = self.window(window);
+
+        if samples.len() < 2 {
+            return None;
+        }
+
+        let mut increase = 0.0;
+
+        for i in 1..samples.len() {
+            let (prev, cur) = (samples[i - 1].1, samples[i].1);
+            increase += if cur < prev { cur } else { cur - prev };
+        }
+
+        let elapsed = samples[samples.len() - 1].0 - samples[0].0;
+
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        Some(increase / elapsed)
+    }
+
+    /// Least-square slope per second over the window
+    fn deriv(&self, window: f64) -> Option<f64> {
+        let samples = self.window(window);
+
+        if samples.len() < 2 {
+            return None;
+        }
+
+        let n = samples.len() as f64;
+        let mean_ts: f64 = samples.iter().map(|(ts, _)| ts).sum::<f64>() / n;
+        let mean_val: f64 = samples.iter().map(|(_, v)| v).sum::<f64>() / n;
+
+        let mut num = 0.0;
+        let mut denom = 0.0;
+
+        for (ts, v) in samples.iter() {
+            num += (ts - mean_ts) * (v - mean_val);
+            denom += (ts - mean_ts) * (ts - mean_ts);
+        }
+
+        if denom == 0.0 {
+            return None;
+        }
+
+        Some(num / denom)
+    }
+}
+
+type Prediction<'a> = Box<dyn Fn(&String) -> Option<f64> + 'a>;
+
+/// Expected values of the metrics of a job from the Extra-P
+/// models of its command evaluated at its size
+pub(crate) struct AlarmModel<'a> {
+    /// Final value of a metric predicted for the job
+    predict: Prediction<'a>,
+    /// Time since the start of the job in milliseconds
+    elapsed: f64,
+}
+
+impl<'a> AlarmModel<'a> {
+    pub(crate) fn new<F>(predict: F, elapsed: f64) -> AlarmModel<'a>
+    where
+        F: Fn(&String) -> Option<f64> + 'a,
+    {
+        AlarmModel {
+            predict: Box::new(predict),
+            elapsed,
+        }
+    }
+
+    /// Expected value of a metric at this point of the run, counters
+    /// are scaled by the elapsed fraction of the predicted walltime
+    fn expected(&self, metric: &String, cumulative: bool) -> Option<f64> {
+        let total = (self.predict)(metric)?;
+
+        if !cumulative {
+            return Some(total);
+        }
+
+        let walltime = (self.predict)(&"walltime".to_string())?;
+
+        if walltime <= 0.0 {
+            return None;
+        }
+
+        Some(total * f64::min(self.elapsed / walltime, 1.0))
+    }
+}
+
+/// Source of the value in an alarm condition
+#[derive(Clone, Debug)]
+enum AlarmOperand {
+    /// Current value of the metric
+    Value(String),
+    /// rate(metric, window) per-second increase of a counter
+    Rate { metric: String, window: f64 },
+    /// deriv(metric, window) slope of a gauge
+    Deriv { metric: String, window: f64 },
+    /// deviation(metric) ratio between the metric and its Extra-P prediction
+    Deviation(String),
+}
+
+impl AlarmOperand {
+    fn metric(&self) -> &String {
+        match self {
+            Self::Value(metric) => metric,
+            Self::Rate { metric, .. } => metric,
+            Self::Deriv { metric, .. } => metric,
+            Self::Deviation(metric) => metric,
+        }
+    }
+
+    fn window(&self) -> f64 {
+        match self {
+            Self::Value(_) | Self::Deviation(_) => 0.0,
+            Self::Rate { window, .. } => *window,
+            Self::Deriv { window, .. } => *window,
+        }
+    }
+
+    fn eval(
+        &self,
+        metrics: &HashMap<String, AlarmMetric>,
+        model: Option<&AlarmModel>,
+    ) -> Option<f64> {
+        let m = metrics.get(self.metric())?;
+
+        match self {
+            Self::Value(_) => m.last(),
+            Self::Rate { window, .. } => m.rate(*window),
+            Self::Deriv { window, .. } => m.deriv(*window),
+            Self::Deviation(metric) => {
+                let expected = model?.expected(metric, m.is_counter())?;
+                if expected <= 0.0 {
+                    return None;
+                }
+                Some(m.last()? / expected)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum AlarmExpr {
+    Condition {
+        operand: AlarmOperand,
+        op: AlarmOperator,
+    },
+    Not(Box<AlarmExpr>),
+    And(Box<AlarmExpr>, Box<AlarmExpr>),
+    Or(Box<AlarmExpr>, Box<AlarmExpr>),
+}
+
+impl AlarmExpr {
+    /// Whether the rule holds, None when it is unknown as an operand has no
+    /// sample or no prediction: "not unknown" is unknown, "false and unknown"
+    /// is false and "true or unknown" is true
+    fn eval(
+        &self,
+        metrics: &HashMap<String, AlarmMetric>,
+        model: Option<&AlarmModel>,
+    ) -> Option<bool> {
+        match self {
+            Self::Condition { operand, op } => operand.eval(metrics, model).map(|v| op.apply(v)),
+            Self::Not(e) => e.eval(metrics, model).map(|v| !v),
+            Self::And(a, b) => match (a.eval(metrics, model), b.eval(metrics, model)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Self::Or(a, b) => match (a.eval(metrics, model), b.eval(metrics, model)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        }
+    }
+
+    /// Operands in their order of appearance in the rule
+    fn operands<'a>(&'a self, out: &mut Vec<&'a AlarmOperand>) {
+        match self {
+            Self::Condition { operand, .. } => out.push(operand),
+            Self::Not(e) => e.operands(out),
+            Self::And(a, b) | Self::Or(a, b) => {
+                a.operands(out);
+                b.operands(out);
+            }
+        }
+    }
+
+    fn parse(rule: &str) -> Result<AlarmExpr, ProxyErr> {
+        let tokens = AlarmToken::tokenize(rule)?;
+        let mut parser = AlarmParser { tokens, pos: 0 };
+
+        let expr = parser.parse_or()?;
+
+        if let Some(t) = parser.peek() {
+            return Err(ProxyErr::new(format!(
+                "Unexpected {:?} at the end of rule '{}'",
+                t, rule
+            )));
+        }
+
+        Ok(expr)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AlarmToken {
+    Ident(String),
+    Number(f64),
+    Duration(f64),
+    Compare(String),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl AlarmToken {
+    fn is_ident_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '.' | '-')
+    }
+
+    /// Read a metric name including its {label="value"} part if any
+    fn read_ident(chars: &[char], mut i: usize) -> Result<(String, usize), ProxyErr> {
+        let start = i;
+
+        while i < chars.len() && AlarmToken::is_ident_char(chars[i]) {
+            i += 1;
+        }
+
+        if i < chars.len() && chars[i] == '{' {
+            let mut in_quote = false;
+            loop {
+                i += 1;
+                match chars.get(i) {
+                    None => {
+                        return Err(ProxyErr::new("Unmatched '{' in alarm rule"));
+                    }
+                    Some('\\') if in_quote => {
+                        i += 1;
+                    }
+                    Some('"') => in_quote = !in_quote,
+                    Some('}') if !in_quote => {
+                        i += 1;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok((chars[start..i].iter().collect(), i))
+    }
+
+    fn read_number(chars: &[char], mut i: usize) -> Result<(AlarmToken, usize), ProxyErr> {
+        let start = i;
+
+        if matches!(chars[i], '-' | '+') {
+            i += 1;
+        }
+
+        while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+            i += 1;
+        }
+
+        /* Exponent */
+        if i + 1 < chars.len()
+            && matches!(chars[i], 'e' | 'E')
+            && (chars[i + 1].is_ascii_digit() || matches!(chars[i + 1], '-' | '+'))
+        {
+            i += 2;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+
+        let number: String = chars[start..i].iter().collect();
+
+        /* A unit makes it a duration */
+        let unit_start = i;
+        while i < chars.len() && chars[i].is_ascii_alphabetic() {
+            i += 1;
+        }
+
+        if unit_start != i {
+            let duration: String = chars[start..i].iter().collect();
+            return Ok((AlarmToken::Duration(parse_duration(&duration)?), i));
+        }
+
+        let value = number
+            .parse::<f64>()
+            .map_err(|e| ProxyErr::new(format!("Failed to parse number '{}' : {}", number, e)))?;
+
+        Ok((AlarmToken::Number(value), i))
+    }
+
+    fn tokenize(rule: &str) -> Result<Vec<AlarmToken>, ProxyErr> {
+        let chars: Vec<char> = rule.chars().collect();
+        let mut ret: Vec<AlarmToken> = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).cloned().unwrap_or(' ');
+
+            match c {
+                ' ' | '\t' | '\n' | '\r' => {
+                    i += 1;
+                }
+                '(' => {
+                    ret.push(AlarmToken::LParen);
+                    i += 1;
+                }
+                ')' => {
+                    ret.push(AlarmToken::RParen);
+                    i += 1;
+                }
+                ',' => {
+                    ret.push(AlarmToken::Comma);
+                    i += 1;
+                }
+                '&' if next == '&' => {
+                    ret.push(AlarmToken::And);
+                    i += 2;
+                }
+                '|' if next == '|' => {
+                    ret.push(AlarmToken::Or);
+                    i += 2;
+                }
+                '!' if next == '=' => {
+                    ret.push(AlarmToken::Compare("!=".to_string()));
+                    i += 2;
+                }
+                '!' => {
+                    ret.push(AlarmToken::Not);
+                    i += 1;
+                }
+                '<' | '>' | '=' => {
+                    if next == '=' {
+                        ret.push(AlarmToken::Compare(format!("{}=", c)));
+                        i += 2;
+                    } else {
+                        ret.push(AlarmToken::Compare(c.to_string()));
+                        i += 1;
+                    }
+                }
+                '0'..='9' | '.' | '-' | '+' => {
+                    let (tok, end) = AlarmToken::read_number(&chars, i)?;
+                    ret.push(tok);
+                    i = end;
+                }
+                _ if AlarmToken::is_ident_char(c) => {
+                    let (ident, end) = AlarmToken::read_ident(&chars, i)?;
+                    ret.push(match ident.as_str() {
+                        "and" => AlarmToken::And,
+                        "or" => AlarmToken::Or,
+                        "not" => AlarmToken::Not,
+                        _ => AlarmToken::Ident(ident),
+                    });
+                    i = end;
+                }
+                _ => {
+                    return Err(ProxyErr::new(format!(
+                        "Unexpected character '{}' in alarm rule '{}'",
+                        c, rule
+                    )));
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Recursive descent parser for alarm rules:
+///
+/// ```text
+/// expr      := and ( ("||" | "or") and )*
+/// and       := unary ( ("&&" | "and") unary )*
+/// unary     := ("!" | "not") unary | "(" expr ")" | condition
+/// condition := operand ("=" | "==" | "!=" | "<" | "<=" | ">" | ">=") number
+/// operand   := metric | rate(metric [, duration]) | deriv(metric [, duration]) | deviation(metric)
+/// metric    := name [ "{" label ("=" | "!=" | "=~" | "!~") "\"" value "\"" , ... "}" ]
+/// ```
+struct AlarmParser {
+    tokens: Vec<AlarmToken>,
+    pos: usize,
+}
+
+impl AlarmParser {
+    fn peek(&self) -> Option<&AlarmToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<AlarmToken, ProxyErr> {
+        let ret = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(ProxyErr::new("Unexpected end of alarm rule"))?;
+        self.pos += 1;
+        Ok(ret)
+    }
+
+    fn expect(&mut self, tok: AlarmToken) -> Result<(), ProxyErr> {
+        let next = self.next()?;
+        if next != tok {
+            return Err(ProxyErr::new(format!(
+                "Expected {:?} got {:?} in alarm rule",
+                tok, next
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<AlarmExpr, ProxyErr> {
+        let mut left = self.parse_and()?;
+
+        while self.peek() == Some(&AlarmToken::Or) {
+            self.pos += 1;
+            let right = self.parse_and()?;
+            left = AlarmExpr::Or(Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<AlarmExpr, ProxyErr> {
+        let mut left = self.parse_unary()?;
+
+        while self.peek() == Some(&AlarmToken::And) {
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = AlarmExpr::And(Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<AlarmExpr, ProxyErr> {
+        match self.peek() {
+            Some(AlarmToken::Not) => {
+                self.pos += 1;
+                Ok(AlarmExpr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(AlarmToken::LParen) => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                self.expect(AlarmToken::RParen)?;
+                Ok(expr)
+            }
+            _ => self.parse_condition(),
+        }
+    }
+
+    fn parse_condition(&mut self) -> Result<AlarmExpr, ProxyErr> {
+        let operand = self.parse_operand()?;
+
+        let op = match self.next()? {
+            AlarmToken::Compare(op) => op,
+            t => {
+                return Err(ProxyErr::new(format!(
+                    "Expected a comparison after {} got {:?}",
+                    operand.metric(),
+                    t
+                )));
+            }
+        };
+
+        let value = match self.next()? {
+            AlarmToken::Number(v) => v,
+            t => {
+                return Err(ProxyErr::new(format!(
+                    "Expected a number after {} {} got {:?}",
+                    operand.metric(),
+                    op,
+                    t
+                )));
+            }
+        };
+
+        Ok(AlarmExpr::Condition {
+            operand,
+            op: AlarmOperator::new(&op, value)?,
+        })
+    }
+
+    fn parse_operand(&mut self) -> Result<AlarmOperand, ProxyErr> {
+        let name = match self.next()? {
+            AlarmToken::Ident(name) => name,
+            t => {
+                return Err(ProxyErr::new(format!("Expected a metric got {:?}", t)));
+            }
+        };
+
+        let is_call = self.peek() == Some(&AlarmToken::LParen);
+
+        if !is_call || !matches!(name.as_str(), "rate" | "deriv" | "deviation") {
+            return Ok(AlarmOperand::Value(name));
+        }
+
+        self.pos += 1;
+
+        let metric = match self.next()? {
+            AlarmToken::Ident(metric) => metric,
+            t => {
+                return Err(ProxyErr::new(format!(
+                    "Expected a metric in {}() got {:?}",
+                    name, t
+                )));
+            }
+        };
+
+        if name == "deviation" {
+            self.expect(AlarmToken::RParen)?;
+            return Ok(AlarmOperand::Deviation(metric));
+        }
+
+        let window = if self.peek() == Some(&AlarmToken::Comma) {
+            self.pos += 1;
+            match self.next()? {
+                AlarmToken::Duration(v) | AlarmToken::Number(v) => v,
+                t => {
+                    return Err(ProxyErr::new(format!(
+                        "Expected a duration in {}() got {:?}",
+                        name, t
+                    )));
+                }
+            }
+        } else {
+            DEFAULT_ALARM_WINDOW
+        };
+
+        self.expect(AlarmToken::RParen)?;
+
+        if name == "rate" {
+            Ok(AlarmOperand::Rate { metric, window })
+        } else {
+            Ok(AlarmOperand::Deriv { metric, window })
+        }
+    }
+}
+
+/*******************
+ * SERIES SELECTOR *
+ *******************/
+
+#[derive(Clone, Debug)]
+enum LabelMatcher {
+    Equal(String),
+    NotEqual(String),
+    Match(Regex),
+    NotMatch(Regex),
+}
+
+impl LabelMatcher {
+    fn new(op: &str, value: String) -> Result<LabelMatcher, ProxyErr> {
+        /* Regular expressions are anchored as in Prometheus */
+        let regex = |v: &str| {
+            Regex::new(&format!("^(?:{})$", v))
+                .map_err(|e| ProxyErr::new(format!("Bad label regex '{}' : {}", v, e)))
+        };
+
+        match op {
+            "=" => Ok(LabelMatcher::Equal(value)),
+            "!=" => Ok(LabelMatcher::NotEqual(value)),
+            "=~" => Ok(LabelMatcher::Match(regex(&value)?)),
+            "!~" => Ok(LabelMatcher::NotMatch(regex(&value)?)),
+            _ => Err(ProxyErr::new(format!("Bad label matcher '{}'", op))),
+        }
+    }
+
+    /// A missing label matches as an empty value
+    fn matches(&self, value: Option<&String>) -> bool {
+        let value = value.map(|v| v.as_str()).unwrap_or("");
+
+        match self {
+            LabelMatcher::Equal(v) => v == value,
+            LabelMatcher::NotEqual(v) => v != value,
+            LabelMatcher::Match(r) => r.is_match(value),
+            LabelMatcher::NotMatch(r) => !r.is_match(value),
+        }
+    }
+}
+
+/// Select the series of a basename from their labels
+/// for example proxy_disk_usage_percent{mountpoint=~"/scratch.*"}
+#[derive(Clone, Debug)]
+pub(crate) struct MetricSelector {
+    basename: String,
+    matchers: Vec<(String, LabelMatcher)>,
+}
+
+impl MetricSelector {
+    /// Parse the label="value" list of a series, the
+    /// operators are returned alongside labels and values
+    fn parse_labels(labels: &str) -> Result<Vec<(String, String, String)>, ProxyErr> {
+        let chars: Vec<char> = labels.chars().collect();
+        let mut ret: Vec<(String, String, String)> = Vec::new();
+        let mut i = 0;
+
+        let skip_blanks = |i: &mut usize| {
+            while *i < chars.len() && (chars[*i].is_whitespace() || chars[*i] == ',') {
+                *i += 1;
+            }
+        };
+
+        loop {
+            skip_blanks(&mut i);
+
+            if i == chars.len() {
+                break;
+            }
+
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let label: String = chars[start..i].iter().collect();
+
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+
+            let start = i;
+            while i < chars.len() && matches!(chars[i], '=' | '!' | '~') {
+                i += 1;
+            }
+            let op: String = chars[start..i].iter().collect();
+
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+
+            if label.is_empty() || chars.get(i) != Some(&'"') {
+                return Err(ProxyErr::new(format!("Bad label list '{}'", labels)));
+            }
+
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ProxyErr::new(format!("Unmatched quote in '{}'", labels)));
+                    }
+                    Some('\\') => {
+                        if let Some(c) = chars.get(i + 1) {
+                            value.push(*c);
+                        }
+                        i += 2;
+                    }
+                    Some('"') => {
+                        i += 1;
+                        break;
+                    }
+                    Some(c) => {
+                        value.push(*c);
+                        i += 1;
+                    }
+                }
+            }
+
+            ret.push((label, op, value));
+        }
+
+        Ok(ret)
+    }
+
+    /// Split a series name in its basename and label list
+    fn split(name: &str) -> Result<(&str, &str), ProxyErr> {
+        match name.find('{') {
+            Some(start) => {
+                if !name.ends_with('}') {
+                    return Err(ProxyErr::new(format!("Unmatched '{{' in '{}'", name)));
+                }
+                Ok((&name[..start], &name[start + 1..name.len() - 1]))
+            }
+            None => Ok((name, "")),
+        }
+    }
+
+    pub(crate) fn parse(selector: &str) -> Result<MetricSelector, ProxyErr> {
+        let (basename, labels) = MetricSelector::split(selector.trim())?;
+
+        let matchers = MetricSelector::parse_labels(labels)?
+            .into_iter()
+            .map(|(label, op, value)| Ok((label, LabelMatcher::new(&op, value)?)))
+            .collect::<Result<Vec<(String, LabelMatcher)>, ProxyErr>>()?;
+
+        Ok(MetricSelector {
+            basename: basename.to_string(),
+            matchers,
+        })
+    }
+
+    /// Split a series in its basename and its labels
+    pub(crate) fn parse_series(series: &str) -> Result<(String, Vec<(String, String)>), ProxyErr> {
+        let (basename, labels) = MetricSelector::split(series.trim())?;
+
+        let labels = MetricSelector::parse_labels(labels)?
+            .into_iter()
+            .map(|(k, _, v)| (k, v))
+            .collect();
+
+        Ok((basename.to_string(), labels))
+    }
+
+    pub(crate) fn basename(&self) -> &String {
+        &self.basename
+    }
+
+    pub(crate) fn matches(&self, series: &str) -> bool {
+        let (basename, labels) = match MetricSelector::split(series) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+
+        if basename != self.basename {
+            return false;
+        }
+
+        let labels: HashMap<String, String> = match MetricSelector::parse_labels(labels) {
+            Ok(l) => l.into_iter().map(|(k, _, v)| (k, v)).collect(),
+            Err(_) => return false,
+        };
+
+        self.matchers
+            .iter()
+            .all(|(label, m)| m.matches(labels.get(label)))
+    }
+}
+
+/// An alarm which metric does not designate a single series, it is
+/// fanned out as one alarm for each current and future matching series
+pub(crate) struct FanoutAlarm {
+    spec: AlarmSpec,
+    /// The metric of the rule used as selector
+    metric: String,
+    selector: MetricSelector,
+    /// Series with an alarm
+    bound: HashSet<String>,
+}
+
+impl FanoutAlarm {
+    /// An alarm is fanned out when only one of its metrics is not an existing series
+    pub(crate) fn new<F>(spec: &AlarmSpec, exists: F) -> Result<Option<FanoutAlarm>, ProxyErr>
+    where
+        F: Fn(&String) -> bool,
+    {
+        let missing: Vec<String> = spec.metrics()?.into_iter().filter(|m| !exists(m)).collect();
+
+        if missing.len() != 1 {
+            return Ok(None);
+        }
+
+        let metric = missing[0].to_string();
+        let selector = MetricSelector::parse(&metric)?;
+
+        Ok(Some(FanoutAlarm {
+            spec: spec.clone(),
+            metric,
+            selector,
+            bound: HashSet::new(),
+        }))
+    }
+
+    pub(crate) fn spec(&self) -> &AlarmSpec {
+        &self.spec
+    }
+
+    pub(crate) fn basename(&self) -> &String {
+        self.selector.basename()
+    }
+
+    /// Name of the alarm for a given series
+    fn child_name(&self, series: &String) -> String {
+        if series == &self.metric {
+            return self.spec.name.to_string();
+        }
+
+        match series.find('{') {
+            Some(start) => format!("{}{}", self.spec.name, &series[start..]),
+            None => self.spec.name.to_string(),
+        }
+    }
+
+    pub(crate) fn children(&self) -> Vec<String> {
+        self.bound.iter().map(|s| self.child_name(s)).collect()
+    }
+
+    /// Create alarms for the matching series not yet bound
+    pub(crate) fn expand<F>(
+        &mut self,
+        series: Vec<(String, Arc<RwLock<CounterSnapshot>>)>,
+        resolve: F,
+    ) -> Vec<(String, ValueAlarm)>
+    where
+        F: Fn(&String) -> Result<Arc<RwLock<CounterSnapshot>>, ProxyErr>,
+    {
+        let mut ret: Vec<(String, ValueAlarm)> = Vec::new();
+
+        for (name, counter) in series {
+            if self.bound.contains(&name) || !self.selector.matches(&name) {
+                continue;
+            }
+
+            let mut spec = self.spec.clone();
+            spec.name = self.child_name(&name);
+
+            let alarm = ValueAlarm::new(spec, |m| {
+                if m == &self.metric {
+                    Ok(counter.clone())
+                } else {
+                    resolve(m)
+                }
+            });
+
+            match alarm {
+                Ok(mut alarm) => {
+                    alarm.series = Some(name.to_string());
+                    ret.push((alarm.spec.name.to_string(), alarm));
+                    self.bound.insert(name);
+                }
+                Err(e) => log::debug!("Cannot bind {} to {} : {}", self.spec.name, name, e),
+            }
+        }
+
+        ret
+    }
+}
+
+/******************
+ * ALARM INSTANCE *
+ ******************/
+
+/// Definition of an alarm as posted to /alarms/add
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AlarmSpec {
+    pub(crate) name: String,
+    /// Rule firing the alarm (eg "proxy_cpu_load_average_percent > 90")
+    pub(crate) rule: String,
+    /// How long in seconds the rule must hold before firing
+    #[serde(default, rename = "for", deserialize_with = "deserialize_duration")]
+    pub(crate) pending: f64,
+    /// Optionnal rule clearing a firing alarm (hysteresis)
+    /// if not set the alarm clears as soon as the rule does not hold
+    #[serde(default)]
+    pub(crate) clear: Option<String>,
+    #[serde(default)]
+    pub(crate) severity: AlarmSeverity,
+}
+
+impl AlarmSpec {
+    /// Rule for the legacy metric / operation / value alarm definition
+    pub(crate) fn threshold_rule(metric: &str, op: &str, value: f64) -> String {
+        format!("{} {} {}", metric, op, value)
+    }
+
+    /// Check that the rules of the alarm and their series selectors parse
+    pub(crate) fn validate(&self) -> Result<(), ProxyErr> {
+        for m in self.metrics()? {
+            MetricSelector::parse(&m)?;
+        }
+        Ok(())
+    }
+
+    /// Distinct metrics the rules of the alarm refer to
+    pub(crate) fn metrics(&self) -> Result<Vec<String>, ProxyErr> {
+        let rule = AlarmExpr::parse(&self.rule)?;
+        let clear = match &self.clear {
+            Some(c) => Some(AlarmExpr::parse(c)?),
+            None => None,
+        };
+
+        let mut operands: Vec<&AlarmOperand> = Vec::new();
+        rule.operands(&mut operands);
+        if let Some(c) = &clear {
+            c.operands(&mut operands);
+        }
+
+        let mut ret: Vec<String> = Vec::new();
+
+        for o in operands {
+            if !ret.contains(o.metric()) {
+                ret.push(o.metric().to_string());
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Trigger for an alarm which metrics do not exist yet
+    pub(crate) fn as_unbound_trigger(&self) -> ValueAlarmTrigger {
+        ValueAlarmTrigger {
+            name: self.name.to_string(),
+            host: hostname(),
+            metric: "".to_string(),
+            operator: self.rule.to_string(),
+            current: f64::NAN,
+            active: false,
+            state: AlarmState::Inactive,
+            severity: self.severity,
+            since: 0,
+            pretty: format!(
+                "{} [{}] : {} (UNBOUND waiting for metrics)",
+                self.name, self.severity, self.rule
+            ),
+            unbound: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ValueAlarmTrigger {
+    pub(crate) name: String,
+    /// Host of the proxy evaluating the alarm
+    #[serde(default)]
+    pub(crate) host: String,
+    pub(crate) metric: String,
+    pub(crate) operator: String,
+    pub(crate) current: f64,
+    pub(crate) active: bool,
+    pub(crate) state: AlarmState,
+    pub(crate) severity: AlarmSeverity,
+    pub(crate) since: u64,
+    pub(crate) pretty: String,
+    /// The metrics of the alarm do not exist yet, it is not evaluated
+    #[serde(default)]
+    pub(crate) unbound: bool,
+}
+
+pub(crate) struct ValueAlarm {
+    spec: AlarmSpec,
+    rule: AlarmExpr,
+    clear: Option<AlarmExpr>,
+    metrics: HashMap<String, AlarmMetric>,
+    state: AlarmState,
+    /// Timestamp in seconds of the last state change
+    since: f64,
+    /// Value of the first operand of the rule at last evaluation
+    current: f64,
+    /// Series bound to the selector of a fanned out alarm
+    series: Option<String>,
+}
+
+impl fmt::Display for ValueAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{}] : {} ({})",
+            self.spec.name, self.spec.severity, self.spec.rule, self.state
+        )?;
+
+        if self.spec.pending > 0.0 {
+            write!(f, " for {}s", self.spec.pending)?;
+        }
+
+        if let Some(clear) = &self.spec.clear {
+            write!(f, " clears on {}", clear)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ValueAlarm {
+    /// Create an alarm resolving all the metrics its rules refer to
+    pub(crate) fn new<F>(spec: AlarmSpec, resolve: F) -> Result<ValueAlarm, ProxyErr>
+    where
+        F: Fn(&String) -> Result<Arc<RwLock<CounterSnapshot>>, ProxyErr>,
+    {
+        let rule = AlarmExpr::parse(&spec.rule)?;
+        let clear = match &spec.clear {
+            Some(c) => Some(AlarmExpr::parse(c)?),
+            None => None,
+        };
+
+        let mut operands: Vec<&AlarmOperand> = Vec::new();
+        rule.operands(&mut operands);
+        if let Some(c) = &clear {
+            c.operands(&mut operands);
+        }
+
+        let mut metrics: HashMap<String, AlarmMetric> = HashMap::new();
+
+        for o in operands {
+            if !metrics.contains_key(o.metric()) {
+                let counter = resolve(o.metric())?;
+                metrics.insert(o.metric().to_string(), AlarmMetric::new(counter));
+            }
+        }
+
+        Ok(ValueAlarm {
+            spec,
+            rule,
+            clear,
+            metrics,
+            state: AlarmState::Inactive,
+            since: unix_ts() as f64 / 1000.0,
+            current: f64::NAN,
+            series: None,
+        })
+    }
+
+    fn first_operand(&self) -> Option<&AlarmOperand> {
+        let mut operands: Vec<&AlarmOperand> = Vec::new();
+        self.rule.operands(&mut operands);
+        operands.first().cloned()
+    }
+
+    /// Largest window required by the operands of the alarm
+    fn horizon(&self) -> f64 {
+        let mut operands: Vec<&AlarmOperand> = Vec::new();
+        self.rule.operands(&mut operands);
+        if let Some(c) = &self.clear {
+            c.operands(&mut operands);
+        }
+        operands.iter().map(|o| o.window()).fold(0.0, f64::max)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn spec(&self) -> &AlarmSpec {
+        &self.spec
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.state == AlarmState::Firing
+    }
+
+    pub(crate) fn as_trigger(&self) -> ValueAlarmTrigger {
+        ValueAlarmTrigger {
+            name: self.spec.name.to_string(),
+            host: hostname(),
+            metric: match &self.series {
+                Some(series) => series.to_string(),
+                None => self
+                    .first_operand()
+                    .map(|o| o.metric().to_string())
+                    .unwrap_or_default(),
+            },
+            operator: self.spec.rule.to_string(),
+            current: self.current,
+            active: self.is_active(),
+            state: self.state,
+            severity: self.spec.severity,
+            since: self.since as u64,
+            pretty: self.to_string(),
+            unbound: false,
+        }
+    }
+
+    /// Sample the metrics and update the alarm state
+    /// the trigger is returned only when the state changed
+    pub(crate) fn evaluate(&mut self, model: Option<&AlarmModel>) -> Option<ValueAlarmTrigger> {
+        let now = unix_ts() as f64 / 1000.0;
+        let horizon = self.horizon();
+
+        for m in self.metrics.values_mut() {
+            m.sample(now, horizon);
+        }
+
+        self.current = self
+            .first_operand()
+            .and_then(|o| o.eval(&self.metrics, model))
+            .unwrap_or(f64::NAN);
+
+        let holds = self.rule.eval(&self.metrics, model);
+
+        /* Unknown rules leave the alarm in its state */
+        let new_state = match (self.state, holds) {
+            (AlarmState::Inactive | AlarmState::Pending, None) => self.state,
+            (AlarmState::Inactive | AlarmState::Pending, Some(false)) => AlarmState::Inactive,
+            (AlarmState::Inactive, _) if self.spec.pending > 0.0 => AlarmState::Pending,
+            (AlarmState::Pending, _) if now - self.since < self.spec.pending => AlarmState::Pending,
+            (AlarmState::Inactive | AlarmState::Pending, _) => AlarmState::Firing,
+            (AlarmState::Firing, _) => {
+                let cleared = match &self.clear {
+                    Some(c) => c.eval(&self.metrics, model),
+                    None => holds.map(|h| !h),
+                };
+                if cleared == Some(true) {
+                    AlarmState::Inactive
+                } else {
+                    AlarmState::Firing
+                }
+            }
+        };
+
+        if new_state == self.state {
+            return None;
+        }
+
+        log::debug!(
+            "Alarm {} goes from {} to {}",
+            self.spec.name,
+            self.state,
+            new_state
+        );
+
+        self.state = new_state;
+        self.since = now;
+
+        Some(self.as_trigger())
+    }
+}
+
+/*******************
+ * ALARM TEMPLATES *
+ *******************/
+
+/// An alarm template is an alarm definition which is instantiated
+/// on each new job matching its patterns, patterns are regular
+/// expressions searched in the corresponding field of the job
+/// description, unset patterns match any job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AlarmTemplate {
+    #[serde(flatten)]
+    pub(crate) spec: AlarmSpec,
+    #[serde(default)]
+    pub(crate) command: Option<String>,
+    #[serde(default)]
+    pub(crate) partition: Option<String>,
+    #[serde(default)]
+    pub(crate) user: Option<String>,
+}
+
+/// Compiled patterns of an AlarmTemplate
+struct AlarmTemplateMatcher {
+    command: Option<Regex>,
+    partition: Option<Regex>,
+    user: Option<Regex>,
+}
+
+impl AlarmTemplateMatcher {
+    fn compile(pattern: &Option<String>) -> Result<Option<Regex>, ProxyErr> {
+        match pattern {
+            Some(p) => Regex::new(p)
+                .map(Some)
+                .map_err(|e| ProxyErr::new(format!("Bad pattern '{}' : {}", p, e))),
+            None => Ok(None),
+        }
+    }
+
+    fn new(template: &AlarmTemplate) -> Result<AlarmTemplateMatcher, ProxyErr> {
+        Ok(AlarmTemplateMatcher {
+            command: Self::compile(&template.command)?,
+            partition: Self::compile(&template.partition)?,
+            user: Self::compile(&template.user)?,
+        })
+    }
+
+    fn matches(&self, desc: &JobDesc) -> bool {
+        let check = |re: &Option<Regex>, value: &str| re.as_ref().is_none_or(|r| r.is_match(value));
+
+        check(&self.command, &desc.command)
+            && check(&self.partition, &desc.partition)
+            && check(&self.user, &desc.user)
+    }
+}
+
+/// Storage for the alarm templates, they are kept in
+/// a JSON file in the proxy prefix to survive restarts
+pub(crate) struct AlarmTemplateStore {
+    path: PathBuf,
+    templates: RwLock<Vec<(AlarmTemplate, AlarmTemplateMatcher)>>,
+}
+
+impl AlarmTemplateStore {
+    pub(crate) fn new(prefix: &PathBuf) -> Result<AlarmTemplateStore, Box<dyn Error>> {
+        let mut path = check_prefix_dir(prefix, "alarms")?;
+        path.push("templates.json");
+
+        let ret = AlarmTemplateStore {
+            path,
+            templates: RwLock::new(Vec::new()),
+        };
+
+        ret.load()?;
+
+        Ok(ret)
+    }
+
+    fn load(&self) -> Result<(), Box<dyn Error>> {
+        if !self.path.is_file() {
+            return Ok(());
+        }
+
+        let file = fs::File::open(&self.path)?;
+        let templates: Vec<AlarmTemplate> = serde_json::from_reader(file)?;
+
+        let mut ht = self.templates.write().unwrap();
+
+        for t in templates {
+            match AlarmTemplateMatcher::new(&t) {
+                Ok(m) => ht.push((t, m)),
+                Err(e) => log::error!("Skipping alarm template {} : {}", t.spec.name, e),
+            }
+        }
+
+        log::info!(
+            "Loaded {} alarm template(s) from {}",
+            ht.len(),
+            self.path.to_string_lossy()
+        );
+
+        Ok(())
+    }
+
+    fn save(
+        &self,
+        templates: &[(AlarmTemplate, AlarmTemplateMatcher)],
+    ) -> Result<(), Box<dyn Error>> {
+        let templates: Vec<&AlarmTemplate> = templates.iter().map(|(t, _)| t).collect();
+
+        /* Write aside and rename not to leave a truncated file */
+        let mut tmp = self.path.clone();
+        tmp.set_extension("json.tmp");
+
+        let file = fs::File::create(&tmp)?;
+        serde_json::to_writer_pretty(file, &templates)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub(crate) fn add(&self, template: AlarmTemplate) -> Result<(), ProxyErr> {
+        template.spec.validate()?;
+        let matcher = AlarmTemplateMatcher::new(&template)?;
+
+        let mut ht = self.templates.write().unwrap();
+
+        if ht.iter().any(|(t, _)| t.spec.name == template.spec.name) {
+            return Err(ProxyErr::new(format!(
+                "Alarm template {} is already defined",
+                template.spec.name
+            )));
+        }
+
+        ht.push((template, matcher));
+        self.save(&ht).map_err(ProxyErr::from)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn delete(&self, name: &String) -> Result<(), ProxyErr> {
+        let mut ht = self.templates.write().unwrap();
+
+        let len = ht.len();
+        ht.retain(|(t, _)| &t.spec.name != name);
+
+        if len == ht.len() {
+            return Err(ProxyErr::new(format!("No such alarm template {}", name)));
+        }
+
+        self.save(&ht).map_err(ProxyErr::from)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn list(&self) -> Vec<AlarmTemplate> {
+        self.templates
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(t, _)| t.clone())
+            .collect()
+    }
+
+    /// Alarms to be created for a given job
+    pub(crate) fn instantiate(&self, desc: &JobDesc) -> Vec<AlarmSpec> {
+        self.templates
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, m)| m.matches(desc))
+            .map(|(t, _)| t.spec.clone())
+            .collect()
+    }
+}
+
+/*****************
+ * ALARM HISTORY *
+ *****************/
+
+/// Number of transitions kept in memory for queries
+const ALARM_HISTORY_LENGTH: usize = 16384;
+/// Size of the history log before it is rotated
+const ALARM_HISTORY_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A state transition of an alarm
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AlarmHistoryEntry {
+    /// Timestamp of the transition in milliseconds
+    pub(crate) ts: u64,
+    pub(crate) job: String,
+    pub(crate) host: String,
+    pub(crate) name: String,
+    pub(crate) previous: AlarmState,
+    pub(crate) state: AlarmState,
+    /// Time in seconds spent in the previous state if known
+    pub(crate) duration: Option<f64>,
+    /// Value of the first operand of the rule
+    pub(crate) value: Option<f64>,
+    pub(crate) rule: String,
+    pub(crate) severity: AlarmSeverity,
+}
+
+/// Filter used to query the alarm history
+#[derive(Default)]
+pub(crate) struct AlarmHistoryFilter {
+    pub(crate) job: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) since: Option<u64>,
+    pub(crate) until: Option<u64>,
+    pub(crate) limit: Option<usize>,
+}
+
+impl AlarmHistoryFilter {
+    pub(crate) fn matches(&self, entry: &AlarmHistoryEntry) -> bool {
+        self.job.as_ref().is_none_or(|j| j == &entry.job)
+            && self.name.as_ref().is_none_or(|n| n == &entry.name)
+            && self.since.is_none_or(|s| s <= entry.ts)
+            && self.until.is_none_or(|u| entry.ts <= u)
+    }
+}
+
+/// Last state and transition time of each (job, host, alarm)
+type AlarmStates = HashMap<(String, String, String), (AlarmState, u64)>;
+
+/// Persistent log of all the alarm transitions which
+/// are also forwarded to the subscribed event streams
+pub(crate) struct AlarmHistory {
+    path: PathBuf,
+    entries: Mutex<VecDeque<AlarmHistoryEntry>>,
+    last: Mutex<AlarmStates>,
+
+    subscribers: Mutex<Vec<Sender<AlarmHistoryEntry>>>,
+}
+
+impl AlarmHistory {
+    pub(crate) fn new(prefix: &PathBuf) -> Result<AlarmHistory, Box<dyn Error>> {
+        let mut path = check_prefix_dir(prefix, "alarms")?;
+        path.push("history.jsonl");
+
+        let ret = AlarmHistory {
+            path,
+            entries: Mutex::new(VecDeque::new()),
+            last: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(Vec::new()),
+        };
+
+        ret.load()?;
+
+        Ok(ret)
+    }
+
+    fn load(&self) -> Result<(), Box<dyn Error>> {
+        if !self.path.is_file() {
+            return Ok(());
+        }
+
+        let file = fs::File::open(&self.path)?;
+        let mut entries = self.entries.lock().unwrap();
+
+        for line in BufReader::new(file).lines() {
+            /* Skip truncated lines */
+            if let Ok(e) = serde_json::from_str::<AlarmHistoryEntry>(&line?) {
+                entries.push_back(e);
+                if entries.len() > ALARM_HISTORY_LENGTH {
+                    entries.pop_front();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(&self, new: &[AlarmHistoryEntry]) -> Result<(), Box<dyn Error>> {
+        if let Ok(meta) = fs::metadata(&self.path) {
+            if meta.len() > ALARM_HISTORY_MAX_SIZE {
+                let mut old = self.path.clone();
+                old.set_extension("jsonl.old");
+                fs::rename(&self.path, old)?;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        for e in new {
+            let mut line = serde_json::to_vec(e)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the transitions returned by the alarm evaluation
+    pub(crate) fn record(&self, changed: HashMap<String, Vec<ValueAlarmTrigger>>) {
+        if changed.is_empty() {
+            return;
+        }
+
+        let now = unix_ts();
+        let mut new: Vec<AlarmHistoryEntry> = Vec::new();
+
+        let mut last = self.last.lock().unwrap();
+
+        for (job, triggers) in changed {
+            for t in triggers {
+                let key = (job.clone(), t.host.clone(), t.name.clone());
+
+                let (previous, duration) = match last.get(&key) {
+                    Some((state, ts)) => (*state, Some(now.saturating_sub(*ts) as f64 / 1000.0)),
+                    None => (AlarmState::Inactive, None),
+                };
+
+                last.insert(key, (t.state, now));
+
+                new.push(AlarmHistoryEntry {
+                    ts: now,
+                    job: job.to_string(),
+                    host: t.host,
+                    name: t.name,
+                    previous,
+                    state: t.state,
+                    duration,
+                    value: if t.current.is_finite() {
+                        Some(t.current)
+                    } else {
+                        None
+                    },
+                    rule: t.operator,
+                    severity: t.severity,
+                });
+            }
+        }
+
+        if let Err(e) = self.append(&new) {
+            log::error!(
+                "Failed to save alarm history in {} : {}",
+                self.path.to_string_lossy(),
+                e
+            );
+        }
+
+        /* Forward to streams dropping closed ones */
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| new.iter().all(|e| s.send(e.clone()).is_ok()));
+
+        let mut entries = self.entries.lock().unwrap();
+
+        for e in new {
+            entries.push_back(e);
+            if entries.len() > ALARM_HISTORY_LENGTH {
+                entries.pop_front();
+            }
+        }
+    }
+
+    /// Drop the last known states of a job which left
+    pub(crate) fn forget_job(&self, jobid: &str) {
+        self.last
+            .lock()
+            .unwrap()
+            .retain(|(job, _, _), _| job != jobid);
+    }
+
+    /// Transitions matching the filter oldest first
+    pub(crate) fn query(&self, filter: &AlarmHistoryFilter) -> Vec<AlarmHistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+
+        let mut ret: Vec<AlarmHistoryEntry> = entries
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+
+        ret.reverse();
+        ret
+    }
+
+    /// Get a channel receiving all the upcoming transitions
+    pub(crate) fn subscribe(&self) -> Receiver<AlarmHistoryEntry> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/*********************
+ * ALARM PROPAGATION *
+ *********************/
+
+/// Deadline of the requests pushing alarms to the child proxies
+const PROPAGATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Alarm definition pushed down to the child proxies of the reduction tree
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct PropagatedAlarm {
+    pub(crate) target: String,
+    #[serde(flatten)]
+    pub(crate) spec: AlarmSpec,
+}
+
+impl PropagatedAlarm {
+    fn post(url: String, body: &serde_json::Value) -> Result<(), Box<dyn Error>> {
+        let response = Auth::post(&url, body, PROPAGATION_TIMEOUT)?.send()?;
+
+        if !response.status().is_success() {
+            return Err(ProxyErr::newboxed(format!(
+                "{} replied {} : {}",
+                url,
+                response.status(),
+                response.text().unwrap_or_default()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Register the alarm in a child proxy which forwards it to its own children
+    pub(crate) fn push(&self, child_url: &str) -> Result<(), Box<dyn Error>> {
+        let mut body = serde_json::to_value(self)?;
+        body["propagate"] = serde_json::Value::Bool(true);
+        PropagatedAlarm::post(format!("{}/alarms/add", child_url), &body)
+    }
+
+    /// Remove an alarm from a child proxy and its own children
+    pub(crate) fn push_delete(
+        target: &str,
+        name: &str,
+        child_url: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let body = serde_json::json!({
+            "target": target,
+            "name": name,
+            "propagate": true
+        });
+        PropagatedAlarm::post(format!("{}/alarms/del", child_url), &body)
+    }
+}
+
+/// Alarms of the child proxies as listed by their /alarms/list
+/// and alarm definitions to push down to children joining later
+pub(crate) struct RemoteAlarms {
+    /// Last listing of each child by scrape URL
+    children: Mutex<HashMap<String, HashMap<String, Vec<ValueAlarmTrigger>>>>,
+    propagated: Mutex<Vec<PropagatedAlarm>>,
+}
+
+impl RemoteAlarms {
+    pub(crate) fn new() -> RemoteAlarms {
+        RemoteAlarms {
+            children: Mutex::new(HashMap::new()),
+            propagated: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Replace the listing of a child returning the alarms which changed state
+    pub(crate) fn update(
+        &self,
+        child: &str,
+        alarms: HashMap<String, Vec<ValueAlarmTrigger>>,
+    ) -> HashMap<String, Vec<ValueAlarmTrigger>> {
+        let mut children = self.children.lock().unwrap();
+
+        let previous: HashMap<(&String, &String, &String), AlarmState> = children
+            .get(child)
+            .into_iter()
+            .flat_map(|l| l.iter())
+            .flat_map(|(job, triggers)| {
+                triggers
+                    .iter()
+                    .map(move |t| ((job, &t.host, &t.name), t.state))
+            })
+            .collect();
+
+        let mut changed: HashMap<String, Vec<ValueAlarmTrigger>> = HashMap::new();
+
+        for (job, triggers) in alarms.iter() {
+            for t in triggers {
+                let before = previous
+                    .get(&(job, &t.host, &t.name))
+                    .copied()
+                    .unwrap_or(AlarmState::Inactive);
+
+                if before != t.state {
+                    changed.entry(job.to_string()).or_default().push(t.clone());
+                }
+            }
+        }
+
+        children.insert(child.to_string(), alarms);
+
+        changed
+    }
+
+    /// Forget a child which left the tree
+    pub(crate) fn remove(&self, child: &str) {
+        self.children.lock().unwrap().remove(child);
+    }
+
+    /// Add the alarms of all children to a per job listing
+    pub(crate) fn merge(
+        &self,
+        ret: &mut HashMap<String, Vec<ValueAlarmTrigger>>,
+        active_only: bool,
+    ) {
+        let children = self.children.lock().unwrap();
+
+        for (job, triggers) in children.values().flat_map(|l| l.iter()) {
+            ret.entry(job.to_string()).or_default().extend(
+                triggers
+                    .iter()
+                    .filter(|t| !active_only || t.active)
+                    .cloned(),
+            );
+        }
+    }
+
+    /// Keep a definition for the children joining later
+    pub(crate) fn propagate(&self, alarm: PropagatedAlarm) {
+        let mut propagated = self.propagated.lock().unwrap();
+        propagated.retain(|a| a.target != alarm.target || a.spec.name != alarm.spec.name);
+        propagated.push(alarm);
+    }
+
+    pub(crate) fn unpropagate(&self, target: &String, name: &String) {
+        self.propagated
+            .lock()
+            .unwrap()
+            .retain(|a| &a.target != target || &a.spec.name != name);
+    }
+
+    pub(crate) fn propagated(&self) -> Vec<PropagatedAlarm> {
+        self.propagated.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(rule: &str, pending: f64, clear: Option<&str>) -> AlarmSpec {
+        AlarmSpec {
+            name: "test".to_string(),
+            rule: rule.to_string(),
+            pending,
+            clear: clear.map(|c| c.to_string()),
+            severity: AlarmSeverity::default(),
+        }
+    }
+
+    fn gauge(name: &str, value: f64) -> Arc<RwLock<CounterSnapshot>> {
+        Arc::new(RwLock::new(CounterSnapshot::new(
+            name.to_string(),
+            &[],
+            "".to_string(),
+            CounterType::Gauge {
+                min: value,
+                max: value,
+                hits: 1.0,
+                total: value,
+            },
+        )))
+    }
+
+    fn set(counter: &Arc<RwLock<CounterSnapshot>>, value: f64) {
+        counter.write().unwrap().ctype = CounterType::Gauge {
+            min: value,
+            max: value,
+            hits: 1.0,
+            total: value,
+        };
+    }
+
+    /// Metrics holding the given (timestamp, value) samples
+    fn metrics(samples: &[(&str, &[(f64, f64)])]) -> HashMap<String, AlarmMetric> {
+        samples
+            .iter()
+            .map(|(name, samples)| {
+                let mut m = AlarmMetric::new(gauge(name, 0.0));
+                m.samples.extend(samples.iter().cloned());
+                (name.to_string(), m)
+            })
+            .collect()
+    }
+
+    fn eval(rule: &str, metrics: &HashMap<String, AlarmMetric>) -> Option<bool> {
+        AlarmExpr::parse(rule).unwrap().eval(metrics, None)
+    }
+
+    #[test]
+    fn durations_have_units() {
+        assert_eq!(parse_duration("500ms").unwrap(), 0.5);
+        assert_eq!(parse_duration("30s").unwrap(), 30.0);
+        assert_eq!(parse_duration(" 5m ").unwrap(), 300.0);
+        assert_eq!(parse_duration("2h").unwrap(), 7200.0);
+        assert_eq!(parse_duration("1d").unwrap(), 86400.0);
+        assert_eq!(parse_duration("1.5").unwrap(), 1.5);
+        assert!(parse_duration("3w").is_err());
+        assert!(parse_duration("m").is_err());
+
+        let spec: AlarmSpec =
+            serde_json::from_str(r#"{"name": "a", "rule": "x > 1", "for": "2m"}"#).unwrap();
+        assert_eq!(spec.pending, 120.0);
+        let spec: AlarmSpec =
+            serde_json::from_str(r#"{"name": "a", "rule": "x > 1", "for": 15}"#).unwrap();
+        assert_eq!(spec.pending, 15.0);
+    }
+
+    #[test]
+    fn rules_follow_precedence() {
+        let m = metrics(&[
+            ("a", &[(0.0, 2.0)]),
+            ("b", &[(0.0, 0.0)]),
+            ("c", &[(0.0, 0.0)]),
+        ]);
+
+        /* && binds tighter than || */
+        assert_eq!(eval("a > 1 || b > 1 && c > 1", &m), Some(true));
+        assert_eq!(eval("(a > 1 || b > 1) && c > 1", &m), Some(false));
+        assert_eq!(eval("a > 1 or b > 1 and c > 1", &m), Some(true));
+
+        /* ! binds tighter than && */
+        assert_eq!(eval("!a > 1 && b > 1", &m), Some(false));
+        assert_eq!(eval("!(a > 1 && b > 1)", &m), Some(true));
+        assert_eq!(eval("not not a >= 2", &m), Some(true));
+
+        assert_eq!(eval("a != 2 || b == 0", &m), Some(true));
+        assert_eq!(eval("a = 2 && b <= -1", &m), Some(false));
+    }
+
+    #[test]
+    fn rules_parse_operands_and_windows() {
+        let window = |rule: &str| match AlarmExpr::parse(rule).unwrap() {
+            AlarmExpr::Condition { operand, .. } => operand.window(),
+            e => panic!("{:?} is not a condition", e),
+        };
+
+        assert_eq!(window("rate(a, 5m) > 1"), 300.0);
+        assert_eq!(window("deriv(a, 500ms) > 1"), 0.5);
+        assert_eq!(window("rate(a, 10) > 1"), 10.0);
+        assert_eq!(window("deriv(a) > 1"), DEFAULT_ALARM_WINDOW);
+        assert_eq!(window("deviation(a) > 1.5e0"), 0.0);
+
+        let s = spec(r#"rate(io{job="1"}) > 1 || mem > 2"#, 0.0, Some("mem < 1"));
+        assert_eq!(s.metrics().unwrap(), vec![r#"io{job="1"}"#, "mem"]);
+
+        for bad in [
+            "a >",
+            "a > b",
+            "> 1",
+            "(a > 1",
+            "a > 1)",
+            "a > 1 b > 1",
+            "rate(a, 5x) > 1",
+            "rate(a, b) > 1",
+            "deviation(a, 5m) > 1",
+            "a{job=\"1\" > 1",
+            "a ~ 1",
+        ] {
+            assert!(AlarmExpr::parse(bad).is_err(), "'{}' should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn rates_need_two_samples() {
+        let m = metrics(&[
+            ("one", &[(0.0, 10.0)]),
+            ("grow", &[(0.0, 0.0), (10.0, 50.0)]),
+            ("reset", &[(0.0, 100.0), (10.0, 20.0)]),
+            ("same", &[(5.0, 1.0), (5.0, 2.0)]),
+        ]);
+
+        assert_eq!(m["one"].rate(60.0), None);
+        assert_eq!(m["one"].deriv(60.0), None);
+        assert_eq!(eval("rate(one) > 0", &m), None);
+        assert_eq!(eval("deriv(one) < 0", &m), None);
+
+        assert_eq!(m["grow"].rate(60.0), Some(5.0));
+        assert_eq!(m["grow"].deriv(60.0), Some(5.0));
+        /* A decrease is a counter reset for rate() but not for deriv() */
+        assert_eq!(m["reset"].rate(60.0), Some(2.0));
+        assert_eq!(m["reset"].deriv(60.0), Some(-8.0));
+        /* Samples out of the window are ignored */
+        assert_eq!(m["grow"].rate(5.0), None);
+        /* Samples at the same time give no slope */
+        assert_eq!(m["same"].rate(60.0), None);
+        assert_eq!(m["same"].deriv(60.0), None);
+    }
+
+    #[test]
+    fn missing_samples_are_unknown() {
+        let m = metrics(&[
+            ("none", &[]),
+            ("high", &[(0.0, 2.0)]),
+            ("low", &[(0.0, 0.0)]),
+        ]);
+
+        assert_eq!(eval("none > 1", &m), None);
+        /* Not over a missing sample stays unknown */
+        assert_eq!(eval("!(none > 1)", &m), None);
+        assert_eq!(eval("not none <= 1", &m), None);
+        assert_eq!(eval("none > 1 && low > 1", &m), Some(false));
+        assert_eq!(eval("none > 1 && high > 1", &m), None);
+        assert_eq!(eval("none > 1 || high > 1", &m), Some(true));
+        assert_eq!(eval("none > 1 || low > 1", &m), None);
+        assert_eq!(eval("!(none > 1 || high > 1)", &m), Some(false));
+    }
+
+    #[test]
+    fn alarms_wait_for_their_pending_duration() {
+        let cpu = gauge("cpu", 95.0);
+        let mut alarm =
+            ValueAlarm::new(
+                spec("cpu > 90", 60.0, Some("cpu < 50")),
+                |_| Ok(cpu.clone()),
+            )
+            .unwrap();
+
+        assert_eq!(alarm.evaluate(None).unwrap().state, AlarmState::Pending);
+        assert!(alarm.evaluate(None).is_none());
+
+        /* The rule must hold for the whole pending duration */
+        set(&cpu, 10.0);
+        assert_eq!(alarm.evaluate(None).unwrap().state, AlarmState::Inactive);
+
+        set(&cpu, 95.0);
+        assert_eq!(alarm.evaluate(None).unwrap().state, AlarmState::Pending);
+        alarm.since -= 61.0;
+        let trigger = alarm.evaluate(None).unwrap();
+        assert_eq!(trigger.state, AlarmState::Firing);
+        assert!(trigger.active);
+        assert_eq!(trigger.current, 95.0);
+
+        /* Between the rule and the clear rule the alarm keeps firing */
+        set(&cpu, 70.0);
+        assert!(alarm.evaluate(None).is_none());
+        assert!(alarm.is_active());
+
+        set(&cpu, 40.0);
+        let trigger = alarm.evaluate(None).unwrap();
+        assert_eq!(trigger.state, AlarmState::Inactive);
+        assert!(!trigger.active);
+    }
+
+    #[test]
+    fn alarms_without_clear_rule_clear_with_their_rule() {
+        let cpu = gauge("cpu", 95.0);
+        let mut alarm = ValueAlarm::new(spec("cpu > 90", 0.0, None), |_| Ok(cpu.clone())).unwrap();
+
+        assert_eq!(alarm.evaluate(None).unwrap().state, AlarmState::Firing);
+        assert!(alarm.evaluate(None).is_none());
+
+        set(&cpu, 80.0);
+        assert_eq!(alarm.evaluate(None).unwrap().state, AlarmState::Inactive);
+        assert!(alarm.evaluate(None).is_none());
+    }
+
+    #[test]
+    fn deviation_without_prediction_is_unknown() {
+        let mem = gauge("mem", 300.0);
+        let mut alarm =
+            ValueAlarm::new(spec("deviation(mem) > 2", 0.0, None), |_| Ok(mem.clone())).unwrap();
+
+        let predicted = AlarmModel::new(|_| Some(100.0), 0.0);
+        let higher = AlarmModel::new(|_| Some(1000.0), 0.0);
+        let unknown = AlarmModel::new(|_| None, 0.0);
+
+        assert!(alarm.evaluate(Some(&unknown)).is_none());
+        assert_eq!(
+            alarm.evaluate(Some(&predicted)).unwrap().state,
+            AlarmState::Firing
+        );
+
+        /* Without prediction the alarm neither fires nor clears */
+        assert!(alarm.evaluate(Some(&unknown)).is_none());
+        assert!(alarm.evaluate(None).is_none());
+        assert_eq!(alarm.state, AlarmState::Firing);
+
+        /* A prediction for which the rule is false clears it */
+        assert_eq!(
+            alarm.evaluate(Some(&higher)).unwrap().state,
+            AlarmState::Inactive
+        );
+    }
+}
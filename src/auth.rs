@@ -0,0 +1,435 @@
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::{Certificate, Method, Url};
+use rouille::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::config::ScrapeTargetConfig;
+use crate::proxy_common::{unix_ts, unix_ts_us, ProxyErr};
+
+/// Scheme of the Authorization header of signed requests
+const HMAC_SCHEME: &str = "HMAC-SHA256";
+
+/// Largest difference in seconds between the time of a signed request and ours
+const HMAC_MAX_SKEW: u64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Counter making the nonces of the requests of this proxy unique
+static NONCE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Access granted to a request, admin includes read
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    Read,
+    Admin,
+}
+
+/// A bearer token accepted by the webserver
+#[allow(unused)]
+#[derive(Deserialize)]
+struct TokenConfig {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    scope: Scope,
+}
+
+#[allow(unused)]
+#[derive(Deserialize)]
+struct TlsConfig {
+    /// PEM certificate (chain) of the webserver
+    cert: PathBuf,
+    /// PEM private key of the webserver
+    key: PathBuf,
+    /// PEM certificate of the authority of the other proxies (system ones otherwise)
+    #[serde(default)]
+    ca: Option<PathBuf>,
+}
+
+#[allow(unused)]
+fn default_anonymous() -> Option<Scope> {
+    Some(Scope::Read)
+}
+
+/// Content of the authentication file
+#[allow(unused)]
+#[derive(Deserialize)]
+struct AuthConfig {
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    tokens: Vec<TokenConfig>,
+    /// Secret shared by the proxies to sign their requests
+    #[serde(default)]
+    hmac_key_file: Option<PathBuf>,
+    /// Scope of the requests without credentials (null to deny them)
+    #[serde(default = "default_anonymous")]
+    anonymous: Option<Scope>,
+}
+
+/// Credentials of the webserver and of the requests to other proxies
+pub(crate) struct Auth {
+    tokens: Vec<(String, Scope)>,
+    hmac_key: Option<Vec<u8>>,
+    anonymous: Option<Scope>,
+    /// Certificate and private key of the webserver
+    tls: Option<(Vec<u8>, Vec<u8>)>,
+    ca: Option<Certificate>,
+    /// Timestamp of the signatures already accepted, to refuse replays
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+lazy_static! {
+    static ref AUTH: RwLock<Arc<Auth>> = RwLock::new(Arc::new(Auth::open()));
+}
+
+/// Compare secrets in a time independent of their content
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Auth {
+    /// No credentials, everything is allowed over HTTP
+    fn open() -> Auth {
+        Auth {
+            tokens: Vec::new(),
+            hmac_key: None,
+            anonymous: Some(Scope::Admin),
+            tls: None,
+            ca: None,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[allow(unused)]
+    fn read(path: &PathBuf) -> Result<Vec<u8>, ProxyErr> {
+        fs::read(path)
+            .map_err(|e| ProxyErr::new(format!("Failed to read {} : {}", path.display(), e)))
+    }
+
+    #[allow(unused)]
+    pub(crate) fn load(path: &PathBuf) -> Result<Auth, Box<dyn Error>> {
+        let config: AuthConfig = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        let mut tokens: Vec<(String, Scope)> = Vec::new();
+
+        for t in config.tokens {
+            let token = match (t.token, &t.token_file) {
+                (Some(token), None) => token,
+                (None, Some(f)) => ScrapeTargetConfig::read_secret(f)?,
+                _ => {
+                    return Err(ProxyErr::newboxed(
+                        "Tokens need one of 'token' and 'token_file'",
+                    ))
+                }
+            };
+            if token.is_empty() {
+                return Err(ProxyErr::newboxed("Tokens cannot be empty"));
+            }
+            tokens.push((token, t.scope));
+        }
+
+        let hmac_key = match &config.hmac_key_file {
+            Some(f) => Some(ScrapeTargetConfig::read_secret(f)?.into_bytes()),
+            None => None,
+        };
+
+        let (tls, ca) = match &config.tls {
+            Some(tls) => {
+                let ca = match &tls.ca {
+                    Some(ca) => Some(Certificate::from_pem(&Auth::read(ca)?)?),
+                    None => None,
+                };
+                (Some((Auth::read(&tls.cert)?, Auth::read(&tls.key)?)), ca)
+            }
+            None => (None, None),
+        };
+
+        Ok(Auth {
+            tokens,
+            hmac_key,
+            anonymous: config.anonymous,
+            tls,
+            ca,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Use these credentials for the whole proxy
+    #[allow(unused)]
+    pub(crate) fn install(auth: Auth) {
+        *AUTH.write().unwrap() = Arc::new(auth);
+    }
+
+    pub(crate) fn get() -> Arc<Auth> {
+        AUTH.read().unwrap().clone()
+    }
+
+    /// Certificate and private key of the webserver if TLS is enabled
+    #[allow(unused)]
+    pub(crate) fn tls(&self) -> Option<&(Vec<u8>, Vec<u8>)> {
+        self.tls.as_ref()
+    }
+
+    /// URL of a host:port, over HTTPS when this proxy uses TLS
+    /// unless the address explicitly asks for HTTP
+    pub(crate) fn url(address: &str) -> String {
+        if address.starts_with("http://") || address.starts_with("https://") {
+            address.to_string()
+        } else if Auth::get().tls.is_some() {
+            format!("https://{}", address)
+        } else {
+            format!("http://{}", address)
+        }
+    }
+
+    /*************
+     * WEBSERVER *
+     *************/
+
+    fn signature(
+        key: &[u8],
+        method: &str,
+        path: &str,
+        ts: u64,
+        nonce: &str,
+        body: &[u8],
+    ) -> Result<HmacSha256, ProxyErr> {
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| ProxyErr::new(format!("Bad HMAC key : {}", e)))?;
+        mac.update(
+            format!(
+                "{}\n{}\n{}\n{}\n{}",
+                method,
+                path,
+                ts,
+                nonce,
+                hex(&Sha256::digest(body))
+            )
+            .as_bytes(),
+        );
+        Ok(mac)
+    }
+
+    /// Check a "HMAC-SHA256 TS:NONCE:HEX" authorization of the request,
+    /// each signature being accepted only once
+    fn verify(&self, key: &[u8], req: &Request, body: &[u8], value: &str) -> bool {
+        let (ts, nonce, sig) = match value.splitn(3, ':').collect::<Vec<&str>>()[..] {
+            [ts, nonce, sig] => (ts, nonce, sig),
+            _ => return false,
+        };
+
+        let ts = match ts.trim().parse::<u64>() {
+            Ok(ts) => ts,
+            Err(_) => return false,
+        };
+
+        let now = unix_ts() / 1000;
+
+        if now.abs_diff(ts) > HMAC_MAX_SKEW {
+            return false;
+        }
+
+        let sig: Option<Vec<u8>> = (0..sig.len())
+            .step_by(2)
+            .map(|i| {
+                sig.get(i..i + 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+            })
+            .collect();
+
+        let sig = match (
+            sig,
+            Auth::signature(key, req.method(), req.raw_url(), ts, nonce, body),
+        ) {
+            (Some(sig), Ok(mac)) => match mac.verify_slice(&sig) {
+                Ok(_) => sig,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+
+        /* Older signatures are refused by the skew check */
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, ts| now.abs_diff(*ts) <= HMAC_MAX_SKEW);
+        seen.insert(sig, ts).is_none()
+    }
+
+    /// Read the body of a signed request as the signature covers it,
+    /// returning a copy of the request still holding the body for the handlers
+    #[allow(unused)]
+    pub(crate) fn buffer_signed(req: &Request) -> Option<(Request, Vec<u8>)> {
+        if !req
+            .header("Authorization")
+            .is_some_and(|a| a.trim().starts_with(HMAC_SCHEME))
+        {
+            return None;
+        }
+
+        let mut body: Vec<u8> = Vec::new();
+
+        if let Some(mut data) = req.data() {
+            if data.read_to_end(&mut body).is_err() {
+                body.clear();
+            }
+        }
+
+        let headers: Vec<(String, String)> = req
+            .headers()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let copy = if req.is_secure() {
+            Request::fake_https_from(
+                *req.remote_addr(),
+                req.method(),
+                req.raw_url(),
+                headers,
+                body.clone(),
+            )
+        } else {
+            Request::fake_http_from(
+                *req.remote_addr(),
+                req.method(),
+                req.raw_url(),
+                headers,
+                body.clone(),
+            )
+        };
+
+        Some((copy, body))
+    }
+
+    /// Scope granted to a request, None if it is not allowed at all
+    #[allow(unused)]
+    pub(crate) fn scope(&self, req: &Request, body: &[u8]) -> Option<Scope> {
+        if self.tokens.is_empty() && self.hmac_key.is_none() {
+            return self.anonymous;
+        }
+
+        let authorization = match req.header("Authorization") {
+            Some(a) => a.trim(),
+            None => return self.anonymous,
+        };
+
+        /* Wrong credentials are refused even if anonymous requests are not */
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self
+                .tokens
+                .iter()
+                .filter(|(t, _)| same_secret(t.as_bytes(), token.trim().as_bytes()))
+                .map(|(_, s)| *s)
+                .max();
+        }
+
+        if let (Some(value), Some(key)) = (
+            authorization.strip_prefix(HMAC_SCHEME),
+            self.hmac_key.as_ref(),
+        ) {
+            if self.verify(key, req, body, value.trim()) {
+                return Some(Scope::Admin);
+            }
+        }
+
+        None
+    }
+
+    /**********
+     * CLIENT *
+     **********/
+
+    /// Client verifying the certificates of the other proxies
+    pub(crate) fn client(timeout: Duration) -> Result<Client, reqwest::Error> {
+        let builder = Client::builder().timeout(timeout);
+
+        match &Auth::get().ca {
+            Some(ca) => builder.add_root_certificate(ca.clone()).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// Add the credentials of this proxy to a request to another proxy,
+    /// signed when sharing an HMAC key or with the first admin token
+    pub(crate) fn sign(
+        request: RequestBuilder,
+        method: &Method,
+        url: &str,
+        body: &[u8],
+    ) -> RequestBuilder {
+        let auth = Auth::get();
+
+        if let Some(key) = &auth.hmac_key {
+            let path = match Url::parse(url) {
+                Ok(u) => match u.query() {
+                    Some(q) => format!("{}?{}", u.path(), q),
+                    None => u.path().to_string(),
+                },
+                Err(_) => return request,
+            };
+
+            let ts = unix_ts() / 1000;
+            let nonce = format!(
+                "{:x}-{:x}-{:x}",
+                std::process::id(),
+                unix_ts_us(),
+                NONCE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+
+            if let Ok(mac) = Auth::signature(key, method.as_str(), &path, ts, &nonce, body) {
+                let sig = hex(&mac.finalize().into_bytes());
+                return request.header(
+                    "Authorization",
+                    format!("{} {}:{}:{}", HMAC_SCHEME, ts, nonce, sig),
+                );
+            }
+        }
+
+        match auth.tokens.iter().find(|(_, s)| *s == Scope::Admin) {
+            Some((token, _)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Request to another proxy with the credentials of this one
+    #[allow(unused)]
+    pub(crate) fn request(
+        method: Method,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<RequestBuilder, reqwest::Error> {
+        let url = Auth::url(url);
+        let request = Auth::client(timeout)?.request(method.clone(), &url);
+        Ok(Auth::sign(request, &method, &url, &[]))
+    }
+
+    /// JSON POST to another proxy with the credentials of this one
+    pub(crate) fn post<T: Serialize + ?Sized>(
+        url: &str,
+        body: &T,
+        timeout: Duration,
+    ) -> Result<RequestBuilder, Box<dyn Error>> {
+        let url = Auth::url(url);
+        let body = serde_json::to_vec(body)?;
+        let request = Auth::client(timeout)?
+            .post(&url)
+            .header("Content-Type", "application/json");
+        Ok(Auth::sign(request, &Method::POST, &url, &body).body(body))
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::proxy_common::unix_ts_us;
+use crate::proxywireprotocol::{CounterSnapshot, CounterType};
+
+/// Default location of the Slurm job cgroups relative to the cgroup root
+pub(crate) const DEFAULT_CGROUP_TEMPLATE: &str = "system.slice/slurmstepd.scope/job_{job}";
+
+/// Reads the cgroup v2 accounting of the local jobs
+pub(crate) struct CgroupMetrics {
+    root: PathBuf,
+    template: String,
+}
+
+impl CgroupMetrics {
+    pub(crate) fn new(root: Option<&PathBuf>, template: Option<&String>) -> CgroupMetrics {
+        CgroupMetrics {
+            root: root
+                .cloned()
+                .unwrap_or_else(|| PathBuf::from("/sys/fs/cgroup")),
+            template: template
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_CGROUP_TEMPLATE.to_string()),
+        }
+    }
+
+    /// Directory of the cgroup of a job, the template may use {jobid}
+    /// and the {job} and {step} parts of a Slurm "job-step" identifier
+    fn path(&self, jobid: &str) -> PathBuf {
+        let (job, step) = jobid.split_once('-').unwrap_or((jobid, ""));
+
+        let relative = self
+            .template
+            .replace("{jobid}", jobid)
+            .replace("{job}", job)
+            .replace("{step}", step);
+
+        self.root.join(relative.trim_start_matches('/'))
+    }
+
+    /// Parse the "key value" lines of a cgroup file
+    fn flat_keyed(dir: &Path, file: &str) -> HashMap<String, f64> {
+        fs::read_to_string(dir.join(file))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| {
+                let (k, v) = l.split_once(' ')?;
+                Some((k.to_string(), v.trim().parse::<f64>().ok()?))
+            })
+            .collect()
+    }
+
+    fn single_value(dir: &Path, file: &str) -> Option<f64> {
+        fs::read_to_string(dir.join(file))
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+    }
+
+    /// Counters of the cgroup of a job, None if the job has no cgroup
+    pub(crate) fn scrape(&self, jobid: &str) -> Option<Vec<CounterSnapshot>> {
+        let dir = self.path(jobid);
+
+        if !dir.is_dir() {
+            return None;
+        }
+
+        let ts = unix_ts_us();
+        let mut ret = Vec::new();
+
+        let mut counter = |name: &str, labels: &[(String, String)], doc: &str, value: f64| {
+            ret.push(CounterSnapshot::new(
+                name.to_string(),
+                labels,
+                doc.to_string(),
+                CounterType::Counter { ts, value },
+            ))
+        };
+
+        let label = |k: &str, v: &str| vec![(k.to_string(), v.to_string())];
+
+        /* CPU times are in us */
+        let cpu = CgroupMetrics::flat_keyed(&dir, "cpu.stat");
+        let cpu_get = |k: &str| cpu.get(k).map(|v| v / 1e6);
+
+        for (mode, key) in [("user", "user_usec"), ("system", "system_usec")] {
+            if let Some(v) = cpu_get(key) {
+                counter(
+                    "proxy_cgroup_cpu_seconds_total",
+                    &label("mode", mode),
+                    "CPU time spent in the cgroup of the job",
+                    v,
+                );
+            }
+        }
+
+        if let Some(v) = cpu_get("throttled_usec") {
+            counter(
+                "proxy_cgroup_cpu_throttled_seconds_total",
+                &[],
+                "Time the cgroup of the job was throttled",
+                v,
+            );
+        }
+
+        if let Some(v) = cpu.get("nr_throttled") {
+            counter(
+                "proxy_cgroup_cpu_throttled_periods_total",
+                &[],
+                "Number of periods the cgroup of the job was throttled",
+                *v,
+            );
+        }
+
+        for (event, v) in CgroupMetrics::flat_keyed(&dir, "memory.events") {
+            counter(
+                "proxy_cgroup_memory_events_total",
+                &label("event", &event),
+                "Memory events (low, high, max, oom, oom_kill) of the cgroup of the job",
+                v,
+            );
+        }
+
+        /* io.stat lines are "MAJ:MIN rbytes=X wbytes=X rios=X wios=X ..." */
+        let io = fs::read_to_string(dir.join("io.stat")).unwrap_or_default();
+
+        for l in io.lines() {
+            let mut fields = l.split_whitespace();
+
+            let device = match fields.next() {
+                Some(d) => label("device", d),
+                None => continue,
+            };
+
+            for (key, v) in fields.filter_map(|f| f.split_once('=')) {
+                let v = match v.parse::<f64>() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+
+                let (name, doc) = match key {
+                    "rbytes" => (
+                        "proxy_cgroup_io_read_bytes_total",
+                        "Bytes read by the cgroup of the job",
+                    ),
+                    "wbytes" => (
+                        "proxy_cgroup_io_write_bytes_total",
+                        "Bytes written by the cgroup of the job",
+                    ),
+                    "rios" => (
+                        "proxy_cgroup_io_reads_total",
+                        "Read operations of the cgroup of the job",
+                    ),
+                    "wios" => (
+                        "proxy_cgroup_io_writes_total",
+                        "Write operations of the cgroup of the job",
+                    ),
+                    _ => continue,
+                };
+
+                counter(name, &device, doc, v);
+            }
+        }
+
+        let gauges = [
+            (
+                "proxy_cgroup_memory_bytes",
+                "Memory currently used by the cgroup of the job",
+                "memory.current",
+            ),
+            (
+                "proxy_cgroup_memory_peak_bytes",
+                "Peak memory used by the cgroup of the job",
+                "memory.peak",
+            ),
+            (
+                "proxy_cgroup_pids",
+                "Number of processes in the cgroup of the job",
+                "pids.current",
+            ),
+        ];
+
+        for (name, doc, file) in gauges {
+            if let Some(v) = CgroupMetrics::single_value(&dir, file) {
+                ret.push(CounterSnapshot::new(
+                    name.to_string(),
+                    &[],
+                    doc.to_string(),
+                    CounterType::Gauge {
+                        min: v,
+                        max: v,
+                        hits: 1.0,
+                        total: v,
+                    },
+                ));
+            }
+        }
+
+        Some(ret)
+    }
+}
@@ -0,0 +1,525 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use crate::alarms::{deserialize_duration, MetricSelector};
+use crate::counterfiles::CounterFiles;
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::ProxyErr;
+use crate::proxywireprotocol::CounterSnapshot;
+
+/*****************
+ * RELABEL RULES *
+ *****************/
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_regex() -> String {
+    "(.*)".to_string()
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RelabelAction {
+    /// Set target_label to the replacement when the regex matches
+    #[default]
+    Replace,
+    /// Drop the series when the regex does not match
+    Keep,
+    /// Drop the series when the regex matches
+    Drop,
+    /// Remove the labels which name matches the regex
+    LabelDrop,
+    /// Only keep the labels which name matches the regex
+    LabelKeep,
+}
+
+/// Relabel rule as given in the configuration, it follows the Prometheus
+/// relabel_config where the metric name is the __name__ label
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct RelabelConfig {
+    #[serde(default)]
+    action: RelabelAction,
+    #[serde(default)]
+    source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    separator: String,
+    #[serde(default = "default_regex")]
+    regex: String,
+    #[serde(default)]
+    target_label: Option<String>,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+}
+
+pub(crate) struct RelabelRule {
+    config: RelabelConfig,
+    regex: Regex,
+}
+
+impl RelabelRule {
+    fn new(config: &RelabelConfig) -> Result<RelabelRule, ProxyErr> {
+        /* Regular expressions are anchored as in Prometheus */
+        let regex = Regex::new(&format!("^(?:{})$", config.regex))
+            .map_err(|e| ProxyErr::new(format!("Bad relabel regex '{}' : {}", config.regex, e)))?;
+
+        if config.action == RelabelAction::Replace && config.target_label.is_none() {
+            return Err(ProxyErr::new(
+                "A replace relabel rule needs a 'target_label'",
+            ));
+        }
+
+        Ok(RelabelRule {
+            config: config.clone(),
+            regex,
+        })
+    }
+
+    /// Rule setting a label to a fixed value on all the series
+    pub(crate) fn static_label(name: &str, value: &str) -> Result<RelabelRule, ProxyErr> {
+        RelabelRule::new(&RelabelConfig {
+            action: RelabelAction::Replace,
+            source_labels: Vec::new(),
+            separator: default_separator(),
+            regex: default_regex(),
+            target_label: Some(name.to_string()),
+            /* The replacement expands $ references */
+            replacement: value.replace('$', "$$"),
+        })
+    }
+
+    fn get<'a>(labels: &'a [(String, String)], name: &str) -> &'a str {
+        labels
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("")
+    }
+
+    /// Apply the rule on the labels returning false if the series is dropped
+    fn apply(&self, labels: &mut Vec<(String, String)>) -> bool {
+        let source = self
+            .config
+            .source_labels
+            .iter()
+            .map(|l| RelabelRule::get(labels, l))
+            .collect::<Vec<&str>>()
+            .join(&self.config.separator);
+
+        match self.config.action {
+            RelabelAction::Keep => self.regex.is_match(&source),
+            RelabelAction::Drop => !self.regex.is_match(&source),
+            RelabelAction::Replace => {
+                if let (Some(caps), Some(target)) =
+                    (self.regex.captures(&source), &self.config.target_label)
+                {
+                    let mut value = String::new();
+                    caps.expand(&self.config.replacement, &mut value);
+
+                    labels.retain(|(k, _)| k != target);
+                    if !value.is_empty() {
+                        labels.push((target.to_string(), value));
+                    }
+                }
+                true
+            }
+            RelabelAction::LabelDrop => {
+                labels.retain(|(k, _)| k == "__name__" || !self.regex.is_match(k));
+                true
+            }
+            RelabelAction::LabelKeep => {
+                labels.retain(|(k, _)| k == "__name__" || self.regex.is_match(k));
+                true
+            }
+        }
+    }
+
+    /// Apply a list of rules to a snapshot returning None if it is dropped
+    pub(crate) fn relabel(
+        rules: &[RelabelRule],
+        snap: &CounterSnapshot,
+    ) -> Option<CounterSnapshot> {
+        if rules.is_empty() {
+            return Some(snap.clone());
+        }
+
+        let (name, mut labels) = match MetricSelector::parse_series(&snap.name) {
+            Ok(v) => v,
+            Err(e) => {
+                log::debug!("Not relabeling {} : {}", snap.name, e);
+                return Some(snap.clone());
+            }
+        };
+
+        labels.insert(0, ("__name__".to_string(), name));
+
+        for r in rules {
+            if !r.apply(&mut labels) {
+                return None;
+            }
+        }
+
+        let name = RelabelRule::get(&labels, "__name__").to_string();
+
+        if name.is_empty() {
+            return None;
+        }
+
+        /* Labels starting with __ are internal to relabeling */
+        let labels: Vec<(String, String)> = labels
+            .into_iter()
+            .filter(|(k, _)| !k.starts_with("__"))
+            .collect();
+
+        Some(CounterSnapshot::new(
+            name,
+            &labels,
+            snap.doc.to_string(),
+            snap.ctype.clone(),
+        ))
+    }
+}
+
+/*****************
+ * SCRAPE CONFIG *
+ *****************/
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ScrapeTargetType {
+    Proxy,
+    Prometheus,
+    System,
+    File,
+    Cgroup,
+    Sysfs,
+    Processes,
+}
+
+/// Format of the files tailed by file targets
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FileFormat {
+    /// One JSON object of values per line
+    Jsonl,
+    /// Comma separated values with a header line
+    Csv,
+    /// Prometheus text exposition, re-read when it changes
+    Prometheus,
+}
+
+/// Groups of metrics collected by system targets
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SystemCollector {
+    /// Usage of the mounted filesystems
+    Disks,
+    /// Block device statistics from /proc/diskstats
+    Diskstats,
+    Network,
+    Temperatures,
+    Memory,
+    /// Host, kernel and uptime information
+    Info,
+    Cpu,
+    /// Pressure stall information from /proc/pressure
+    Pressure,
+    /// Paging, swapping and OOM kills from /proc/vmstat
+    Vmstat,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CounterFileType {
+    #[default]
+    Counter,
+    Gauge,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Files holding a value exported by sysfs targets
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct CounterFileConfig {
+    /// Glob of the files relative to the root, any path segment may use * and ?
+    pub(crate) glob: String,
+    /// Name of the metric
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) doc: Option<String>,
+    /// Regular expression applied to the path, named groups become labels
+    #[serde(default)]
+    pub(crate) labels: Option<String>,
+    #[serde(default, rename = "type")]
+    pub(crate) ctype: CounterFileType,
+    /// Factor applied to the value read
+    #[serde(default = "default_scale")]
+    pub(crate) scale: f64,
+    /// In files of "key value" lines, the key of the line to read
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// Index of the value in the whitespace separated fields of the line
+    #[serde(default)]
+    pub(crate) field: Option<usize>,
+    /// File next to a counter holding the value at which it wraps
+    #[serde(default)]
+    pub(crate) range: Option<String>,
+}
+
+fn default_honor_timestamps() -> bool {
+    true
+}
+
+/// HTTP basic authentication of a scrape target
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct BasicAuthConfig {
+    username: String,
+    #[serde(default)]
+    password: Option<String>,
+    /// File holding the password, read when the configuration is loaded
+    #[serde(default)]
+    password_file: Option<PathBuf>,
+}
+
+/// Credentials sent along the scrape requests
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ScrapeAuth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+/// Local scrapes which are always running, configuring them
+/// only changes their settings and removing them restores the defaults
+pub(crate) const BUILTIN_SCRAPES: [&str; 3] = ["/system", "/processes", "/cgroup"];
+
+/// A static scrape target of the configuration file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ScrapeTargetConfig {
+    /// Address (host:port) or URL of the target, optionnal for system
+    #[serde(default)]
+    pub(crate) target: String,
+    /// Type of the target, detected when not set
+    #[serde(default, rename = "type")]
+    pub(crate) ttype: Option<ScrapeTargetType>,
+    /// Scraping period in seconds (0 for the proxy period)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) period: f64,
+    /// Timeout of scrape requests in seconds (0 for the default)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) timeout: f64,
+    /// Consecutive failures before dropping the target (0 for never)
+    #[serde(default)]
+    pub(crate) failure_budget: Option<u64>,
+    /// Path of the Prometheus metrics when the target is host:port
+    #[serde(default)]
+    pub(crate) metrics_path: Option<String>,
+    /// Use the timestamps exposed by the target instead of the scrape time
+    #[serde(default = "default_honor_timestamps")]
+    pub(crate) honor_timestamps: bool,
+    #[serde(default)]
+    basic_auth: Option<BasicAuthConfig>,
+    #[serde(default)]
+    bearer_token: Option<String>,
+    #[serde(default)]
+    bearer_token_file: Option<PathBuf>,
+    /// Job receiving the values of file targets
+    #[serde(default)]
+    pub(crate) job: Option<String>,
+    /// Format of file targets, detected from the extension when not set
+    #[serde(default)]
+    pub(crate) format: Option<FileFormat>,
+    /// Prefix of the metrics read from file targets
+    #[serde(default)]
+    pub(crate) prefix: Option<String>,
+    /// Root of the files read by local targets (procfs for system and processes)
+    #[serde(default)]
+    pub(crate) root: Option<PathBuf>,
+    /// Path of the cgroup of a job relative to the root
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// Metric groups of system targets (all when not set)
+    #[serde(default)]
+    pub(crate) collectors: Option<Vec<SystemCollector>>,
+    /// Files read by sysfs targets
+    #[serde(default)]
+    pub(crate) files: Vec<CounterFileConfig>,
+    #[serde(default)]
+    pub(crate) relabel: Vec<RelabelConfig>,
+}
+
+impl ScrapeTargetConfig {
+    /// Name identifying the target across reloads
+    pub(crate) fn key(&self) -> String {
+        match self.ttype {
+            Some(ScrapeTargetType::System) if self.target.is_empty() => "/system".to_string(),
+            Some(ScrapeTargetType::Cgroup) if self.target.is_empty() => "/cgroup".to_string(),
+            Some(ScrapeTargetType::Sysfs) if self.target.is_empty() => "/sysfs".to_string(),
+            Some(ScrapeTargetType::Processes) if self.target.is_empty() => "/processes".to_string(),
+            _ => self.target.to_string(),
+        }
+    }
+
+    /// True when the target can be updated in place from the other config
+    pub(crate) fn same_source(&self, other: &ScrapeTargetConfig) -> bool {
+        self.ttype == other.ttype
+            && self.metrics_path == other.metrics_path
+            && self.job == other.job
+            && self.format == other.format
+            && self.prefix == other.prefix
+            && self.root == other.root
+            && self.path == other.path
+            && self.collectors == other.collectors
+            && self.files == other.files
+    }
+
+    pub(crate) fn relabel_rules(&self) -> Result<Vec<RelabelRule>, ProxyErr> {
+        self.relabel.iter().map(RelabelRule::new).collect()
+    }
+
+    pub(crate) fn read_secret(path: &PathBuf) -> Result<String, ProxyErr> {
+        fs::read_to_string(path)
+            .map(|s| s.trim().to_string())
+            .map_err(|e| ProxyErr::new(format!("Failed to read {} : {}", path.display(), e)))
+    }
+
+    /// Credentials of the target if any, secret files are read here
+    pub(crate) fn auth(&self) -> Result<Option<ScrapeAuth>, ProxyErr> {
+        match (
+            &self.basic_auth,
+            &self.bearer_token,
+            &self.bearer_token_file,
+        ) {
+            (None, None, None) => Ok(None),
+            (Some(basic), None, None) => {
+                let password = match (&basic.password, &basic.password_file) {
+                    (Some(_), Some(_)) => {
+                        return Err(ProxyErr::new(
+                            "Only one of 'password' and 'password_file' can be set",
+                        ))
+                    }
+                    (Some(p), None) => Some(p.to_string()),
+                    (None, Some(f)) => Some(ScrapeTargetConfig::read_secret(f)?),
+                    (None, None) => None,
+                };
+
+                Ok(Some(ScrapeAuth::Basic {
+                    username: basic.username.to_string(),
+                    password,
+                }))
+            }
+            (None, Some(token), None) => Ok(Some(ScrapeAuth::Bearer(token.to_string()))),
+            (None, None, Some(f)) => Ok(Some(ScrapeAuth::Bearer(ScrapeTargetConfig::read_secret(
+                f,
+            )?))),
+            _ => Err(ProxyErr::new(
+                "Only one of 'basic_auth', 'bearer_token' and 'bearer_token_file' can be set",
+            )),
+        }
+    }
+}
+
+/// Content of the proxy configuration file
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct ProxyConfig {
+    #[serde(default)]
+    pub(crate) scrapes: Vec<ScrapeTargetConfig>,
+    /// Files listing targets which are watched for changes
+    #[serde(default)]
+    pub(crate) discovery: Vec<PathBuf>,
+}
+
+impl ProxyConfig {
+    pub(crate) fn load(path: &PathBuf) -> Result<ProxyConfig, Box<dyn Error>> {
+        let data = fs::read_to_string(path)?;
+        let config: ProxyConfig = serde_json::from_str(&data)?;
+
+        let mut seen: HashSet<String> = HashSet::new();
+
+        for s in config.scrapes.iter() {
+            if s.key().is_empty() {
+                return Err(ProxyErr::newboxed("A scrape target needs a 'target'"));
+            }
+
+            if !seen.insert(s.key()) {
+                return Err(ProxyErr::newboxed(format!(
+                    "Scrape target {} is listed twice",
+                    s.key()
+                )));
+            }
+
+            s.relabel_rules()?;
+            s.auth()?;
+
+            if s.ttype == Some(ScrapeTargetType::File) && s.job.is_none() {
+                return Err(ProxyErr::newboxed(format!(
+                    "File target {} needs a 'job'",
+                    s.key()
+                )));
+            }
+
+            if s.ttype == Some(ScrapeTargetType::Sysfs) {
+                CounterFiles::new(s.root.as_ref(), &s.files)?;
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Outcome of a configuration reload
+#[derive(Serialize, Default, Debug)]
+pub(crate) struct ConfigReload {
+    pub(crate) added: Vec<String>,
+    pub(crate) updated: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) failed: Vec<(String, String)>,
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reload the configuration of the factory when the proxy receives SIGHUP
+#[allow(unused)]
+pub(crate) fn reload_on_sighup(factory: Arc<ExporterFactory>) {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+
+    thread::spawn(move || loop {
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            log::info!("Got SIGHUP reloading configuration");
+            match ExporterFactory::reload_config(factory.clone()) {
+                Ok(r) => log::info!(
+                    "Configuration reloaded : {} added, {} updated, {} removed, {} failed",
+                    r.added.len(),
+                    r.updated.len(),
+                    r.removed.len(),
+                    r.failed.len()
+                ),
+                Err(e) => log::error!("Failed to reload configuration : {}", e),
+            }
+        }
+        sleep(Duration::from_millis(500));
+    });
+}
@@ -0,0 +1,238 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::{CounterFileConfig, CounterFileType};
+use crate::filetail::FileTail;
+use crate::proxy_common::{unix_ts_us, ProxyErr};
+use crate::proxywireprotocol::{CounterSnapshot, CounterType};
+
+/// A path segment of a glob
+enum GlobSegment {
+    Name(String),
+    Pattern(Regex),
+}
+
+/// A configured file entry ready to be scraped
+struct CounterFile {
+    config: CounterFileConfig,
+    segments: Vec<GlobSegment>,
+    labels: Option<Regex>,
+}
+
+impl CounterFile {
+    fn new(config: &CounterFileConfig) -> Result<CounterFile, ProxyErr> {
+        let segments = Path::new(&config.glob)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(n) => Some(n.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .map(|n| {
+                if n.contains(['*', '?']) {
+                    Ok(GlobSegment::Pattern(FileTail::glob_regex(&n)?))
+                } else {
+                    Ok(GlobSegment::Name(n))
+                }
+            })
+            .collect::<Result<Vec<GlobSegment>, ProxyErr>>()?;
+
+        if segments.is_empty() {
+            return Err(ProxyErr::new(format!("Bad glob '{}'", config.glob)));
+        }
+
+        let labels = match &config.labels {
+            Some(l) => Some(
+                Regex::new(l)
+                    .map_err(|e| ProxyErr::new(format!("Bad labels regex {} : {}", l, e)))?,
+            ),
+            None => None,
+        };
+
+        Ok(CounterFile {
+            config: config.clone(),
+            segments,
+            labels,
+        })
+    }
+
+    /// Files matching the glob under the root
+    fn matching(&self, root: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![root.to_path_buf()];
+
+        for segment in self.segments.iter() {
+            paths = match segment {
+                GlobSegment::Name(n) => paths.iter().map(|p| p.join(n)).collect(),
+                GlobSegment::Pattern(regex) => paths
+                    .iter()
+                    .filter_map(|p| fs::read_dir(p).ok())
+                    .flat_map(|entries| {
+                        entries
+                            .filter_map(|e| e.ok())
+                            .filter(|e| regex.is_match(&e.file_name().to_string_lossy()))
+                            .map(|e| e.path())
+                    })
+                    .collect(),
+            };
+        }
+
+        paths.retain(|p| p.is_file());
+        paths.sort();
+        paths
+    }
+
+    /// Value of a file, either its only value or a field of a keyed line
+    fn value(&self, data: &str) -> Option<f64> {
+        let line = match &self.config.key {
+            Some(key) => data
+                .lines()
+                .find(|l| l.split_whitespace().next() == Some(key.as_str()))?,
+            None => data.trim(),
+        };
+
+        let field = self
+            .config
+            .field
+            .unwrap_or(if self.config.key.is_some() { 1 } else { 0 });
+
+        line.split_whitespace()
+            .nth(field)?
+            .parse::<f64>()
+            .ok()
+            .map(|v| v * self.config.scale)
+    }
+
+    /// Labels extracted from the path relative to the root
+    fn labels(&self, path: &str) -> Vec<(String, String)> {
+        let regex = match &self.labels {
+            Some(r) => r,
+            None => return Vec::new(),
+        };
+
+        let captures = match regex.captures(path) {
+            Some(c) => c,
+            None => return Vec::new(),
+        };
+
+        regex
+            .capture_names()
+            .flatten()
+            .filter_map(|n| Some((n.to_string(), captures.name(n)?.as_str().to_string())))
+            .collect()
+    }
+}
+
+/// Last value read from a counter file and what was lost in its wraps
+struct Wrap {
+    last: f64,
+    offset: f64,
+}
+
+/// Reads the values exposed as files by the kernel or drivers
+/// (sysfs, procfs) as configured by sysfs targets
+pub(crate) struct CounterFiles {
+    root: PathBuf,
+    files: Vec<CounterFile>,
+    wraps: HashMap<PathBuf, Wrap>,
+}
+
+impl CounterFiles {
+    pub(crate) fn new(
+        root: Option<&PathBuf>,
+        files: &[CounterFileConfig],
+    ) -> Result<CounterFiles, ProxyErr> {
+        if files.is_empty() {
+            return Err(ProxyErr::new("Sysfs targets need 'files'"));
+        }
+
+        Ok(CounterFiles {
+            root: root.cloned().unwrap_or_else(|| PathBuf::from("/")),
+            files: files
+                .iter()
+                .map(CounterFile::new)
+                .collect::<Result<Vec<CounterFile>, ProxyErr>>()?,
+            wraps: HashMap::new(),
+        })
+    }
+
+    /// Running total of a counter file, when the value goes down the counter
+    /// wrapped at its range (or was reset when it has none) and what was
+    /// counted before is kept
+    fn unwrap(
+        wraps: &mut HashMap<PathBuf, Wrap>,
+        file: &CounterFile,
+        path: &Path,
+        value: f64,
+    ) -> f64 {
+        let range = file.config.range.as_ref().and_then(|r| {
+            let data = fs::read_to_string(path.with_file_name(r)).ok()?;
+            file.value(&data)
+        });
+
+        let wrap = wraps.entry(path.to_path_buf()).or_insert(Wrap {
+            last: value,
+            offset: 0.0,
+        });
+
+        if value < wrap.last {
+            wrap.offset += range.unwrap_or(wrap.last);
+        }
+
+        wrap.last = value;
+        wrap.offset + value
+    }
+
+    pub(crate) fn scrape(&mut self) -> Vec<CounterSnapshot> {
+        let mut ret = Vec::new();
+
+        for file in self.files.iter() {
+            let doc = file
+                .config
+                .doc
+                .clone()
+                .unwrap_or_else(|| format!("Read from {}", file.config.glob));
+
+            for path in file.matching(&self.root) {
+                let value = match fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|data| file.value(&data))
+                {
+                    Some(v) => v,
+                    None => {
+                        log::debug!("No value in {} for {}", path.display(), file.config.name);
+                        continue;
+                    }
+                };
+
+                /* Labels are matched on the path as it would be without the root */
+                let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                let labels = file.labels(&format!("/{}", relative.display()));
+
+                let ctype = match file.config.ctype {
+                    CounterFileType::Counter => CounterType::Counter {
+                        ts: unix_ts_us(),
+                        value: CounterFiles::unwrap(&mut self.wraps, file, &path, value),
+                    },
+
+                    CounterFileType::Gauge => CounterType::Gauge {
+                        min: value,
+                        max: value,
+                        hits: 1.0,
+                        total: value,
+                    },
+                };
+
+                ret.push(CounterSnapshot::new(
+                    file.config.name.to_string(),
+                    labels.as_slice(),
+                    doc.to_string(),
+                    ctype,
+                ));
+            }
+        }
+
+        ret
+    }
+}
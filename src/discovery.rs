@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::alarms::deserialize_duration;
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::{self, unix_ts};
+
+/// Delay in ms before adding again the targets which failed
+const DISCOVERY_RETRY: u64 = 30 * 1000;
+
+/// Group of targets of a discovery file, as in Prometheus file_sd_configs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct TargetGroup {
+    pub(crate) targets: Vec<String>,
+    /// Labels added to all the values scraped from the targets
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+    /// Scraping period in seconds (0 for the proxy period)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) period: f64,
+}
+
+/// Settings of a discovered target, changing them recreates its scrape
+#[derive(Clone, PartialEq)]
+struct DiscoveredTarget {
+    period: u64,
+    labels: Vec<(String, String)>,
+}
+
+/// Last content read from a discovery file
+struct DiscoveryFile {
+    mtime: Option<SystemTime>,
+    groups: Vec<TargetGroup>,
+}
+
+#[derive(Default)]
+struct DiscoveryState {
+    files: HashMap<PathBuf, DiscoveryFile>,
+    /// Scraped targets with the key of their scrape
+    targets: HashMap<String, (DiscoveredTarget, String)>,
+    /// Targets which could not be added
+    failed: Vec<String>,
+    /// Time in ms of the last update of the scrapes
+    last_update: u64,
+}
+
+/// Keeps the scrapes in line with the targets listed in discovery files
+pub(crate) struct Discovery {
+    paths: Mutex<Vec<PathBuf>>,
+    state: Mutex<DiscoveryState>,
+}
+
+impl Discovery {
+    pub(crate) fn new() -> Discovery {
+        Discovery {
+            paths: Mutex::new(Vec::new()),
+            state: Mutex::new(DiscoveryState::default()),
+        }
+    }
+
+    /// Change the watched files, applied at the next refresh
+    pub(crate) fn set_files(&self, paths: Vec<PathBuf>) {
+        *self.paths.lock().unwrap() = paths;
+    }
+
+    /// Discovery files are YAML or JSON lists of target groups
+    fn load(path: &Path) -> Result<Vec<TargetGroup>, Box<dyn Error>> {
+        let data = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(&data)?),
+            _ => Ok(serde_json::from_str(&data)?),
+        }
+    }
+
+    /// Read the files which changed, returns true if any did
+    fn reload_files(state: &mut DiscoveryState, paths: &[PathBuf]) -> bool {
+        let count = state.files.len();
+        state.files.retain(|p, _| paths.contains(p));
+        let mut changed = count != state.files.len();
+
+        for path in paths {
+            let mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+
+            let previous = state.files.get(path);
+
+            if previous.map(|f| f.mtime) == Some(mtime) {
+                continue;
+            }
+
+            let groups = match mtime {
+                /* The file was removed */
+                None => Vec::new(),
+                Some(_) => match Discovery::load(path) {
+                    Ok(g) => g,
+                    Err(e) => {
+                        /* Keep the targets of a file being rewritten */
+                        log::error!("Failed to load discovery file {} : {}", path.display(), e);
+                        previous.map(|f| f.groups.clone()).unwrap_or_default()
+                    }
+                },
+            };
+
+            state
+                .files
+                .insert(path.to_path_buf(), DiscoveryFile { mtime, groups });
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Targets of all the files, the first occurence of a target wins
+    fn wanted(state: &DiscoveryState, paths: &[PathBuf]) -> Vec<(String, DiscoveredTarget)> {
+        let mut ret: Vec<(String, DiscoveredTarget)> = Vec::new();
+
+        for group in paths
+            .iter()
+            .filter_map(|p| state.files.get(p))
+            .flat_map(|f| f.groups.iter())
+        {
+            let settings = DiscoveredTarget {
+                period: if group.period > 0.0 {
+                    (group.period * 1000.0) as u64
+                } else {
+                    proxy_common::get_proxy_period()
+                },
+                labels: group
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            };
+
+            for target in group.targets.iter() {
+                if !ret.iter().any(|(t, _)| t == target) {
+                    ret.push((target.to_string(), settings.clone()));
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Apply the changes of the discovery files to the scrapes
+    pub(crate) fn refresh(&self, factory: &Arc<ExporterFactory>) {
+        let paths = self.paths.lock().unwrap().clone();
+        let mut state = self.state.lock().unwrap();
+
+        let changed = Discovery::reload_files(&mut state, &paths);
+        /* Targets dropped after exhausting their failure budget are retried too */
+        let retry = unix_ts() - state.last_update > DISCOVERY_RETRY
+            && (!state.failed.is_empty()
+                || state
+                    .targets
+                    .values()
+                    .any(|(_, key)| !factory.has_scrape(key)));
+
+        if !changed && !retry {
+            return;
+        }
+
+        state.last_update = unix_ts();
+        state.failed.clear();
+
+        let wanted = Discovery::wanted(&state, &paths);
+
+        /* Dropping a scrape releases the jobs it was contributing to */
+        let removed: Vec<String> = state
+            .targets
+            .keys()
+            .filter(|t| !wanted.iter().any(|(w, _)| w == *t))
+            .cloned()
+            .collect();
+
+        for target in removed {
+            if let Some((_, key)) = state.targets.remove(&target) {
+                log::info!("Removing discovered target {}", target);
+                factory.remove_scrape(&key);
+            }
+        }
+
+        for (target, settings) in wanted {
+            /* Targets dropped after exhausting their failure budget are added again */
+            if let Some((previous, key)) = state.targets.get(&target) {
+                if *previous == settings && factory.has_scrape(key) {
+                    continue;
+                }
+            }
+
+            match ExporterFactory::add_labeled_scrape(
+                factory.clone(),
+                &target,
+                settings.period,
+                &settings.labels,
+            ) {
+                Ok(key) => {
+                    log::info!("Adding discovered target {}", target);
+                    if let Some((_, old)) = state.targets.insert(target, (settings, key.clone())) {
+                        if old != key {
+                            factory.remove_scrape(&old);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to add discovered target {} : {}", target, e);
+                    state.failed.push(target);
+                }
+            }
+        }
+    }
+}
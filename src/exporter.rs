@@ -1,40 +1,63 @@
-use retry::{delay::Fixed, retry};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::{Arc, Mutex, RwLock};
-use std::thread::sleep;
+use std::thread::{self, sleep};
 use std::time::Duration;
 
+use crate::alarms::{
+    AlarmHistory, AlarmHistoryEntry, AlarmHistoryFilter, AlarmModel, AlarmSpec, AlarmTemplate,
+    AlarmTemplateStore, FanoutAlarm, PropagatedAlarm, RemoteAlarms, ValueAlarm, ValueAlarmTrigger,
+};
+use crate::config::{ConfigReload, ProxyConfig, RelabelRule, ScrapeTargetConfig, BUILTIN_SCRAPES};
+use crate::discovery::Discovery;
+use crate::forwarded::ForwardedSources;
 use crate::proxy_common;
 use crate::proxywireprotocol::{
-    ApiResponse, CounterSnapshot, CounterType, JobDesc, JobProfile, ValueAlarm, ValueAlarmTrigger,
+    CounterSnapshot, CounterType, DeltaBatch, JobChanges, JobDesc, JobProfile,
 };
 
 use crate::profiles::ProfileView;
 use crate::trace::{Trace, TraceView};
 
-use super::proxy_common::{hostname, ProxyErr};
+use super::proxy_common::{hostname, unix_ts, ProxyErr};
 
-use crate::scrapper::{ProxyScraper, ProxyScraperSnapshot};
+use crate::scrapper::{ProxyScraper, ProxyScraperSnapshot, ScrapeTask};
 
 /***********************
  * PROMETHEUS EXPORTER *
  ***********************/
 
+/// Sequence number of the last change of a value in any exporter
+static CHANGE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Sequence number of a new change
+fn next_change() -> u64 {
+    CHANGE_SEQ.fetch_add(1, Ordering::SeqCst) + 1
+}
+
 /// This is a refcounted reference to a counter and
 /// its documentation this allows to lock at counter
 /// granularity if needed
 struct ExporterEntry {
     value: Arc<RwLock<CounterSnapshot>>,
+    /// Sequence number of the last change of the value
+    seq: AtomicU64,
 }
 
 impl ExporterEntry {
     fn new(value: CounterSnapshot) -> ExporterEntry {
         ExporterEntry {
             value: Arc::new(RwLock::new(value)),
+            seq: AtomicU64::new(next_change()),
         }
     }
+
+    fn touch(&self) {
+        self.seq.store(next_change(), Ordering::SeqCst);
+    }
 }
 
 /// This is a group of values used to have counters with the
@@ -83,6 +106,7 @@ impl ExporterEntryGroup {
             Some(v) => {
                 let mut val = v.value.write().unwrap();
                 *val = value;
+                v.touch();
                 Ok(())
             }
             None => Err(ProxyErr::new("Failed to set counter")),
@@ -101,6 +125,7 @@ impl ExporterEntryGroup {
                 } else {
                     val.set(snapshot)?;
                 }
+                v.touch();
                 Ok(())
             }
             None => Err(ProxyErr::new(
@@ -123,6 +148,16 @@ impl ExporterEntryGroup {
         Ok(ret)
     }
 
+    /// References to all the values of the group
+    fn series(&self) -> Vec<(String, Arc<RwLock<CounterSnapshot>>)> {
+        self.ht
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.value.clone()))
+            .collect()
+    }
+
     /// Insert a new value in the counter list
     fn push(&self, snapshot: CounterSnapshot) -> Result<(), ProxyErr> {
         let name = snapshot.name.to_string();
@@ -146,10 +181,23 @@ impl ExporterEntryGroup {
     fn serialize(&self) -> Result<String, ProxyErr> {
         let mut ret: String = String::new();
 
+        let ht = self.ht.read().unwrap();
+
+        /* Values of a group share their type */
+        let mtype = match ht
+            .values()
+            .next()
+            .map(|e| e.value.read().unwrap().ctype.clone())
+        {
+            Some(CounterType::Counter { .. }) => "counter",
+            Some(CounterType::Gauge { .. }) => "gauge",
+            None => "untyped",
+        };
+
         ret += format!("# HELP {} {}\n", self.basename, self.doc).as_str();
-        ret += format!("# TYPE {} counter\n", self.basename).as_str();
+        ret += format!("# TYPE {} {}\n", self.basename, mtype).as_str();
 
-        for (_, exporter_counter) in self.ht.read().unwrap().iter() {
+        for (_, exporter_counter) in ht.iter() {
             // Acquire the Mutex for this specific ExporterEntry
             let value = exporter_counter.value.read().unwrap();
             ret += value.serialize().as_str();
@@ -172,6 +220,17 @@ impl ExporterEntryGroup {
 
         Ok(ret)
     }
+
+    /// Values changed after a sequence number
+    fn changes(&self, since: u64) -> Vec<CounterSnapshot> {
+        self.ht
+            .read()
+            .unwrap()
+            .values()
+            .filter(|e| e.seq.load(Ordering::SeqCst) > since)
+            .map(|e| e.value.read().unwrap().clone())
+            .collect()
+    }
 }
 
 /// An exporter is the central metric storage structure
@@ -185,6 +244,10 @@ pub(crate) struct Exporter {
     ht: RwLock<HashMap<String, ExporterEntryGroup>>,
     /// List of alarms each refering to a counter
     alarms: RwLock<HashMap<String, ValueAlarm>>,
+    /// Alarms waiting for their metrics to appear
+    unbound_alarms: RwLock<Vec<AlarmSpec>>,
+    /// Alarms on series selectors by name
+    fanout_alarms: RwLock<HashMap<String, FanoutAlarm>>,
 }
 
 impl Exporter {
@@ -192,6 +255,8 @@ impl Exporter {
         Exporter {
             ht: RwLock::new(HashMap::new()),
             alarms: RwLock::new(HashMap::new()),
+            unbound_alarms: RwLock::new(Vec::new()),
+            fanout_alarms: RwLock::new(HashMap::new()),
         }
     }
 
@@ -282,53 +347,175 @@ impl Exporter {
         Ok(ret)
     }
 
-    pub(crate) fn add_alarm(
-        &self,
-        name: String,
-        metric: String,
-        op: String,
-        value: f64,
-    ) -> Result<(), ProxyErr> {
-        let cnt: Arc<RwLock<CounterSnapshot>> = self.get(&metric)?;
-        let alarm = ValueAlarm::new(&name, cnt, op, value)?;
+    /// Profile holding only the values changed after a sequence number
+    pub(crate) fn changes(&self, desc: &JobDesc, since: u64) -> JobProfile {
+        JobProfile {
+            desc: desc.clone(),
+            counters: self
+                .ht
+                .read()
+                .unwrap()
+                .values()
+                .flat_map(|g| g.changes(since))
+                .collect(),
+        }
+    }
+
+    /// Add an alarm, if its metrics do not exist yet the alarm is kept
+    /// aside and bound when they first appear, false is returned and it
+    /// is listed as unbound until then
+    pub(crate) fn add_alarm(&self, spec: AlarmSpec) -> Result<bool, ProxyErr> {
+        spec.validate()?;
 
-        log::info!("Adding new alarm {}", alarm);
+        let name = spec.name.to_string();
 
         let mut lht = self.alarms.write().unwrap();
+        let mut unbound = self.unbound_alarms.write().unwrap();
+        let fanout = self.fanout_alarms.read().unwrap();
 
-        if lht.contains_key(&name) {
+        if lht.contains_key(&name)
+            || unbound.iter().any(|s| s.name == name)
+            || fanout.contains_key(&name)
+        {
             return Err(ProxyErr::new(format!("Alarm {} is already defined", name)));
         }
 
-        lht.insert(name, alarm);
+        match ValueAlarm::new(spec.clone(), |metric| self.get(metric)) {
+            Ok(alarm) => {
+                log::info!("Adding new alarm {}", alarm);
+                lht.insert(name, alarm);
+                Ok(true)
+            }
+            Err(e) => {
+                log::info!("Alarm {} is waiting for its metrics ({})", name, e);
+                unbound.push(spec);
+                Ok(false)
+            }
+        }
+    }
+
+    /// All the series of a basename
+    fn series(&self, basename: &String) -> Vec<(String, Arc<RwLock<CounterSnapshot>>)> {
+        match self.ht.read().unwrap().get(basename) {
+            Some(group) => group.series(),
+            None => Vec::new(),
+        }
+    }
 
-        Ok(())
+    /// Bind alarms which metrics are now present and fan
+    /// out selector alarms over their new matching series
+    fn bind_alarms(&self) {
+        if self.unbound_alarms.read().unwrap().is_empty()
+            && self.fanout_alarms.read().unwrap().is_empty()
+        {
+            return;
+        }
+
+        /* Same locking order as in add_alarm */
+        let mut lht = self.alarms.write().unwrap();
+        let mut unbound = self.unbound_alarms.write().unwrap();
+        let mut fanout = self.fanout_alarms.write().unwrap();
+
+        unbound.retain(
+            |spec| match ValueAlarm::new(spec.clone(), |metric| self.get(metric)) {
+                Ok(alarm) => {
+                    log::info!("Binding alarm {}", alarm);
+                    lht.insert(spec.name.to_string(), alarm);
+                    false
+                }
+                Err(_) => match FanoutAlarm::new(spec, |metric| self.get(metric).is_ok()) {
+                    Ok(Some(f)) => {
+                        fanout.insert(spec.name.to_string(), f);
+                        false
+                    }
+                    _ => true,
+                },
+            },
+        );
+
+        for f in fanout.values_mut() {
+            let series = self.series(f.basename());
+
+            for (name, alarm) in f.expand(series, |metric| self.get(metric)) {
+                log::info!("Binding alarm {}", alarm);
+                lht.insert(name, alarm);
+            }
+        }
     }
 
     pub(crate) fn delete_alarm(&self, alarm_name: &String) -> Result<(), ProxyErr> {
-        self.alarms
-            .write()
-            .unwrap()
-            .remove(alarm_name)
-            .ok_or(ProxyErr::new(format!(
+        let mut lht = self.alarms.write().unwrap();
+        let mut unbound = self.unbound_alarms.write().unwrap();
+        let mut fanout = self.fanout_alarms.write().unwrap();
+
+        /* Removing a selector alarm removes all its instances */
+        if let Some(f) = fanout.remove(alarm_name) {
+            for child in f.children() {
+                lht.remove(&child);
+            }
+            return Ok(());
+        }
+
+        if lht.remove(alarm_name).is_some() {
+            return Ok(());
+        }
+
+        let len = unbound.len();
+        unbound.retain(|s| &s.name != alarm_name);
+
+        if len == unbound.len() {
+            return Err(ProxyErr::new(format!(
                 "Failed to remove alarm {}",
                 alarm_name
-            )))?;
+            )));
+        }
+
         Ok(())
     }
 
+    /// Update the state of all the alarms returning the ones which changed
+    pub(crate) fn evaluate_alarms(&self, model: Option<&AlarmModel>) -> Vec<ValueAlarmTrigger> {
+        self.bind_alarms();
+
+        let mut alarmv = self.alarms.write().unwrap();
+
+        alarmv
+            .values_mut()
+            .filter_map(|a| a.evaluate(model))
+            .collect()
+    }
+
     pub(crate) fn check_alarms(&self) -> Vec<ValueAlarmTrigger> {
         let alarmv = self.alarms.read().unwrap();
 
-        let mut ret: Vec<ValueAlarmTrigger> = Vec::new();
+        alarmv
+            .values()
+            .filter(|a| a.is_active())
+            .map(|a| a.as_trigger())
+            .collect()
+    }
 
-        for (_, a) in alarmv.iter() {
-            if let Some(v) = a.check() {
-                ret.push(v);
-            }
-        }
+    pub(crate) fn list_alarms(&self) -> Vec<ValueAlarmTrigger> {
+        let alarmv = self.alarms.read().unwrap();
+        let unbound = self.unbound_alarms.read().unwrap();
+        let fanout = self.fanout_alarms.read().unwrap();
 
-        ret
+        /* Selector alarms are listed through their instances */
+        let waiting = fanout
+            .values()
+            .filter(|f| f.children().is_empty())
+            .map(|f| f.spec());
+
+        alarmv
+            .values()
+            .map(|a| a.as_trigger())
+            .chain(
+                unbound
+                    .iter()
+                    .chain(waiting)
+                    .map(|s| s.as_unbound_trigger()),
+            )
+            .collect()
     }
 }
 
@@ -346,6 +533,10 @@ struct PerJobRefcount {
     /// A job from a scrapper is not a local one
     /// It is used to only blame node-local metrics to local jobs
     islocal: bool,
+    /// PIDs of the local client processes of the job
+    pids: HashSet<u32>,
+    /// Change sequence number when the job was created
+    since: u64,
 }
 
 impl Drop for PerJobRefcount {
@@ -360,6 +551,28 @@ impl PerJobRefcount {
     }
 }
 
+/// Number of ended jobs kept to answer /job/delta
+const MAX_DEPARTURES: usize = 4096;
+
+#[derive(Default)]
+struct Departures {
+    /// Changes before this sequence number may miss ended jobs
+    floor: u64,
+    jobs: VecDeque<(u64, JobDesc)>,
+}
+
+impl Departures {
+    fn push(&mut self, desc: &JobDesc) {
+        self.jobs.push_back((next_change(), desc.clone()));
+
+        while self.jobs.len() > MAX_DEPARTURES {
+            if let Some((seq, _)) = self.jobs.pop_front() {
+                self.floor = seq;
+            }
+        }
+    }
+}
+
 /// This is the central pivot for metric and job management
 /// in the metric proxy all operations pass trough here
 /// and they are then dispatched to individual exporter instances
@@ -376,9 +589,9 @@ pub(crate) struct ExporterFactory {
     /// corresponding exporter
     perjob: Mutex<HashMap<String, PerJobRefcount>>,
     /// List of scrapres to be run a dedicated thread
-    /// will run them according to their polling
-    /// frequency
-    scrapes: Mutex<HashMap<String, ProxyScraper>>,
+    /// dispatches them to a pool of workers according
+    /// to their polling frequency
+    scrapes: Mutex<HashMap<String, Arc<ScrapeTask>>>,
     /// Pending scrapes to be backpushed
     pending_scrapes: Mutex<Vec<(String, ProxyScraper)>>,
     /// Instance of the profile manager
@@ -392,34 +605,73 @@ pub(crate) struct ExporterFactory {
     max_trace_size: usize,
     /// This is where the traces are stored
     pub trace_store: Arc<TraceView>,
+    /// Alarms to be instantiated on new jobs
+    alarm_templates: AlarmTemplateStore,
+    /// Log of all alarm transitions
+    alarm_history: AlarmHistory,
+    /// Alarms of the child proxies and the ones pushed down to them
+    remote_alarms: RemoteAlarms,
+    /// Configuration file listing static scrapes
+    config_path: Option<PathBuf>,
+    /// Scrapes from the configuration file with the key of their scraper
+    configured_scrapes: Mutex<HashMap<String, (ScrapeTargetConfig, String)>>,
+    /// Targets listed in the discovery files of the configuration
+    discovery: Discovery,
+    /// Jobs of the child proxies pushing their changes
+    forwarded: ForwardedSources,
+    /// Identifies this run of the proxy in change sequence numbers
+    epoch: u64,
+    /// Last jobs which ended with their change sequence number
+    departures: Mutex<Departures>,
 }
 
 impl ExporterFactory {
     /// This function if the mainloop of the scrapting thread
-    /// It runs infinitely every 1 second checking all scrapes
-    fn run_scrapping(&self) {
-        loop {
-            let mut to_delete: Vec<String> = Vec::new();
-
-            /* Scrape all the candidates */
-            if let Ok(scrapes) = self.scrapes.lock().as_mut() {
-                for (k, v) in scrapes.iter_mut() {
-                    if let Err(e) = v.scrape() {
-                        log::debug!("Failed to scrape {} : {}", k, e);
-                        to_delete.push(k.to_string());
+    /// It runs infinitely every 10ms dispatching the scrapes
+    /// which are due to a pool of workers
+    fn run_scrapping(factory: Arc<ExporterFactory>) {
+        let (tx, rx) = channel::<Arc<ScrapeTask>>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..proxy_common::get_scrape_workers() {
+            let rx = rx.clone();
+            let factory = factory.clone();
+            thread::spawn(move || loop {
+                let task = match rx.lock().unwrap().recv() {
+                    Ok(task) => task,
+                    Err(_) => return,
+                };
+
+                if let Err(e) = task.run() {
+                    if task.exhausted() {
+                        log::warn!("Dropping scrape of {} : {}", task.url(), e);
+                        factory.remove_scrape(task.url());
+                    } else {
+                        log::debug!("Failed to scrape {}, will retry : {}", task.url(), e);
                     }
                 }
+            });
+        }
+
+        loop {
+            let now = unix_ts();
 
+            if let Ok(scrapes) = factory.scrapes.lock().as_mut() {
                 /* Now backpush pending scrapes (traces might be added as we run) */
-                if let Ok(pending) = self.pending_scrapes.lock().as_mut() {
+                if let Ok(pending) = factory.pending_scrapes.lock().as_mut() {
                     for (name, scrape) in pending.drain(..) {
-                        scrapes.insert(name, scrape);
+                        scrapes.insert(name, Arc::new(ScrapeTask::new(scrape)));
                     }
                 }
 
-                /* Remove failed scrapes */
-                for k in to_delete {
-                    scrapes.remove(&k);
+                for task in scrapes.values() {
+                    if task.claim(now) {
+                        if tx.send(task.clone()).is_err() {
+                            log::error!("Scraping workers are gone");
+                        }
+                    } else if let Some(late) = task.overrun(now) {
+                        log::warn!("Scrape of {} is {} ms over its deadline", task.url(), late);
+                    }
                 }
             }
 
@@ -427,6 +679,41 @@ impl ExporterFactory {
         }
     }
 
+    /// Remove a scrape releasing the jobs it was contributing to
+    pub(crate) fn remove_scrape(&self, key: &String) {
+        let task = self.scrapes.lock().unwrap().remove(key);
+        self.remote_alarms.remove(key);
+
+        /* Out of the scrapes lock as we may wait for a running scrape */
+        if let Some(task) = task {
+            task.forget();
+        }
+    }
+
+    pub(crate) fn has_scrape(&self, key: &String) -> bool {
+        self.scrapes.lock().unwrap().contains_key(key)
+    }
+
+    /// This function is the mainloop of the discovery thread
+    /// it applies the changes of the discovery files every second
+    fn run_discovery(factory: Arc<ExporterFactory>) {
+        loop {
+            factory.discovery.refresh(&factory);
+            sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// This function is the mainloop of the alarm thread
+    /// alarms are evaluated every sampling period as rate
+    /// and durations are computed over the sampled values
+    fn run_alarms(&self) {
+        loop {
+            let changed = self.evaluate_alarms();
+            self.alarm_history.record(changed);
+            sleep(Duration::from_millis(proxy_common::get_proxy_period()));
+        }
+    }
+
     #[allow(unused)]
     /// Add a new scrape to the scrape list
     pub(crate) fn add_scrape(
@@ -434,13 +721,59 @@ impl ExporterFactory {
         url: &String,
         period: u64,
     ) -> Result<(), Box<dyn Error>> {
-        let new = ProxyScraper::new(url, period, factory.clone())?;
-        factory
+        ExporterFactory::add_labeled_scrape(factory, url, period, &[])?;
+        Ok(())
+    }
+
+    /// Add a new scrape adding the given labels to its values,
+    /// returns the key of the scrape in the scrape list
+    pub(crate) fn add_labeled_scrape(
+        factory: Arc<ExporterFactory>,
+        url: &String,
+        period: u64,
+        labels: &[(String, String)],
+    ) -> Result<String, Box<dyn Error>> {
+        let mut new = ProxyScraper::new(url, period, factory.clone())?;
+        new.set_labels(labels)?;
+
+        let key = new.url().to_string();
+
+        /* Scrapes of the configuration file are only changed by a reload */
+        let configured = factory.configured_scrapes.lock().unwrap();
+
+        if configured.values().any(|(_, k)| *k == key) {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is already scraped from the configuration file",
+                key
+            )));
+        }
+
+        let child_url = new.proxy_url().cloned();
+
+        let previous = factory
             .scrapes
             .lock()
             .unwrap()
-            .insert(new.url().to_string(), new);
-        Ok(())
+            .insert(key.clone(), Arc::new(ScrapeTask::new(new)));
+
+        drop(configured);
+
+        /* Joining proxies get the alarms pushed down the tree */
+        if let Some(child_url) = child_url {
+            factory.push_propagated_alarms(&child_url);
+        }
+
+        if let Some(task) = previous {
+            task.forget();
+        }
+
+        Ok(key)
+    }
+
+    /// Snapshot of a scrape, None if it is not in the scrape list
+    #[allow(unused)]
+    pub(crate) fn scrape_snapshot(&self, key: &String) -> Option<ProxyScraperSnapshot> {
+        self.scrapes.lock().unwrap().get(key).map(|t| t.snapshot())
     }
 
     #[allow(unused)]
@@ -456,53 +789,11 @@ impl ExporterFactory {
         ret
     }
 
-    #[allow(unused)]
-    /// This function is called when joining another proxy
-    ///
-    /// It will first request the target address from the root server
-    /// and then it will register itself in the returned address
-    /// This function is used to dynamically build the reduction tree
-    pub(crate) fn join(
-        root_server: &String,
-        my_server_address: &String,
-        period: u64,
-    ) -> Result<(), ProxyErr> {
-        let mut pivot_url = root_server.to_string() + "/pivot?from=" + my_server_address;
-
-        if !pivot_url.starts_with("http") {
-            pivot_url = format!("http://{}", pivot_url);
-        }
-
-        /* We add some delay as the root server may get smashed */
-        let resp = retry(Fixed::from_millis(2000).take(5), || {
-            ApiResponse::query(&pivot_url)
-        })?;
-
-        let target_url = "http://".to_string()
-            + resp.operation.as_str()
-            + "/join?to="
-            + my_server_address
-            + "&period="
-            + period.to_string().as_str();
-
-        /* We add some delay as the root server may get smashed */
-        match ApiResponse::query(&target_url) {
-            Ok(_) => {
-                log::info!(
-                    "Joining aggregating proxy {} with period {}",
-                    root_server,
-                    period
-                );
-                Ok(())
-            }
-            Err(e) => Err(ProxyErr::from(e)),
-        }
-    }
-
     pub(crate) fn new(
         profile_prefix: PathBuf,
         aggregate: bool,
         max_trace_size: usize,
+        config_path: Option<PathBuf>,
     ) -> Result<Arc<ExporterFactory>, Box<dyn Error>> {
         let main_jobdesc = JobDesc {
             jobid: "main".to_string(),
@@ -514,6 +805,9 @@ impl ExporterFactory {
             run_dir: "".to_string(),
             start_time: 0,
             end_time: 0,
+            user: "".to_string(),
+            pid: 0,
+            accounting: None,
         };
 
         let nodejob_desc = JobDesc {
@@ -526,6 +820,9 @@ impl ExporterFactory {
             run_dir: "".to_string(),
             start_time: 0,
             end_time: 0,
+            user: "".to_string(),
+            pid: 0,
+            accounting: None,
         };
 
         let trace_store = Arc::new(TraceView::new(&profile_prefix)?);
@@ -551,12 +848,33 @@ impl ExporterFactory {
             trace_store: trace_store.clone(),
             aggregator: aggregate,
             max_trace_size,
+            alarm_templates: AlarmTemplateStore::new(&profile_prefix)?,
+            alarm_history: AlarmHistory::new(&profile_prefix)?,
+            remote_alarms: RemoteAlarms::new(),
+            config_path,
+            configured_scrapes: Mutex::new(HashMap::new()),
+            discovery: Discovery::new(),
+            forwarded: ForwardedSources::new(),
+            epoch: unix_ts(),
+            departures: Mutex::new(Departures::default()),
         });
 
         let scrape_ref = ret.clone();
         // Start Scraping thread
         std::thread::spawn(move || {
-            scrape_ref.run_scrapping();
+            ExporterFactory::run_scrapping(scrape_ref);
+        });
+
+        let alarm_ref = ret.clone();
+        // Start Alarm evaluation thread
+        std::thread::spawn(move || {
+            alarm_ref.run_alarms();
+        });
+
+        let discovery_ref = ret.clone();
+        // Start the thread watching discovery files
+        std::thread::spawn(move || {
+            ExporterFactory::run_discovery(discovery_ref);
         });
 
         ret.insert_ftio_exporter(trace_store.clone(), &main_jobdesc.jobid)?;
@@ -568,6 +886,8 @@ impl ExporterFactory {
             exporter: ret.main.clone(),
             counter: 1,
             islocal: false,
+            pids: HashSet::new(),
+            since: next_change(),
         };
         ret.perjob
             .lock()
@@ -580,27 +900,147 @@ impl ExporterFactory {
             exporter: ret.pernode.clone(),
             counter: 1,
             islocal: false,
+            pids: HashSet::new(),
+            since: next_change(),
         };
         ret.perjob
             .lock()
             .unwrap()
             .insert(node_job.desc.jobid.to_string(), node_job);
 
-        /* Now insert the default system scrape */
-        let systemurl = "/system".to_string();
-        if let Ok(sys_metrics) =
-            ProxyScraper::new(&systemurl, proxy_common::get_proxy_period(), ret.clone())
-        {
-            ret.scrapes.lock().unwrap().insert(systemurl, sys_metrics);
+        /* Now insert the default system, process and cgroup scrapes */
+        for url in BUILTIN_SCRAPES {
+            let url = url.to_string();
+            if let Ok(local) =
+                ProxyScraper::new(&url, proxy_common::get_proxy_period(), ret.clone())
+            {
+                ret.scrapes
+                    .lock()
+                    .unwrap()
+                    .insert(url, Arc::new(ScrapeTask::new(local)));
+            }
         }
 
         /* Now insert tracing events */
         ret.insert_tracing(ret.main.clone(), main_job_trace)?;
         ret.insert_tracing(ret.pernode.clone(), node_job_trace)?;
 
+        /* And the static scrapes of the configuration */
+        if ret.config_path.as_ref().is_some_and(|p| p.is_file()) {
+            let report = ExporterFactory::reload_config(ret.clone())?;
+            for (target, e) in report.failed {
+                log::error!("Failed to add configured scrape {} : {}", target, e);
+            }
+        }
+
         Ok(ret)
     }
 
+    /// (Re)load the scrapes of the configuration file, only the targets
+    /// which were added, changed or removed from the file are affected
+    pub(crate) fn reload_config(
+        factory: Arc<ExporterFactory>,
+    ) -> Result<ConfigReload, Box<dyn Error>> {
+        let path = factory
+            .config_path
+            .as_ref()
+            .ok_or(ProxyErr::new("No configuration file for this proxy"))?;
+
+        let config = ProxyConfig::load(path)?;
+
+        /* Discovered targets are updated by the discovery thread */
+        factory.discovery.set_files(config.discovery.clone());
+
+        let mut report = ConfigReload::default();
+        let mut configured = factory.configured_scrapes.lock().unwrap();
+
+        /* Targets which left the configuration */
+        let new_keys: Vec<String> = config.scrapes.iter().map(|s| s.key()).collect();
+        let removed: Vec<String> = configured
+            .keys()
+            .filter(|k| !new_keys.contains(k))
+            .cloned()
+            .collect();
+
+        for target in removed {
+            if let Some((_, key)) = configured.remove(&target) {
+                if BUILTIN_SCRAPES.contains(&key.as_str()) {
+                    /* Builtin scrapes are always there only reset them */
+                    let local =
+                        ProxyScraper::new(&key, proxy_common::get_proxy_period(), factory.clone())?;
+                    let previous = factory
+                        .scrapes
+                        .lock()
+                        .unwrap()
+                        .insert(key, Arc::new(ScrapeTask::new(local)));
+                    if let Some(task) = previous {
+                        task.forget();
+                    }
+                } else {
+                    factory.remove_scrape(&key);
+                }
+                report.removed.push(target);
+            }
+        }
+
+        for target in config.scrapes {
+            let name = target.key();
+
+            /* Same type and address can be updated in place */
+            /* Targets dropped after exhausting their failure budget are recreated */
+            if let Some((previous, key)) = configured.get_mut(&name) {
+                let task = factory.scrapes.lock().unwrap().get(key).cloned();
+
+                if let Some(s) = task {
+                    if *previous == target {
+                        continue;
+                    }
+
+                    if previous.same_source(&target) {
+                        s.configure(&target)?;
+                        *previous = target;
+                        report.updated.push(name);
+                        continue;
+                    }
+                }
+            }
+
+            /* Created out of the scrape lock as the type may be detected */
+            let scraper = match ProxyScraper::from_config(&target, factory.clone()) {
+                Ok(s) => s,
+                Err(e) => {
+                    report.failed.push((name, e.to_string()));
+                    continue;
+                }
+            };
+
+            if let Some(child_url) = scraper.proxy_url() {
+                factory.push_propagated_alarms(child_url);
+            }
+
+            let key = scraper.url().to_string();
+            let mut scrapes = factory.scrapes.lock().unwrap();
+
+            let mut replaced = Vec::new();
+
+            if let Some((_, old_key)) = configured.insert(name.clone(), (target, key.clone())) {
+                replaced.extend(scrapes.remove(&old_key));
+                report.updated.push(name);
+            } else {
+                report.added.push(name);
+            }
+
+            replaced.extend(scrapes.insert(key, Arc::new(ScrapeTask::new(scraper))));
+            drop(scrapes);
+
+            for task in replaced {
+                task.forget();
+            }
+        }
+
+        Ok(report)
+    }
+
     fn insert_tracing(
         &self,
         exporter: Arc<Exporter>,
@@ -661,6 +1101,10 @@ impl ExporterFactory {
                 if tobesaved {
                     e.islocal = true;
                 }
+                /* Local clients report their PID */
+                if tobesaved && desc.pid != 0 {
+                    e.pids.insert(desc.pid);
+                }
                 log::debug!(
                     "ACQUIRING Per Job exporter {} has refcount {}",
                     &desc.jobid,
@@ -681,11 +1125,25 @@ impl ExporterFactory {
                     exporter: Arc::new(Exporter::new()),
                     counter: 1,
                     islocal: tobesaved,
+                    /* Local clients report their PID */
+                    pids: if tobesaved && desc.pid != 0 {
+                        HashSet::from([desc.pid])
+                    } else {
+                        HashSet::new()
+                    },
+                    since: next_change(),
                 };
 
                 /* Add the trace scrapping */
                 self.insert_tracing(new.exporter.clone(), trace).unwrap();
 
+                /* Instantiate matching alarm templates */
+                for spec in self.alarm_templates.instantiate(desc) {
+                    if let Err(e) = new.exporter.add_alarm(spec) {
+                        log::error!("Failed to add templated alarm to {} : {}", desc.jobid, e);
+                    }
+                }
+
                 self.insert_ftio_exporter(self.trace_store.clone(), &desc.jobid)
                     .unwrap_or(());
 
@@ -709,6 +1167,17 @@ impl ExporterFactory {
             .collect()
     }
 
+    /// Number of running jobs without the main and node summaries
+    #[allow(unused)]
+    pub(crate) fn job_count(&self) -> usize {
+        self.perjob
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|j| j.as_str() != "main" && !j.starts_with("Node: "))
+            .count()
+    }
+
     #[allow(unused)]
     pub(crate) fn profiles(&self, full: bool) -> Vec<JobProfile> {
         let mut ret: Vec<JobProfile> = Vec::new();
@@ -751,10 +1220,13 @@ impl ExporterFactory {
                     if self.aggregator {
                         let snap = perjob.exporter.profile(desc, false)?;
                         self.profile_store.saveprofile(snap, desc)?;
+                        self.profile_store.enrich_with_accounting(desc);
                         self.trace_store.done(desc)?;
                     }
                     /* Delete */
                     ht.remove(&desc.jobid);
+                    self.departures.lock().unwrap().push(desc);
+                    self.alarm_history.forget_job(&desc.jobid);
                 }
             }
         } else {
@@ -764,6 +1236,92 @@ impl ExporterFactory {
         Ok(())
     }
 
+    /// Merge the changes of the counters of a job learned from a child proxy
+    pub(crate) fn merge_profile(
+        &self,
+        delta: &JobProfile,
+        relabel: &[RelabelRule],
+    ) -> Result<(), ProxyErr> {
+        let exporter = self
+            .resolve_by_id(&delta.desc.jobid)
+            .ok_or(ProxyErr::new("No such JobID"))?;
+
+        for cnt in delta
+            .counters
+            .iter()
+            .filter_map(|c| RelabelRule::relabel(relabel, c))
+        {
+            exporter.push(&cnt)?;
+            exporter.accumulate(&cnt, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes of the jobs after a sequence number of the given epoch,
+    /// all the jobs are returned when they cannot be computed
+    #[allow(unused)]
+    pub(crate) fn changes(&self, since: u64, epoch: Option<u64>) -> JobChanges {
+        /* Values changed while listing are sent again next time */
+        let seq = CHANGE_SEQ.load(Ordering::SeqCst);
+
+        let departures = self.departures.lock().unwrap();
+
+        let full = since == 0 || epoch != Some(self.epoch) || since < departures.floor;
+
+        let mut ret = JobChanges {
+            epoch: self.epoch,
+            seq,
+            full,
+            jobs: Vec::new(),
+            left: Vec::new(),
+        };
+
+        if !full {
+            ret.left = departures
+                .jobs
+                .iter()
+                .filter(|(s, _)| *s > since)
+                .map(|(_, d)| d.clone())
+                .collect();
+        }
+
+        drop(departures);
+
+        for v in self.perjob.lock().unwrap().values() {
+            if full || v.since > since {
+                if let Ok(p) = v.profile(false) {
+                    ret.jobs.push(p);
+                }
+            } else {
+                let p = v.exporter.changes(&v.desc, since);
+                if !p.counters.is_empty() {
+                    ret.jobs.push(p);
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Apply a batch pushed by a child, false if it has to send a snapshot
+    #[allow(unused)]
+    pub(crate) fn receive_forwarded(&self, batch: DeltaBatch) -> Result<bool, Box<dyn Error>> {
+        self.forwarded.receive(self, batch)
+    }
+
+    /// Timestamp in ms of the last batch applied for a child
+    #[allow(unused)]
+    pub(crate) fn last_forwarded(&self, name: &str) -> Option<u64> {
+        self.forwarded.last_batch(name)
+    }
+
+    /// Release the jobs of a child which stopped pushing its changes
+    #[allow(unused)]
+    pub(crate) fn remove_forwarded(&self, name: &str) {
+        self.forwarded.remove(self, name);
+    }
+
     #[allow(unused)]
     pub(crate) fn push(
         &self,
@@ -811,14 +1369,7 @@ impl ExporterFactory {
     }
 
     #[allow(unused)]
-    pub(crate) fn add_alarm(
-        &self,
-        name: String,
-        target_job: String,
-        metric: String,
-        op: String,
-        value: f64,
-    ) -> Result<(), ProxyErr> {
+    pub(crate) fn add_alarm(&self, target_job: String, spec: AlarmSpec) -> Result<bool, ProxyErr> {
         let perjobht = self.perjob.lock().unwrap();
 
         let perjob = perjobht.get(&target_job).ok_or(ProxyErr::new(format!(
@@ -826,9 +1377,44 @@ impl ExporterFactory {
             target_job
         )))?;
 
-        perjob.exporter.add_alarm(name, metric, op, value)?;
+        perjob.exporter.add_alarm(spec)
+    }
+
+    /// Predictions of the Extra-P models for a running job
+    fn alarm_model<'a>(&'a self, desc: &'a JobDesc) -> Option<AlarmModel<'a>> {
+        /* Cumulative jobs have no model nor start */
+        if desc.start_time == 0 || desc.size <= 0 {
+            return None;
+        }
 
-        Ok(())
+        let elapsed = unix_ts().saturating_sub(desc.start_time) as f64;
+
+        Some(AlarmModel::new(
+            move |metric| {
+                self.profile_store
+                    .extrap_model_eval(desc, metric.to_string(), desc.size as f64)
+                    .ok()
+                    .map(|(_, v)| v)
+            },
+            elapsed,
+        ))
+    }
+
+    /// Evaluate alarms of all jobs returning the ones which changed state
+    pub(crate) fn evaluate_alarms(&self) -> HashMap<String, Vec<ValueAlarmTrigger>> {
+        let mut ret: HashMap<String, Vec<ValueAlarmTrigger>> = HashMap::new();
+
+        let perjobht = self.perjob.lock().unwrap();
+
+        for (k, v) in perjobht.iter() {
+            let model = self.alarm_model(&v.desc);
+            let changed: Vec<ValueAlarmTrigger> = v.exporter.evaluate_alarms(model.as_ref());
+            if !changed.is_empty() {
+                ret.insert(k.to_string(), changed);
+            }
+        }
+
+        ret
     }
 
     #[allow(unused)]
@@ -842,6 +1428,8 @@ impl ExporterFactory {
             ret.insert(k.to_string(), alarms);
         }
 
+        self.remote_alarms.merge(&mut ret, true);
+
         ret
     }
 
@@ -852,20 +1440,171 @@ impl ExporterFactory {
         let perjobht = self.perjob.lock().unwrap();
 
         for (k, v) in perjobht.iter() {
-            let alarms: Vec<ValueAlarmTrigger> = v
-                .exporter
-                .alarms
-                .read()
-                .unwrap()
-                .iter()
-                .map(|(_, v)| v.as_trigger(None))
-                .collect();
+            let alarms: Vec<ValueAlarmTrigger> = v.exporter.list_alarms();
             ret.insert(k.to_string(), alarms);
         }
 
+        self.remote_alarms.merge(&mut ret, false);
+
         ret
     }
 
+    /// Store the alarms listed by a child proxy and log their transitions
+    pub(crate) fn update_remote_alarms(
+        &self,
+        child: &String,
+        alarms: HashMap<String, Vec<ValueAlarmTrigger>>,
+    ) {
+        let changed = self.remote_alarms.update(child, alarms);
+        self.alarm_history.record(changed);
+    }
+
+    /// Base URLs of the child proxies currently scraped
+    fn child_proxies(&self) -> Vec<String> {
+        self.scrapes
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|s| s.proxy_url().cloned())
+            .collect()
+    }
+
+    /// Register an alarm locally and in all the child proxies
+    /// returning the number of children which accepted it
+    #[allow(unused)]
+    pub(crate) fn propagate_alarm(
+        &self,
+        target_job: String,
+        spec: AlarmSpec,
+    ) -> Result<usize, ProxyErr> {
+        spec.validate()?;
+
+        /* The job may only exist in the subtree, any other error is the caller's */
+        match self.perjob.lock().unwrap().get(&target_job) {
+            Some(perjob) => {
+                if !perjob.exporter.add_alarm(spec.clone())? {
+                    log::info!("Alarm {} is unbound on {}", spec.name, target_job);
+                }
+            }
+            None => log::debug!(
+                "Job {} is not local, alarm {} is only propagated",
+                target_job,
+                spec.name
+            ),
+        }
+
+        let alarm = PropagatedAlarm {
+            target: target_job,
+            spec,
+        };
+
+        let children = self.child_proxies();
+        let mut pushed = 0;
+
+        for child in children.iter() {
+            match alarm.push(child) {
+                Ok(_) => pushed += 1,
+                Err(e) => log::debug!(
+                    "Failed to push alarm {} to {} : {}",
+                    alarm.spec.name,
+                    child,
+                    e
+                ),
+            }
+        }
+
+        self.remote_alarms.propagate(alarm);
+
+        Ok(pushed)
+    }
+
+    /// Push the propagated alarms to a joining child proxy
+    fn push_propagated_alarms(&self, child_url: &String) {
+        for alarm in self.remote_alarms.propagated() {
+            if let Err(e) = alarm.push(child_url) {
+                log::debug!(
+                    "Failed to push alarm {} to {} : {}",
+                    alarm.spec.name,
+                    child_url,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Register a template and apply it to running jobs
+    #[allow(unused)]
+    pub(crate) fn add_alarm_template(&self, template: AlarmTemplate) -> Result<(), ProxyErr> {
+        let name = template.spec.name.to_string();
+        self.alarm_templates.add(template)?;
+
+        let perjobht = self.perjob.lock().unwrap();
+
+        for v in perjobht.values() {
+            for spec in self
+                .alarm_templates
+                .instantiate(&v.desc)
+                .into_iter()
+                .filter(|s| s.name == name)
+            {
+                /* Jobs may already have this alarm */
+                if let Err(e) = v.exporter.add_alarm(spec) {
+                    log::debug!("Not adding templated alarm to {} : {}", v.desc.jobid, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub(crate) fn delete_alarm_template(&self, name: &String) -> Result<(), ProxyErr> {
+        self.alarm_templates.delete(name)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn list_alarm_templates(&self) -> Vec<AlarmTemplate> {
+        self.alarm_templates.list()
+    }
+
+    #[allow(unused)]
+    pub(crate) fn alarm_history(&self, filter: &AlarmHistoryFilter) -> Vec<AlarmHistoryEntry> {
+        self.alarm_history.query(filter)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn subscribe_alarms(&self) -> Receiver<AlarmHistoryEntry> {
+        self.alarm_history.subscribe()
+    }
+
+    /// Exporters of the local jobs along the PIDs of their clients
+    pub(crate) fn get_local_job_pids(&self) -> Vec<(String, Arc<Exporter>, Vec<u32>)> {
+        self.perjob
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| v.islocal && !v.pids.is_empty())
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    v.exporter.clone(),
+                    v.pids.iter().copied().collect(),
+                )
+            })
+            .collect()
+    }
+
+    /// Identifiers and exporters of the local jobs
+    pub(crate) fn get_local_jobs(&self) -> Vec<(String, Arc<Exporter>)> {
+        self.perjob
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| v.islocal)
+            .map(|(k, v)| (k.to_string(), v.exporter.clone()))
+            .collect()
+    }
+
     pub(crate) fn get_local_job_exporters(
         &self,
     ) -> Result<Vec<Arc<Exporter>>, Box<dyn Error + '_>> {
@@ -894,4 +1633,117 @@ impl ExporterFactory {
 
         Ok(())
     }
+
+    /// Delete an alarm locally and in all the child proxies
+    #[allow(unused)]
+    pub(crate) fn delete_propagated_alarm(
+        &self,
+        target_job: &String,
+        alarm_name: &String,
+    ) -> Result<(), ProxyErr> {
+        self.remote_alarms.unpropagate(target_job, alarm_name);
+
+        for child in self.child_proxies() {
+            if let Err(e) = PropagatedAlarm::push_delete(target_job, alarm_name, &child) {
+                log::debug!("Failed to delete alarm {} in {} : {}", alarm_name, child, e);
+            }
+        }
+
+        self.delete_alarm(target_job, alarm_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarms::AlarmSeverity;
+
+    fn spec(name: &str, rule: &str) -> AlarmSpec {
+        AlarmSpec {
+            name: name.to_string(),
+            rule: rule.to_string(),
+            pending: 0.0,
+            clear: None,
+            severity: AlarmSeverity::default(),
+        }
+    }
+
+    #[test]
+    fn propagated_alarms_must_be_valid() {
+        let prefix =
+            std::env::temp_dir().join(format!("proxy-exporter-{}-propagate", std::process::id()));
+        let _ = std::fs::remove_dir_all(&prefix);
+        std::fs::create_dir_all(&prefix).unwrap();
+
+        let factory = ExporterFactory::new(prefix.clone(), false, 1024 * 1024, None).unwrap();
+
+        let desc = JobDesc {
+            jobid: "1234".to_string(),
+            command: "solver".to_string(),
+            size: 1,
+            nodelist: hostname(),
+            partition: "".to_string(),
+            cluster: "".to_string(),
+            run_dir: "".to_string(),
+            start_time: unix_ts(),
+            end_time: 0,
+            user: "".to_string(),
+            pid: 0,
+            accounting: None,
+        };
+        factory.resolve_job(&desc, false);
+
+        /* A job of the subtree, the alarm is only propagated */
+        assert_eq!(
+            factory
+                .propagate_alarm("5678".to_string(), spec("load", "load > 1"))
+                .unwrap(),
+            0
+        );
+
+        factory
+            .propagate_alarm("1234".to_string(), spec("load", "load > 1"))
+            .unwrap();
+
+        /* Invalid and duplicate alarms are refused and not kept for children */
+        assert!(factory
+            .propagate_alarm("5678".to_string(), spec("bad", "load >"))
+            .is_err());
+        assert!(factory
+            .propagate_alarm("1234".to_string(), spec("load", "load > 2"))
+            .is_err());
+        assert_eq!(factory.remote_alarms.propagated().len(), 2);
+
+        std::fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn alarms_without_metrics_are_unbound() {
+        let exporter = Exporter::new();
+
+        assert!(!exporter.add_alarm(spec("load", "load > 1")).unwrap());
+
+        let listed = exporter.list_alarms();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].unbound);
+
+        exporter
+            .push(&CounterSnapshot::new(
+                "load".to_string(),
+                &[],
+                "".to_string(),
+                CounterType::Gauge {
+                    min: 2.0,
+                    max: 2.0,
+                    hits: 1.0,
+                    total: 2.0,
+                },
+            ))
+            .unwrap();
+
+        /* Bound when the metric appears, alarms on existing metrics are bound right away */
+        exporter.bind_alarms();
+        assert!(!exporter.list_alarms()[0].unbound);
+        assert!(exporter.add_alarm(spec("high_load", "load > 10")).unwrap());
+    }
 }
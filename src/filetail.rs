@@ -0,0 +1,366 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::FileFormat;
+use crate::proxy_common::{unix_ts_us, ProxyErr};
+use crate::proxywireprotocol::{CounterSnapshot, CounterType};
+use crate::scrapper::ProxyScraper;
+
+/// Position of the tail in one of the followed files
+struct FileCursor {
+    file: File,
+    /// Inode of the open file to detect rotations
+    ino: u64,
+    offset: u64,
+    /// Modification time in ns of the last read (whole file formats)
+    mtime: i128,
+    /// Trailing line not terminated yet
+    partial: String,
+    /// Column names of CSV files
+    header: Option<Vec<String>>,
+}
+
+impl FileCursor {
+    fn open(path: &Path) -> Result<FileCursor, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+
+        Ok(FileCursor {
+            file,
+            ino: meta.ino(),
+            offset: 0,
+            mtime: -1,
+            partial: String::new(),
+            header: None,
+        })
+    }
+
+    fn rewind(&mut self) {
+        self.offset = 0;
+        self.partial.clear();
+        self.header = None;
+    }
+
+    /// Read the complete lines appended since the last call
+    fn read_lines(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut data = Vec::new();
+
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        self.offset += self.file.read_to_end(&mut data)? as u64;
+
+        self.partial += String::from_utf8_lossy(&data).as_ref();
+
+        let mut lines: Vec<String> = self.partial.split('\n').map(|l| l.to_string()).collect();
+        /* The last chunk is either empty or a line being written */
+        self.partial = lines.pop().unwrap_or_default();
+
+        Ok(lines)
+    }
+}
+
+/// Follows a file (or the files matching a glob) written by
+/// an application and turns its content into counters
+pub(crate) struct FileTail {
+    pattern: String,
+    /// File name regular expression when the pattern is a glob
+    glob: Option<(PathBuf, Regex)>,
+    format: Option<FileFormat>,
+    prefix: Option<String>,
+    cursors: HashMap<PathBuf, FileCursor>,
+}
+
+impl FileTail {
+    pub(crate) fn new(
+        pattern: &str,
+        format: Option<FileFormat>,
+        prefix: Option<String>,
+    ) -> Result<FileTail, ProxyErr> {
+        let path = Path::new(pattern);
+
+        let name = path
+            .file_name()
+            .ok_or(ProxyErr::new(format!("{} is not a file", pattern)))?
+            .to_string_lossy()
+            .to_string();
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        if dir.to_string_lossy().contains(['*', '?']) {
+            return Err(ProxyErr::new(format!(
+                "Only file names can be globbed in {}",
+                pattern
+            )));
+        }
+
+        let glob = if name.contains(['*', '?']) {
+            Some((dir.to_path_buf(), FileTail::glob_regex(&name)?))
+        } else {
+            None
+        };
+
+        /* Without a format all the files must have a known extension */
+        if format.is_none() && FileTail::detect_format(path).is_none() {
+            return Err(ProxyErr::new(format!(
+                "Cannot infer the format of {} please set 'format'",
+                pattern
+            )));
+        }
+
+        Ok(FileTail {
+            pattern: pattern.to_string(),
+            glob,
+            format,
+            prefix,
+            cursors: HashMap::new(),
+        })
+    }
+
+    /// Regular expression matching a file name glob (* and ?)
+    pub(crate) fn glob_regex(name: &str) -> Result<Regex, ProxyErr> {
+        let expr = regex::escape(name).replace("\\*", ".*").replace("\\?", ".");
+        Regex::new(&format!("^{}$", expr))
+            .map_err(|e| ProxyErr::new(format!("Bad glob {} : {}", name, e)))
+    }
+
+    fn detect_format(path: &Path) -> Option<FileFormat> {
+        match path.extension()?.to_str()? {
+            "jsonl" | "ndjson" | "json" => Some(FileFormat::Jsonl),
+            "csv" => Some(FileFormat::Csv),
+            "prom" | "txt" => Some(FileFormat::Prometheus),
+            _ => None,
+        }
+    }
+
+    fn matching(&self) -> Vec<PathBuf> {
+        match &self.glob {
+            None => vec![PathBuf::from(&self.pattern)],
+            Some((dir, regex)) => {
+                let mut ret: Vec<PathBuf> = fs::read_dir(dir)
+                    .map(|entries| {
+                        entries
+                            .filter_map(|e| e.ok())
+                            .filter(|e| regex.is_match(&e.file_name().to_string_lossy()))
+                            .map(|e| e.path())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                ret.sort();
+                ret
+            }
+        }
+    }
+
+    /// Metric name from a field of the file
+    fn metric_name(&self, field: &str) -> String {
+        let field: String = field
+            .trim()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        match &self.prefix {
+            Some(prefix) => format!("{}_{}", prefix, field),
+            None => field,
+        }
+    }
+
+    fn jsonl_values(line: &str) -> Vec<(String, f64)> {
+        let value: serde_json::Value = match serde_json::from_str(line) {
+            Ok(v) => v,
+            Err(e) => {
+                log::debug!("Skipping bad JSON line '{}' : {}", line, e);
+                return Vec::new();
+            }
+        };
+
+        match value {
+            serde_json::Value::Object(fields) => fields
+                .iter()
+                .filter_map(|(k, v)| match v {
+                    serde_json::Value::Number(n) => n.as_f64().map(|n| (k.to_string(), n)),
+                    serde_json::Value::Bool(b) => Some((k.to_string(), *b as u8 as f64)),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn csv_values(cursor: &mut FileCursor, line: &str) -> Vec<(String, f64)> {
+        let columns: Vec<String> = line
+            .split(',')
+            .map(|c| c.trim().trim_matches('"').to_string())
+            .collect();
+
+        match &cursor.header {
+            None => {
+                cursor.header = Some(columns);
+                Vec::new()
+            }
+            Some(header) => header
+                .iter()
+                .zip(columns.iter())
+                .filter_map(|(k, v)| v.parse::<f64>().ok().map(|v| (k.to_string(), v)))
+                .collect(),
+        }
+    }
+
+    /// Turn the rows read during a scrape into counters, gauges are
+    /// averaged over the rows and _total fields keep their last value
+    /// which replaces the previous one in the exporter
+    fn rows_to_counters
+(&self, file: &str, rows: Vec<Vec<(String, f64)>>) -> Vec<CounterSnapshot> {
+        let mut values: HashMap<String, CounterType> = HashMap::new();
+
+        for (field, v) in rows.into_iter().flatten() {
+            if !v.is_finite() {
+                continue;
+            }
+
+            let name = self.metric_name(&field);
+
+            if name.ends_with("_total") {
+                values.insert(
+                    name,
+                    CounterType::Counter {
+                        ts: unix_ts_us(),
+                        value: v,
+                    },
+                );
+                continue;
+            }
+
+            let entry = values.entry(name).or_insert(CounterType::Gauge {
+                min: v,
+                max: v,
+                hits: 0.0,
+                total: 0.0,
+            });
+
+            if let CounterType::Gauge {
+                min,
+                max,
+                hits,
+                total,
+            } = entry
+            {
+                *min = min.min(v);
+                *max = max.max(v);
+                *hits += 1.0;
+                *total += v;
+            }
+        }
+
+        let labels = [("file".to_string(), file.to_string())];
+
+        values
+            .into_iter()
+            .map(|(name, ctype)| {
+                CounterSnapshot::new(name, &labels, format!("Read from {}", file), ctype)
+            })
+            .collect()
+    }
+
+    /// Read the new content of the followed files
+    pub(crate) fn scrape(&mut self) -> Result<Vec<CounterSnapshot>, Box<dyn Error>> {
+        let paths = self.matching();
+
+        /* Forget the files which disappeared */
+        self.cursors.retain(|p, _| paths.contains(p));
+
+        let mut ret = Vec::new();
+
+        for path in paths {
+            let meta = match fs::metadata(&path) {
+                Ok(m) => m,
+                Err(e) => {
+                    if self.glob.is_none() {
+                        return Err(ProxyErr::newboxed(format!(
+                            "Cannot read {} : {}",
+                            path.display(),
+                            e
+                        )));
+                    }
+                    continue;
+                }
+            };
+
+            let format = match self.format.or_else(|| FileTail::detect_format(&path)) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            let file = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            let mut rows: Vec<Vec<(String, f64)>> = Vec::new();
+
+            let mut cursor = match self.cursors.remove(&path) {
+                Some(c) => c,
+                None => FileCursor::open(&path)?,
+            };
+
+            if cursor.ino != meta.ino() {
+                /* Rotated, drain the previous file before following the new one */
+                if format != FileFormat::Prometheus {
+                    for l in cursor.read_lines()? {
+                        rows.push(self.parse_line(format, &mut cursor, &l));
+                    }
+                }
+                cursor = FileCursor::open(&path)?;
+            } else if meta.len() < cursor.offset {
+                /* Truncated, start over */
+                cursor.rewind();
+            }
+
+            if format == FileFormat::Prometheus {
+                /* Whole file format, parse it again when it changes */
+                let mtime = meta.mtime() as i128 * 1_000_000_000 + meta.mtime_nsec() as i128;
+                if mtime != cursor.mtime {
+                    cursor.mtime = mtime;
+                    let data = fs::read_to_string(&path)?;
+                    ret.extend(
+                        ProxyScraper::parse_prometheus(&data, false)?
+                            .into_iter()
+                            .map(|(_, m)| m),
+                    );
+                }
+            } else {
+                for l in cursor.read_lines()? {
+                    rows.push(self.parse_line(format, &mut cursor, &l));
+                }
+            }
+
+            ret.extend(self.rows_to_counters(&file, rows));
+            self.cursors.insert(path, cursor);
+        }
+
+        Ok(ret)
+    }
+
+    fn parse_line(
+        &self,
+        format: FileFormat,
+        cursor: &mut FileCursor,
+        line: &str,
+    ) -> Vec<(String, f64)> {
+        let line = line.trim();
+
+        if line.is_empty() {
+            return Vec::new();
+        }
+
+        match format {
+            FileFormat::Jsonl => FileTail::jsonl_values(line),
+            FileFormat::Csv => FileTail::csv_values(cursor, line),
+            FileFormat::Prometheus => unreachable!("Prometheus files are parsed as a whole"),
+        }
+    }
+}
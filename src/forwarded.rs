@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::{unix_ts, ProxyErr};
+use crate::proxywireprotocol::{DeltaBatch, JobDesc, JobProfile};
+
+/// Apply changes of counters to the absolute values of a job
+fn accumulate(state: &mut JobProfile, delta: &JobProfile) -> Result<(), ProxyErr> {
+    state.desc = delta.desc.clone();
+
+    for cnt in delta.counters.iter() {
+        match state.counters.iter_mut().find(|c| c.name == cnt.name) {
+            Some(existing) => existing.merge(cnt)?,
+            None => state.counters.push(cnt.clone()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Jobs of a child as known from its batches
+struct ForwardedSource {
+    seq: u64,
+    /// Timestamp in ms of the last batch applied
+    last: u64,
+    /// Absolute values of the counters of each job
+    jobs: HashMap<String, JobProfile>,
+}
+
+/// Merges the batches pushed by the children in the exporters of this proxy
+pub(crate) struct ForwardedSources {
+    sources: Mutex<HashMap<String, ForwardedSource>>,
+}
+
+impl ForwardedSources {
+    pub(crate) fn new() -> ForwardedSources {
+        ForwardedSources {
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns false when the batch does not follow the previous one, the
+    /// child then has to resynchronize with a full snapshot. Snapshots are
+    /// merged as their difference with the values already received so
+    /// they can be resent when an acknowledgement is lost
+    pub(crate) fn receive(
+        &self,
+        factory: &ExporterFactory,
+        batch: DeltaBatch,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut sources = self.sources.lock().unwrap();
+
+        if !batch.full {
+            match sources.get(&batch.source) {
+                Some(s) if s.seq + 1 == batch.seq => {}
+                /* Delivered again, it was already applied */
+                Some(s) if batch.seq <= s.seq => return Ok(true),
+                Some(s) => {
+                    log::warn!(
+                        "Batch {} from {} follows {}, requesting a snapshot",
+                        batch.seq,
+                        batch.source,
+                        s.seq
+                    );
+                    return Ok(false);
+                }
+                None => return Ok(false),
+            }
+        }
+
+        let source = sources
+            .entry(batch.source.to_string())
+            .or_insert(ForwardedSource {
+                seq: 0,
+                last: 0,
+                jobs: HashMap::new(),
+            });
+
+        let mut left: Vec<JobDesc> = batch.left;
+
+        /* Jobs missing from a snapshot have ended */
+        if batch.full {
+            left.extend(
+                source
+                    .jobs
+                    .values()
+                    .filter(|p| !batch.jobs.iter().any(|j| j.desc.jobid == p.desc.jobid))
+                    .map(|p| p.desc.clone()),
+            );
+        }
+
+        for desc in left.iter() {
+            if source.jobs.remove(&desc.jobid).is_some() {
+                factory.relax_job(desc)?;
+            }
+        }
+
+        for p in batch.jobs {
+            let mut delta = p.clone();
+
+            match source.jobs.get_mut(&p.desc.jobid) {
+                Some(previous) => {
+                    if batch.full {
+                        delta.substract(previous)?;
+                        *previous = p;
+                    } else {
+                        accumulate(previous, &p)?;
+                    }
+                }
+                None => {
+                    /* New Job Register in Job List */
+                    let _ = factory.resolve_job(&p.desc, false);
+                    source.jobs.insert(p.desc.jobid.to_string(), p);
+                }
+            }
+
+            factory.merge_profile(&delta, &[])?;
+        }
+
+        source.seq = batch.seq;
+        source.last = unix_ts();
+
+        Ok(true)
+    }
+
+    /// Timestamp in ms of the last batch applied for a child
+    pub(crate) fn last_batch(&self, name: &str) -> Option<u64> {
+        self.sources.lock().unwrap().get(name).map(|s| s.last)
+    }
+
+    /// Release the jobs of a child which left
+    pub(crate) fn remove(&self, factory: &ExporterFactory, name: &str) {
+        if let Some(source) = self.sources.lock().unwrap().remove(name) {
+            for p in source.jobs.values() {
+                if let Err(e) = factory.relax_job(&p.desc) {
+                    log::error!("Failed to release job {} : {}", p.desc.jobid, e);
+                }
+            }
+        }
+    }
+}
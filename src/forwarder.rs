@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::auth::Auth;
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::ProxyErr;
+use crate::proxywireprotocol::{ApiResponse, CounterSnapshot, DeltaBatch, JobProfile};
+use crate::topology::Topology;
+
+/// Deadline of the batches sent to the parent
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pushes the changes of the counters of this proxy to its parent
+pub(crate) struct DeltaForwarder {
+    url: String,
+    factory: Arc<ExporterFactory>,
+    topology: Arc<Topology>,
+    parent: Option<String>,
+    seq: u64,
+    /// Next batch is a full snapshot
+    resync: bool,
+    /// Absolute values of the jobs in the last batch received by the parent
+    sent: HashMap<String, JobProfile>,
+}
+
+impl DeltaForwarder {
+    pub(crate) fn new(
+        url: String,
+        factory: Arc<ExporterFactory>,
+        topology: Arc<Topology>,
+    ) -> DeltaForwarder {
+        DeltaForwarder {
+            url,
+            factory,
+            topology,
+            parent: None,
+            seq: 0,
+            resync: true,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Changed counters of a job, docs are only sent for new counters
+    fn changes(current: &JobProfile, previous: &JobProfile) -> Result<JobProfile, ProxyErr> {
+        let mut counters: Vec<CounterSnapshot> = Vec::new();
+
+        for cnt in current.counters.iter() {
+            match previous.counters.iter().find(|c| c.name == cnt.name) {
+                Some(prev) => {
+                    let mut delta = CounterSnapshot {
+                        name: cnt.name.to_string(),
+                        doc: String::new(),
+                        ctype: cnt.ctype.clone(),
+                    };
+                    delta.delta(prev)?;
+                    if delta.hasdata() {
+                        counters.push(delta);
+                    }
+                }
+                None => counters.push(cnt.clone()),
+            }
+        }
+
+        Ok(JobProfile {
+            desc: current.desc.clone(),
+            counters,
+        })
+    }
+
+    fn batch(&mut self, profiles: &[JobProfile]) -> Result<DeltaBatch, ProxyErr> {
+        self.seq += 1;
+
+        let mut batch = DeltaBatch {
+            source: self.url.to_string(),
+            seq: self.seq,
+            full: self.resync,
+            jobs: Vec::new(),
+            left: Vec::new(),
+        };
+
+        if self.resync {
+            batch.jobs = profiles.to_vec();
+            return Ok(batch);
+        }
+
+        for p in profiles.iter() {
+            match self.sent.get(&p.desc.jobid) {
+                Some(previous) => {
+                    let delta = DeltaForwarder::changes(p, previous)?;
+                    if !delta.counters.is_empty() {
+                        batch.jobs.push(delta);
+                    }
+                }
+                None => batch.jobs.push(p.clone()),
+            }
+        }
+
+        batch.left = self
+            .sent
+            .values()
+            .filter(|s| !profiles.iter().any(|p| p.desc.jobid == s.desc.jobid))
+            .map(|s| s.desc.clone())
+            .collect();
+
+        Ok(batch)
+    }
+
+    fn send(parent: &str, batch: &DeltaBatch) -> Result<ApiResponse, Box<dyn Error>> {
+        let resp = Auth::post(&format!("{}/topo/forward", parent), batch, FORWARD_TIMEOUT)?
+            .send()?
+            .json::<ApiResponse>()?;
+
+        Ok(resp)
+    }
+
+    fn forward(&mut self, parent: &str) -> Result<(), Box<dyn Error>> {
+        /* A new parent knows nothing about our jobs */
+        if self.parent.as_deref() != Some(parent) {
+            self.parent = Some(parent.to_string());
+            self.resync = true;
+        }
+
+        let profiles = self.factory.profiles(false);
+        let batch = self.batch(&profiles)?;
+
+        /* Unless acknowledged the parent may have missed it, send everything again */
+        self.resync = true;
+
+        let resp = DeltaForwarder::send(parent, &batch)?;
+
+        if !resp.success {
+            return Err(ProxyErr::newboxed(format!(
+                "{} rejected batch {} : {}",
+                parent, batch.seq, resp.operation
+            )));
+        }
+
+        self.sent = profiles
+            .into_iter()
+            .map(|p| (p.desc.jobid.to_string(), p))
+            .collect();
+        self.resync = false;
+
+        Ok(())
+    }
+
+    /// Forward the changes every period to the parent assigned by the root
+    pub(crate) fn run(mut self) {
+        loop {
+            match self.topology.parent() {
+                Some((parent, period)) => {
+                    if let Err(e) = self.forward(&parent) {
+                        log::debug!("Failed to forward to {} : {}", parent, e);
+                    }
+                    sleep(Duration::from_millis(period));
+                }
+                None => sleep(Duration::from_secs(1)),
+            }
+        }
+    }
+}
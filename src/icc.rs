@@ -1,11 +1,11 @@
 #[cfg(feature = "admire")]
 mod admire {
+    use crate::alarms::ValueAlarmTrigger;
     use crate::exporter::ExporterFactory;
+    use crate::notifier::AlarmTransitions;
     use crate::proxy_common::ProxyErr;
-    use crate::proxywireprotocol::ValueAlarmTrigger;
     use rust_icc::*;
     use std::{
-        collections::HashMap,
         ffi::CString,
         ptr,
         sync::Arc,
@@ -138,61 +138,13 @@ mod admire {
         fn notify_alarms(
             client: &mut IccClient,
             exporter: &Arc<ExporterFactory>,
-            states: &mut HashMap<(String, String), bool>,
+            transitions: &mut AlarmTransitions,
         ) -> Result<(), ProxyErr> {
             let alarms = exporter.list_alarms();
 
-            for source_exporter in alarms.keys() {
-                let active_alarms: Vec<&ValueAlarmTrigger> = alarms
-                    .get(source_exporter)
-                    .unwrap()
-                    .iter()
-                    .filter(|v| v.active)
-                    .collect();
-                for alarm in active_alarms {
-                    /* Notify only if state has changed */
-                    let al_key = (source_exporter.clone(), alarm.name.clone());
-
-                    let mut notify_alarm = false;
-
-                    if let Some(prev_state) = states.get_mut(&al_key) {
-                        if !*prev_state {
-                            notify_alarm = true;
-                            *prev_state = true
-                        }
-                    } else {
-                        notify_alarm = true;
-                        states.insert(al_key, true);
-                    }
-
-                    if notify_alarm {
-                        unsafe { client.notify_alarm(source_exporter, alarm)? };
-                    }
-                }
-
-                /* Make sure to flag inactive alarms */
-                let inactive_alarms: Vec<&ValueAlarmTrigger> = alarms
-                    .get(source_exporter)
-                    .unwrap()
-                    .iter()
-                    .filter(|v| !v.active)
-                    .collect();
-                for inactive in inactive_alarms {
-                    let al_key = (source_exporter.clone(), inactive.name.clone());
-
-                    let mut notify_alarm = false;
-
-                    if let Some(prev_state) = states.get_mut(&al_key) {
-                        if *prev_state {
-                            notify_alarm = true;
-                            *prev_state = false;
-                        }
-                    }
-
-                    if notify_alarm {
-                        unsafe { client.notify_alarm(source_exporter, inactive)? };
-                    }
-                }
+            /* Notify only if state has changed */
+            for event in transitions.update(&alarms) {
+                unsafe { client.notify_alarm(&event.job, &event.alarm)? };
             }
 
             Ok(())
@@ -203,7 +155,7 @@ mod admire {
 
             log::info!("Connected to the intelligent controller");
 
-            let mut notify_state: HashMap<(String, String), bool> = HashMap::new();
+            let mut transitions = AlarmTransitions::new();
 
             loop {
                 /* This is the loop where we do our IC actions
@@ -212,7 +164,7 @@ mod admire {
                 due to the unsafe nature of the C code */
 
                 if let Err(e) =
-                    IccInterface::notify_alarms(&mut client, &exporter, &mut notify_state)
+                    IccInterface::notify_alarms(&mut client, &exporter, &mut transitions)
                 {
                     log::error!("Error sending alarm {}", e);
                     unsafe { client.close()? };
@@ -5,7 +5,7 @@ use std::process::exit;
 use std::thread::{self, sleep};
 use std::time::Duration;
 mod proxy_common;
-use proxy_common::{get_proxy_path, init_log};
+use proxy_common::{get_proxy_path, hostname, init_log};
 
 mod exporter;
 use exporter::ExporterFactory;
@@ -18,13 +18,32 @@ mod squeue;
 mod webserver;
 use webserver::Web;
 
+mod topology;
+use topology::Topology;
+
+mod alarms;
+mod auth;
+use auth::Auth;
+mod cgroupmetrics;
+mod config;
+mod counterfiles;
+mod discovery;
 mod extrap;
+mod filetail;
+mod forwarded;
+mod forwarder;
+use forwarder::DeltaForwarder;
 mod icc;
+mod notifier;
+use notifier::Notifier;
+mod procmetrics;
 mod profiles;
 mod proxywireprotocol;
+mod sacct;
 mod scrapper;
 mod systemmetrics;
 mod trace;
+mod tracefile;
 
 extern crate clap;
 
@@ -61,6 +80,22 @@ struct Args {
     #[arg(short, long)]
     root_proxy: Option<String>,
 
+    /// Maximum number of child proxies of a proxy in the tree built by this root proxy
+    #[arg(short = 'F', long, default_value_t = 2)]
+    fanout: usize,
+
+    /// Maximum depth of the tree built by this root proxy (0 for unlimited)
+    #[arg(short = 'D', long, default_value_t = 0)]
+    max_depth: usize,
+
+    /// Push the changes of the counters to the parent proxy instead of being scraped by it
+    #[arg(short = 'P', long, default_value_t = false)]
+    push: bool,
+
+    /// JSON file with the TLS settings and the credentials of the proxy
+    #[arg(short = 'A', long)]
+    auth: Option<PathBuf>,
+
     /// Maximum trace size to maintain in the file-system in MB (default 32MB)
     #[arg(short, long)]
     max_trace_size: Option<f64>,
@@ -72,6 +107,14 @@ struct Args {
     /// Sampling period in MS
     #[arg(short = 'S', long, default_value_t = 1000)]
     sampling_period: u64,
+
+    /// Alarm notifiers configuration (optionnal default PREFIX/alarms/notifiers.json)
+    #[arg(short = 'N', long)]
+    notifiers: Option<PathBuf>,
+
+    /// Proxy configuration file listing static scrapes, reloaded on SIGHUP (optionnal default PREFIX/config.json)
+    #[arg(short = 'C', long)]
+    config: Option<PathBuf>,
 }
 
 fn parse_period(arg: &String, default_period: u64) -> (String, u64) {
@@ -101,6 +144,17 @@ fn main() -> Result<(), Box<dyn Error>> {
     /* Make sure it is globally visible */
     env::set_var("PROXY_PERIOD", format!("{}", args.sampling_period));
 
+    /* Credentials are needed before any request to other proxies */
+    if let Some(auth) = &args.auth {
+        match Auth::load(auth) {
+            Ok(a) => Auth::install(a),
+            Err(e) => {
+                log::error!("Failed to load {} : {}", auth.to_string_lossy(), e);
+                exit(1);
+            }
+        }
+    }
+
     let profile_prefix = if let Some(prefix) = args.target_prefix {
         prefix
     } else {
@@ -121,13 +175,22 @@ fn main() -> Result<(), Box<dyn Error>> {
         max_trace_size / (1024.0 * 1024.0)
     );
 
+    let notifiers_config = args
+        .notifiers
+        .unwrap_or(profile_prefix.join("alarms").join("notifiers.json"));
+
+    let config = args.config.unwrap_or(profile_prefix.join("config.json"));
+
     // The central storage is the exporter
     let factory = ExporterFactory::new(
         profile_prefix,
         !args.inhibit_profile_agreggation,
         max_trace_size as usize,
+        Some(config),
     )?;
 
+    config::reload_on_sighup(factory.clone());
+
     if let Some(urls) = args.sub_proxies {
         for url in urls.iter() {
             let (url, freq) = parse_period(url, args.sampling_period);
@@ -150,24 +213,49 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Run the proxy detached with a ref to the exporter data
     thread::spawn(move || proxy.run());
 
+    // The topology manager maintains the reduction tree
+    let url = format!("{}:{}", hostname(), args.port);
+    let topology = Topology::new(
+        url.to_string(),
+        args.fanout,
+        args.max_depth,
+        args.push,
+        factory.clone(),
+    );
+
     // Start the webserver part with a reference to the exporter
-    let web = Web::new(args.port, factory.clone());
+    let web = Web::new(args.port, factory.clone(), topology.clone());
 
-    let web_url = web.url();
+    let forward_factory = factory.clone();
 
     thread::spawn(move || {
         /* Wait for server to start before joining as the server will back-connect  */
         sleep(Duration::from_secs(3));
         if let Some(root) = args.root_proxy {
-            let (url, period) = parse_period(&root, args.sampling_period);
+            let (root_url, period) = parse_period(&root, args.sampling_period);
 
-            if let Err(e) = ExporterFactory::join(&url, &web_url, period) {
+            if let Err(e) = topology.join(&root_url, period) {
                 log::error!("Failed to register in root server {}: {}", root, e);
                 exit(1);
             }
+
+            if args.push {
+                DeltaForwarder::new(url, forward_factory, topology).run();
+            }
         }
     });
 
+    if notifiers_config.is_file() {
+        match Notifier::load(&notifiers_config) {
+            Ok(notifier) => notifier.start(factory.clone()),
+            Err(e) => log::error!(
+                "Failed to load alarm notifiers from {} : {}",
+                notifiers_config.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
     #[cfg(feature = "admire")]
     if args.connect_to_intelligent_controller {
         IccInterface::new(factory.clone());
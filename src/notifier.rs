@@ -0,0 +1,729 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use crate::alarms::{deserialize_duration, AlarmSeverity, ValueAlarmTrigger};
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::{get_proxy_period, hostname, unix_ts, ProxyErr};
+
+/*********************
+ * ALARM TRANSITIONS *
+ *********************/
+
+/// A firing or resolved transition of an alarm in a given job
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct AlarmEvent {
+    pub(crate) job: String,
+    pub(crate) host: String,
+    pub(crate) firing: bool,
+    pub(crate) ts: u64,
+    pub(crate) alarm: ValueAlarmTrigger,
+}
+
+impl AlarmEvent {
+    fn transition(&self) -> &'static str {
+        if self.firing {
+            "FIRING"
+        } else {
+            "RESOLVED"
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "[{}] {} in job {} on {} : {}",
+            self.transition(),
+            self.alarm.name,
+            self.job,
+            self.host,
+            self.alarm.pretty
+        )
+    }
+}
+
+/// Tracks the active flag of each (job, host, alarm) over successive
+/// alarm listings to only report firing and resolved transitions
+pub(crate) struct AlarmTransitions {
+    states: HashMap<(String, String, String), bool>,
+}
+
+impl AlarmTransitions {
+    pub(crate) fn new() -> AlarmTransitions {
+        AlarmTransitions {
+            states: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        alarms: &HashMap<String, Vec<ValueAlarmTrigger>>,
+    ) -> Vec<AlarmEvent> {
+        let mut ret: Vec<AlarmEvent> = Vec::new();
+        let mut seen: HashSet<(String, String, String)> = HashSet::new();
+
+        for (source_exporter, list) in alarms.iter() {
+            for alarm in list {
+                let al_key = (
+                    source_exporter.clone(),
+                    alarm.host.clone(),
+                    alarm.name.clone(),
+                );
+
+                /* Notify only if state has changed, alarms
+                which were never active are not resolved */
+                let notify = matches!(
+                    (self.states.get(&al_key), alarm.active),
+                    (Some(false) | None, true) | (Some(true), false)
+                );
+
+                if notify {
+                    self.states.insert(al_key.clone(), alarm.active);
+                    ret.push(AlarmEvent {
+                        job: source_exporter.to_string(),
+                        host: alarm.host.to_string(),
+                        firing: alarm.active,
+                        ts: unix_ts(),
+                        alarm: alarm.clone(),
+                    });
+                }
+
+                seen.insert(al_key);
+            }
+        }
+
+        /* Forget deleted alarms and ended jobs */
+        self.states.retain(|k, _| seen.contains(k));
+
+        ret
+    }
+}
+
+/******************
+ * NOTIFIER SINKS *
+ ******************/
+
+fn default_syslog_socket() -> String {
+    "/dev/log".to_string()
+}
+
+fn default_syslog_facility() -> u8 {
+    /* LOG_USER */
+    1
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NotifierSinkType {
+    /// POST the event as JSON to an URL
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Run a command with the event as JSON on stdin
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Send to the local syslog socket (also read by journald)
+    #[serde(alias = "journal")]
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: String,
+        #[serde(default = "default_syslog_facility")]
+        facility: u8,
+    },
+    /// Send a mail through a plain SMTP relay
+    Smtp {
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct RateLimit {
+    /// Maximum number of notifications
+    count: usize,
+    /// Over this duration in seconds
+    #[serde(deserialize_with = "deserialize_duration")]
+    per: f64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct NotifierSinkConfig {
+    #[serde(flatten)]
+    ttype: NotifierSinkType,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+}
+
+struct NotifierSink {
+    name: String,
+    config: NotifierSinkConfig,
+    /// Timestamps of the last notifications for rate limiting
+    sent: VecDeque<u64>,
+    /// Number of notifications dropped due to rate limiting
+    dropped: u64,
+}
+
+impl NotifierSink {
+    fn new(name: &str, config: NotifierSinkConfig) -> NotifierSink {
+        NotifierSink {
+            name: name.to_string(),
+            config,
+            sent: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn rate_limited(&mut self, now: u64) -> bool {
+        if let Some(limit) = &self.config.rate_limit {
+            let horizon = now.saturating_sub((limit.per * 1000.0) as u64);
+
+            while self.sent.front().is_some_and(|ts| *ts < horizon) {
+                self.sent.pop_front();
+            }
+
+            if self.sent.len() >= limit.count {
+                return true;
+            }
+        }
+
+        self.sent.push_back(now);
+        false
+    }
+
+    fn webhook(
+        url: &str,
+        headers: &HashMap<String, String>,
+        event: &AlarmEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        let client = reqwest::blocking::Client::new();
+
+        let mut req = client
+            .post(url)
+            .timeout(Duration::from_secs(10))
+            .json(event);
+
+        for (k, v) in headers.iter() {
+            req = req.header(k, v);
+        }
+
+        req.send()?.error_for_status()?;
+
+        Ok(())
+    }
+
+    fn exec(command: &str, args: &[String], event: &AlarmEvent) -> Result<(), Box<dyn Error>> {
+        let mut child = Command::new(command)
+            .args(args)
+            .env("PROXY_ALARM_NAME", &event.alarm.name)
+            .env("PROXY_ALARM_JOB", &event.job)
+            .env("PROXY_ALARM_HOST", &event.host)
+            .env("PROXY_ALARM_STATE", event.transition())
+            .env("PROXY_ALARM_SEVERITY", event.alarm.severity.to_string())
+            .env("PROXY_ALARM_RULE", &event.alarm.operator)
+            .env("PROXY_ALARM_VALUE", event.alarm.current.to_string())
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        /* Dropping stdin closes it */
+        if let Some(mut stdin) = child.stdin.take() {
+            serde_json::to_writer(&mut stdin, event)?;
+        }
+
+        /* Do not block the notifications on the command */
+        let command = command.to_string();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if !status.success() => {
+                log::error!("Alarm notification command {} failed : {}", command, status)
+            }
+            Err(e) => log::error!("Failed to wait alarm command {} : {}", command, e),
+            _ => {}
+        });
+
+        Ok(())
+    }
+
+    fn syslog(socket: &str, facility: u8, event: &AlarmEvent) -> Result<(), Box<dyn Error>> {
+        let level = match (event.firing, event.alarm.severity) {
+            (false, _) => 5, /* LOG_NOTICE */
+            (true, AlarmSeverity::Info) => 6,
+            (true, AlarmSeverity::Warning) => 4,
+            (true, AlarmSeverity::Critical) => 2,
+        };
+
+        let msg = format!(
+            "<{}>proxy_v2[{}]: {}",
+            facility as u32 * 8 + level,
+            std::process::id(),
+            event.summary()
+        );
+
+        let sock = UnixDatagram::unbound()?;
+        sock.send_to(msg.as_bytes(), socket)?;
+
+        Ok(())
+    }
+
+    fn smtp_reply(reader: &mut BufReader<TcpStream>, expected: &[u32]) -> Result<(), ProxyErr> {
+        /* Replies can span multiple lines as "250-" until "250 " */
+        loop {
+            let mut line = String::new();
+            reader
+                .read_line(&mut line)
+                .map_err(|e| ProxyErr::new(format!("SMTP read failed : {}", e)))?;
+
+            if line.len() < 4 {
+                return Err(ProxyErr::new(format!("Bad SMTP reply '{}'", line.trim())));
+            }
+
+            let code = line[..3].parse::<u32>().unwrap_or(0);
+
+            if !expected.contains(&code) {
+                return Err(ProxyErr::new(format!("SMTP error '{}'", line.trim())));
+            }
+
+            if line.as_bytes()[3] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    fn smtp_cmd(
+        stream: &mut TcpStream,
+        reader: &mut BufReader<TcpStream>,
+        cmd: &str,
+        expected: &[u32],
+    ) -> Result<(), Box<dyn Error>> {
+        stream.write_all(format!("{}\r\n", cmd).as_bytes())?;
+        Self::smtp_reply(reader, expected)?;
+        Ok(())
+    }
+
+    /// Header value on a single line, non ASCII text is sent as
+    /// encoded words (RFC 2047) of at most 75 characters
+    fn smtp_header(value: &str) -> String {
+        let value: String = value
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+
+        if value.is_ascii() {
+            return value;
+        }
+
+        let mut words = vec![String::new()];
+
+        for c in value.chars() {
+            let encoded = match c {
+                ' ' => "_".to_string(),
+                c if c.is_ascii_alphanumeric() => c.to_string(),
+                c => c
+                    .to_string()
+                    .bytes()
+                    .map(|b| format!("={:02X}", b))
+                    .collect(),
+            };
+
+            /* 75 characters minus the "=?utf-8?Q?" and "?=" delimiters */
+            if words.last().unwrap().len() + encoded.len() > 63 {
+                words.push(String::new());
+            }
+
+            words.last_mut().unwrap().push_str(&encoded);
+        }
+
+        words
+            .iter()
+            .map(|w| format!("=?utf-8?Q?{}?=", w))
+            .collect::<Vec<String>>()
+            .join("\r\n ")
+    }
+
+    /// Date of a message (RFC 5322) from a timestamp in milliseconds
+    fn smtp_date(ts: u64) -> String {
+        const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let secs = ts / 1000;
+        let days = secs / 86400;
+
+        /* Civil date from the days since the epoch (H. Hinnant) */
+        let z = days as i64 + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+            DAYS[(days % 7) as usize],
+            day,
+            MONTHS[(month - 1) as usize],
+            year,
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+
+    /// Headers and body of the message, lines starting with a dot are
+    /// doubled so that only the final "." ends the data
+    fn smtp_message(
+        from: &str,
+        to: &[String],
+        event: &AlarmEvent,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut message = format!(
+            "Date: {}\r\nFrom: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            Self::smtp_date(event.ts),
+            from,
+            to.join(", "),
+            Self::smtp_header(&format!(
+                "[{}] {} ({})",
+                event.transition(),
+                event.alarm.name,
+                event.job
+            ))
+        );
+
+        let body = format!(
+            "{}\n\n{}",
+            event.summary(),
+            serde_json::to_string_pretty(event)?
+        );
+
+        for line in body.replace('\r', "").lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message += line;
+            message += "\r\n";
+        }
+
+        Ok(message)
+    }
+
+    fn smtp(
+        server: &str,
+        from: &str,
+        to: &[String],
+        event: &AlarmEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        let server = if server.contains(':') {
+            server.to_string()
+        } else {
+            format!("{}:25", server)
+        };
+
+        let mut stream = TcpStream::connect(&server)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        Self::smtp_reply(&mut reader, &[220])?;
+        Self::smtp_cmd(
+            &mut stream,
+            &mut reader,
+            &format!("HELO {}", hostname()),
+            &[250],
+        )?;
+        Self::smtp_cmd(
+            &mut stream,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", from),
+            &[250],
+        )?;
+        for rcpt in to {
+            Self::smtp_cmd(
+                &mut stream,
+                &mut reader,
+                &format!("RCPT TO:<{}>", rcpt),
+                &[250, 251],
+            )?;
+        }
+        Self::smtp_cmd(&mut stream, &mut reader, "DATA", &[354])?;
+
+        let body = Self::smtp_message(from, to, event)?;
+
+        stream.write_all(body.as_bytes())?;
+        Self::smtp_cmd(&mut stream, &mut reader, ".", &[250])?;
+        Self::smtp_cmd(&mut stream, &mut reader, "QUIT", &[221])?;
+
+        Ok(())
+    }
+
+    fn notify(&mut self, event: &AlarmEvent) -> Result<(), Box<dyn Error>> {
+        let now = unix_ts();
+
+        if self.rate_limited(now) {
+            self.dropped += 1;
+            log::warn!(
+                "Notifier {} is rate limited, dropped {} notification(s) so far",
+                self.name,
+                self.dropped
+            );
+            return Ok(());
+        }
+
+        match &self.config.ttype {
+            NotifierSinkType::Webhook { url, headers } => Self::webhook(url, headers, event),
+            NotifierSinkType::Exec { command, args } => Self::exec(command, args, event),
+            NotifierSinkType::Syslog { socket, facility } => Self::syslog(socket, *facility, event),
+            NotifierSinkType::Smtp { server, from, to } => Self::smtp(server, from, to, event),
+        }
+    }
+}
+
+/************
+ * NOTIFIER *
+ ************/
+
+/// Routes send the events matching all their criteria to the given sinks
+#[derive(Deserialize, Clone, Debug)]
+struct NotifierRouteConfig {
+    /// Pattern on the alarm name
+    #[serde(default)]
+    alarm: Option<String>,
+    /// Pattern on the job ID
+    #[serde(default)]
+    job: Option<String>,
+    /// Minimum severity of the alarm
+    #[serde(default)]
+    severity: Option<AlarmSeverity>,
+    sinks: Vec<String>,
+}
+
+struct NotifierRoute {
+    alarm: Option<Regex>,
+    job: Option<Regex>,
+    severity: Option<AlarmSeverity>,
+    sinks: Vec<String>,
+}
+
+impl NotifierRoute {
+    fn new(config: NotifierRouteConfig) -> Result<NotifierRoute, Box<dyn Error>> {
+        Ok(NotifierRoute {
+            alarm: config.alarm.map(|p| Regex::new(&p)).transpose()?,
+            job: config.job.map(|p| Regex::new(&p)).transpose()?,
+            severity: config.severity,
+            sinks: config.sinks,
+        })
+    }
+
+    fn matches(&self, event: &AlarmEvent) -> bool {
+        self.alarm
+            .as_ref()
+            .is_none_or(|r| r.is_match(&event.alarm.name))
+            && self.job.as_ref().is_none_or(|r| r.is_match(&event.job))
+            && self.severity.is_none_or(|s| event.alarm.severity >= s)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NotifierConfig {
+    sinks: HashMap<String, NotifierSinkConfig>,
+    /// Without routes all events go to all sinks
+    #[serde(default)]
+    routes: Vec<NotifierRouteConfig>,
+    /// Identical transitions are not notified again during this duration
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    dedup: f64,
+}
+
+pub(crate) struct Notifier {
+    sinks: HashMap<String, NotifierSink>,
+    routes: Vec<NotifierRoute>,
+    dedup: f64,
+    /// Last time each (job, host, alarm, firing) was notified
+    last_sent: HashMap<(String, String, String, bool), u64>,
+    transitions: AlarmTransitions,
+}
+
+impl Notifier {
+    pub(crate) fn load(path: &PathBuf) -> Result<Notifier, Box<dyn Error>> {
+        let file = fs::File::open(path)?;
+        let config: NotifierConfig = serde_json::from_reader(file)?;
+
+        let routes = config
+            .routes
+            .into_iter()
+            .map(NotifierRoute::new)
+            .collect::<Result<Vec<NotifierRoute>, Box<dyn Error>>>()?;
+
+        for r in routes.iter() {
+            if let Some(missing) = r.sinks.iter().find(|s| !config.sinks.contains_key(*s)) {
+                return Err(ProxyErr::newboxed(format!(
+                    "Alarm route refers to unknown notifier {}",
+                    missing
+                )));
+            }
+        }
+
+        let sinks: HashMap<String, NotifierSink> = config
+            .sinks
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), NotifierSink::new(&k, v)))
+            .collect();
+
+        log::info!(
+            "Loaded {} alarm notifier(s) and {} route(s) from {}",
+            sinks.len(),
+            routes.len(),
+            path.to_string_lossy()
+        );
+
+        Ok(Notifier {
+            sinks,
+            routes,
+            dedup: config.dedup,
+            last_sent: HashMap::new(),
+            transitions: AlarmTransitions::new(),
+        })
+    }
+
+    fn route(&self, event: &AlarmEvent) -> HashSet<String> {
+        if self.routes.is_empty() {
+            return self.sinks.keys().cloned().collect();
+        }
+
+        self.routes
+            .iter()
+            .filter(|r| r.matches(event))
+            .flat_map(|r| r.sinks.iter().cloned())
+            .collect()
+    }
+
+    fn is_duplicate(&mut self, event: &AlarmEvent) -> bool {
+        let key = (
+            event.job.clone(),
+            event.host.clone(),
+            event.alarm.name.clone(),
+            event.firing,
+        );
+        let dedup = (self.dedup * 1000.0) as u64;
+
+        if let Some(last) = self.last_sent.get(&key) {
+            if event.ts.saturating_sub(*last) < dedup {
+                return true;
+            }
+        }
+
+        self.last_sent.insert(key, event.ts);
+        false
+    }
+
+    fn dispatch(&mut self, event: &AlarmEvent) {
+        if self.is_duplicate(event) {
+            log::debug!("Not notifying duplicate event {}", event.summary());
+            return;
+        }
+
+        for name in self.route(event) {
+            if let Some(sink) = self.sinks.get_mut(&name) {
+                if let Err(e) = sink.notify(event) {
+                    log::error!("Notifier {} failed : {}", name, e);
+                }
+            }
+        }
+    }
+
+    /// Start the notification thread polling the alarms of the factory
+    pub(crate) fn start(mut self, factory: Arc<ExporterFactory>) {
+        thread::spawn(move || loop {
+            let alarms = factory.list_alarms();
+
+            for event in self.transitions.update(&alarms) {
+                self.dispatch(&event);
+            }
+
+            sleep(Duration::from_millis(get_proxy_period()));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarms::AlarmState;
+
+    #[test]
+    fn smtp_message_headers_and_dots() {
+        let event = AlarmEvent {
+            job: "1234\r\nBcc: victim@example.com".to_string(),
+            host: "node0".to_string(),
+            firing: true,
+            ts: 1_792_403_331_000,
+            alarm: ValueAlarmTrigger {
+                name: "h\u{e9}at".to_string(),
+                host: "node0".to_string(),
+                metric: "temp".to_string(),
+                operator: "temp > 90".to_string(),
+                current: 95.0,
+                active: true,
+                state: AlarmState::Firing,
+                severity: AlarmSeverity::Critical,
+                since: 0,
+                pretty: "temp > 90\n.hidden".to_string(),
+                unbound: false,
+            },
+        };
+
+        let message = NotifierSink::smtp_message(
+            "proxy@example.com",
+            &["ops@example.com".to_string()],
+            &event,
+        )
+        .unwrap();
+
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+
+        assert!(headers.starts_with("Date: Mon, 19 Oct 2026 09:48:51 +0000\r\n"));
+        assert!(headers.contains(
+            "Subject: =?utf-8?Q?=5BFIRING=5D_h=C3=A9at_=281234__Bcc=3A_victim=40example=2Ecom?=\r\n =?utf-8?Q?=29?=\r\n"
+        ));
+
+        assert!(!headers.lines().any(|l| l.starts_with("Bcc")));
+
+        /* Every line ends with CRLF and none starts with a single dot */
+        assert!(body.ends_with("\r\n"));
+        assert!(!body.replace("\r\n", "").contains('\n'));
+        assert!(body
+            .split("\r\n")
+            .all(|l| !l.starts_with('.') || l.starts_with("..")));
+        assert!(body.contains("\r\n..hidden"));
+    }
+
+    #[test]
+    fn smtp_headers_are_single_line_ascii() {
+        assert_eq!(
+            NotifierSink::smtp_header("[FIRING] load (1234)"),
+            "[FIRING] load (1234)"
+        );
+        assert_eq!(NotifierSink::smtp_header("a\r\nb"), "a  b");
+
+        let long = NotifierSink::smtp_header(&"\u{e9}".repeat(40));
+        assert!(long.is_ascii());
+        assert!(long
+            .split("\r\n ")
+            .all(|w| w.len() <= 75 && w.starts_with("=?utf-8?Q?")));
+    }
+}
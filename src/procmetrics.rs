@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::proxy_common::unix_ts_us;
+use crate::proxywireprotocol::{CounterSnapshot, CounterType};
+
+/// Values read from /proc for a single process
+#[derive(Default, Clone)]
+struct ProcSample {
+    ppid: u32,
+    starttime: u64,
+    /// CPU times in seconds
+    utime: f64,
+    stime: f64,
+    /// Memory in bytes
+    rss: f64,
+    peak_rss: f64,
+    read_bytes: f64,
+    write_bytes: f64,
+    voluntary_ctxt: f64,
+    involuntary_ctxt: f64,
+    threads: f64,
+}
+
+impl ProcSample {
+    /// Accumulate the cumulative values of another process
+    fn add_counters(&mut self, other: &ProcSample) {
+        self.utime += other.utime;
+        self.stime += other.stime;
+        self.read_bytes += other.read_bytes;
+        self.write_bytes += other.write_bytes;
+        self.voluntary_ctxt += other.voluntary_ctxt;
+        self.involuntary_ctxt += other.involuntary_ctxt;
+    }
+
+    /// Accumulate the instantaneous values of another process
+    fn add_gauges(&mut self, other: &ProcSample) {
+        self.rss += other.rss;
+        self.peak_rss += other.peak_rss;
+        self.threads += other.threads;
+    }
+
+    /// Parse /proc/<pid>/stat only (enough to walk the process tree)
+    fn stat(root: &Path, pid: u32, ticks: f64) -> Option<ProcSample> {
+        let stat = fs::read_to_string(root.join(pid.to_string()).join("stat")).ok()?;
+
+        /* The command may contain spaces and parenthesis */
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+
+        /* fields[0] is the 3rd field of stat(5) */
+        let field = |n: usize| -> Option<f64> { fields.get(n - 3)?.parse::<f64>().ok() };
+
+        Some(ProcSample {
+            ppid: field(4)? as u32,
+            utime: field(14)? / ticks,
+            stime: field(15)? / ticks,
+            threads: field(20)?,
+            starttime: field(22)? as u64,
+            ..Default::default()
+        })
+    }
+
+    /// Complete a sample with /proc/<pid>/status and /proc/<pid>/io
+    fn details(&mut self, root: &Path, pid: u32) {
+        let dir = root.join(pid.to_string());
+
+        let kv = |file: &str| -> HashMap<String, f64> {
+            fs::read_to_string(dir.join(file))
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|l| {
+                    let (k, v) = l.split_once(':')?;
+                    let v = v.split_whitespace().next()?.parse::<f64>().ok()?;
+                    Some((k.trim().to_string(), v))
+                })
+                .collect()
+        };
+
+        let status = kv("status");
+        let get = |m: &HashMap<String, f64>, k: &str| m.get(k).copied().unwrap_or(0.0);
+
+        self.rss = get(&status, "VmRSS") * 1024.0;
+        self.peak_rss = get(&status, "VmHWM") * 1024.0;
+        self.voluntary_ctxt = get(&status, "voluntary_ctxt_switches");
+        self.involuntary_ctxt = get(&status, "nonvoluntary_ctxt_switches");
+
+        /* Only readable for processes of the same user */
+        let io = kv("io");
+        self.read_bytes = get(&io, "read_bytes");
+        self.write_bytes = get(&io, "write_bytes");
+    }
+}
+
+/// Process tree state of a job between two scrapes
+#[derive(Default)]
+struct JobProcesses {
+    /// Start time of the PIDs reported by the job to detect PID reuse
+    roots: HashMap<u32, u64>,
+    /// Processes seen at the last scrape keyed by (pid, starttime)
+    live: HashMap<(u32, u64), ProcSample>,
+    /// Cumulative values of the processes which exited
+    departed: ProcSample,
+}
+
+/// Collects the resources used by the process trees of the local jobs
+pub(crate) struct ProcMetrics {
+    root: PathBuf,
+    ticks: f64,
+    jobs: HashMap<String, JobProcesses>,
+}
+
+impl ProcMetrics {
+    pub(crate) fn new(root: Option<&PathBuf>) -> ProcMetrics {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+
+        ProcMetrics {
+            root: root.cloned().unwrap_or_else(|| PathBuf::from("/proc")),
+
+            ticks: if ticks > 0 { ticks as f64 } else { 100.0 },
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// Snapshot of the stat of all processes
+    fn processes(&self) -> HashMap<u32, ProcSample> {
+        fs::read_dir(&self.root)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().to_str()?.parse::<u32>().ok())
+                    .filter_map(|pid| Some((pid, ProcSample::stat(&self.root, pid, self.ticks)?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn counters(sum: &ProcSample) -> Vec<CounterSnapshot> {
+        let ts = unix_ts_us();
+
+        let counter = |name: &str, labels: &[(String, String)], doc: &str, value: f64| {
+            CounterSnapshot::new(
+                name.to_string(),
+                labels,
+                doc.to_string(),
+                CounterType::Counter { ts, value },
+            )
+        };
+
+        let gauge = |name: &str, doc: &str, value: f64| {
+            CounterSnapshot::new(
+                name.to_string(),
+                &[],
+                doc.to_string(),
+                CounterType::Gauge {
+                    min: value,
+                    max: value,
+                    hits: 1.0,
+                    total: value,
+                },
+            )
+        };
+
+        let label = |k: &str, v: &str| vec![(k.to_string(), v.to_string())];
+
+        vec![
+            counter(
+                "proxy_job_cpu_seconds_total",
+                &label("mode", "user"),
+                "CPU time spent by the processes of the job",
+                sum.utime,
+            ),
+            counter(
+                "proxy_job_cpu_seconds_total",
+                &label("mode", "system"),
+                "CPU time spent by the processes of the job",
+                sum.stime,
+            ),
+            counter(
+                "proxy_job_io_read_bytes_total",
+                &[],
+                "Bytes read from storage by the processes of the job",
+                sum.read_bytes,
+            ),
+            counter(
+                "proxy_job_io_write_bytes_total",
+                &[],
+                "Bytes written to storage by the processes of the job",
+                sum.write_bytes,
+            ),
+            counter(
+                "proxy_job_context_switches_total",
+                &label("kind", "voluntary"),
+                "Context switches of the processes of the job",
+                sum.voluntary_ctxt,
+            ),
+            counter(
+                "proxy_job_context_switches_total",
+                &label("kind", "involuntary"),
+                "Context switches of the processes of the job",
+                sum.involuntary_ctxt,
+            ),
+            gauge(
+                "proxy_job_memory_rss_bytes",
+                "Resident memory of the processes of the job",
+                sum.rss,
+            ),
+            gauge(
+                "proxy_job_memory_peak_rss_bytes",
+                "Sum of the peak resident memory of the processes of the job",
+                sum.peak_rss,
+            ),
+            gauge(
+                "proxy_job_threads",
+                "Number of threads of the processes of the job",
+                sum.threads,
+            ),
+        ]
+    }
+
+    /// Walk the process trees rooted at the PIDs of each job returning
+    /// the counters of each job, jobs absent from the list are forgotten
+    pub(crate) fn scrape(
+        &mut self,
+        jobs: &[(String, Vec<u32>)],
+    ) -> HashMap<String, Vec<CounterSnapshot>> {
+        let mut ret = HashMap::new();
+
+        self.jobs
+            .retain(|jobid, _| jobs.iter().any(|(j, _)| j == jobid));
+
+        if jobs.is_empty() {
+            return ret;
+        }
+
+        let mut procs = self.processes();
+
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (pid, p) in procs.iter() {
+            children.entry(p.ppid).or_default().push(*pid);
+        }
+
+        for (jobid, pids) in jobs {
+            let state = self.jobs.entry(jobid.to_string()).or_default();
+
+            /* Only follow the PIDs which were not reused by another process */
+            let mut stack: Vec<u32> = pids
+                .iter()
+                .filter(|pid| match procs.get(pid) {
+                    Some(p) => *state.roots.entry(**pid).or_insert(p.starttime) == p.starttime,
+                    None => false,
+                })
+                .copied()
+                .collect();
+
+            let mut tree: HashSet<u32> = HashSet::new();
+            while let Some(pid) = stack.pop() {
+                if tree.insert(pid) {
+                    stack.extend(children.get(&pid).into_iter().flatten());
+                }
+            }
+
+            let mut live: HashMap<(u32, u64), ProcSample> = HashMap::new();
+            for pid in tree {
+                if let Some(p) = procs.get_mut(&pid) {
+                    p.details(&self.root, pid);
+                    live.insert((pid, p.starttime), p.clone());
+                }
+            }
+
+            /* Keep the cumulative values of exited processes */
+            for (k, p) in state.live.iter() {
+                if !live.contains_key(k) {
+                    state.departed.add_counters(p);
+                }
+            }
+
+            let mut sum = state.departed.clone();
+            for p in live.values() {
+                sum.add_counters(p);
+                sum.add_gauges(p);
+            }
+
+            let mut counters = ProcMetrics::counters(&sum);
+            counters.push(CounterSnapshot::new(
+                "proxy_job_processes".to_string(),
+                &[],
+                "Number of processes of the job".to_string(),
+                CounterType::Gauge {
+                    min: live.len() as f64,
+                    max: live.len() as f64,
+                    hits: 1.0,
+                    total: live.len() as f64,
+                },
+            ));
+
+            state.live = live;
+            ret.insert(jobid.to_string(), counters);
+        }
+
+        ret
+    }
+}
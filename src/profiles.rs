@@ -7,11 +7,12 @@ use serde::de::value;
 use super::proxywireprotocol::{JobDesc, JobProfile};
 use crate::extrap::ExtrapModel;
 use crate::proxy_common::{check_prefix_dir, list_files_with_ext_in, ProxyErr};
+use crate::sacct::SacctQueue;
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::Read;
 use std::path::PathBuf;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{any, fs};
 
 use anyhow::Result;
@@ -22,6 +23,8 @@ pub(crate) struct ProfileView {
     profdir: PathBuf,
     profiles: RwLock<HashMap<String, JobProfile>>,
     models: Mutex<HashMap<String, ExtrapEval>>,
+    /// None when the accounting is disabled
+    sacct: Option<SacctQueue>,
 }
 
 impl ProfileView {
@@ -170,6 +173,18 @@ impl ProfileView {
         let cmd_hash = md5::compute(&desc.command);
         let hash = format!("{:x}", cmd_hash);
 
+        /* The model may have been generated since the profiles were loaded */
+        if self.models.lock().unwrap().get(&hash).is_none() {
+            if let (Some(path), _) = self.extrap_filename(&desc.command) {
+                let model = ExtrapEval::new(path)?;
+                self.models
+                    .lock()
+                    .unwrap()
+                    .entry(hash.clone())
+                    .or_insert(model);
+            }
+        }
+
         if let Some(m) = self.models.lock().unwrap().get_mut(&hash) {
             let val = m.evaluate(&metric, size)?;
             Ok((size, val))
@@ -309,6 +324,36 @@ impl ProfileView {
         Ok(())
     }
 
+    /// Query the Slurm accounting of a finished job in the background
+    /// and add it to its saved profile once the job reached a final state
+    pub(crate) fn enrich_with_accounting(self: &Arc<Self>, desc: &JobDesc) {
+        let sacct = match &self.sacct {
+            Some(s) => s,
+            None => return,
+        };
+
+        /* Only Slurm jobs have an accounting */
+        if desc.partition.is_empty() && desc.cluster.is_empty() {
+            return;
+        }
+
+        let store = self.clone();
+        let desc = desc.clone();
+
+        sacct.push(&desc.jobid.clone(), move |accounting| {
+            let mut snap = match store.profiles.read().unwrap().get(&desc.jobid) {
+                Some(p) => p.clone(),
+                None => return,
+            };
+
+            snap.set_accounting(accounting);
+
+            if let Err(e) = store.saveprofile(snap, &desc) {
+                log::error!("Failed to save the accounting of {} : {}", desc.jobid, e);
+            }
+        });
+    }
+
     pub(crate) fn new(profdir: &PathBuf) -> Result<ProfileView, Box<dyn Error>> {
         let profdir = check_prefix_dir(profdir, "profiles")?;
 
@@ -316,6 +361,7 @@ impl ProfileView {
             profdir,
             profiles: RwLock::new(HashMap::new()),
             models: Mutex::new(HashMap::new()),
+            sacct: SacctQueue::new(),
         };
 
         ret.refresh_profiles()?;
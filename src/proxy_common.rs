@@ -62,6 +62,29 @@ pub fn get_proxy_period() -> u64 {
         .unwrap_or(1000)
 }
 
+#[allow(unused)]
+pub fn get_scrape_failure_budget() -> u64 {
+    env::var("PROXY_SCRAPE_FAILURE_BUDGET")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(10)
+}
+
+/// Command queried for the accounting of finished jobs (disabled when unset)
+#[allow(unused)]
+pub fn get_sacct_command() -> String {
+    env::var("PROXY_SACCT_COMMAND").unwrap_or_default()
+}
+
+#[allow(unused)]
+pub fn get_scrape_workers() -> usize {
+    env::var("PROXY_SCRAPE_WORKERS")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(8)
+}
+
 #[allow(unused)]
 pub fn unix_ts() -> u64 {
     let current_time = SystemTime::now();
@@ -151,8 +174,11 @@ pub(crate) fn hostname() -> String {
 }
 
 #[allow(unused)]
-pub(crate) fn is_url_live(url: &str, html: bool) -> Result<(), Box<dyn Error>> {
-    let client = reqwest::blocking::Client::new();
+pub(crate) fn is_url_live(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    html: bool,
+) -> Result<(), Box<dyn Error>> {
     let response = client.get(url).send()?;
 
     if response.status().is_success() {
@@ -4,7 +4,6 @@ use crate::proxy_common::ProxyErr;
 
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::sync::{Arc, RwLock};
 
 use std::{collections::HashMap, env, error::Error};
 
@@ -134,8 +133,11 @@ impl CounterType {
 
     fn serialize(&self, name: &String) -> String {
         match self {
-            Self::Counter { ts, value } => {
-                format!("{} {} {}\n", name, ts, value)
+            /* The exposition format expects "name value [timestamp_ms]" while
+            counter timestamps are in us or ms depending on their source,
+            they are left out and samples are stamped at scrape time */
+            Self::Counter { ts: _, value } => {
+                format!("{} {}\n", name, value)
             }
             Self::Gauge {
                 min: _,
@@ -292,122 +294,6 @@ impl CounterType {
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
-pub(crate) enum AlarmOperator {
-    #[allow(unused)]
-    Equal(f64),
-    #[allow(unused)]
-    Less(f64),
-    #[allow(unused)]
-    More(f64),
-}
-
-impl AlarmOperator {
-    fn apply(&self, val: &CounterType) -> bool {
-        let value: f64 = val.value();
-
-        match self {
-            Self::Equal(v) => *v == value,
-            Self::Less(v) => *v > value,
-            Self::More(v) => *v < value,
-        }
-    }
-}
-
-impl fmt::Display for AlarmOperator {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self {
-            Self::Equal(v) => write!(f, "= {}", *v),
-            Self::Less(v) => write!(f, "< {}", *v),
-            Self::More(v) => write!(f, "> {}", *v),
-        }
-    }
-}
-
-#[derive(Serialize, Debug)]
-pub(crate) struct ValueAlarmTrigger {
-    pub(crate) name: String,
-    pub(crate) metric: String,
-    pub(crate) operator: AlarmOperator,
-    pub(crate) current: f64,
-    pub(crate) active: bool,
-    pub(crate) pretty: String,
-}
-
-pub(crate) struct ValueAlarm {
-    name: String,
-    counter: Arc<RwLock<CounterSnapshot>>,
-    op: AlarmOperator,
-}
-
-impl fmt::Display for ValueAlarm {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} : {} {}",
-            self.name,
-            self.counter.read().unwrap(),
-            self.op
-        )
-    }
-}
-
-impl ValueAlarm {
-    #[allow(unused)]
-    pub(crate) fn new(
-        name: &String,
-        counter: Arc<RwLock<CounterSnapshot>>,
-        op: String,
-        val: f64,
-    ) -> Result<ValueAlarm, ProxyErr> {
-        let alop = match op.as_str() {
-            "=" => AlarmOperator::Equal(val),
-            "<" => AlarmOperator::Less(val),
-            ">" => AlarmOperator::More(val),
-            _ => {
-                return Err(ProxyErr::new(format!(
-                    "No operator for {} only has = < and >",
-                    op
-                )));
-            }
-        };
-
-        Ok(ValueAlarm {
-            name: name.to_string(),
-            counter: counter.clone(),
-            op: alop,
-        })
-    }
-
-    #[allow(unused)]
-    pub(crate) fn as_trigger(&self, active: Option<bool>) -> ValueAlarmTrigger {
-        let cnt_locked = self.counter.read().unwrap();
-
-        let is_active = match active {
-            Some(v) => v,
-            None => self.op.apply(&self.counter.read().unwrap().ctype),
-        };
-
-        ValueAlarmTrigger {
-            name: self.name.to_string(),
-            metric: cnt_locked.name.to_string(),
-            operator: self.op.clone(),
-            current: cnt_locked.ctype.value(),
-            active: is_active,
-            pretty: self.to_string(),
-        }
-    }
-
-    #[allow(unused)]
-    pub(crate) fn check(&self) -> Option<ValueAlarmTrigger> {
-        if self.op.apply(&self.counter.read().unwrap().ctype) {
-            Some(self.as_trigger(Some(true)))
-        } else {
-            None
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ValueDesc {
     pub(crate) name: String,
@@ -456,6 +342,22 @@ impl CounterValue {
     }
 }
 
+/// Outcome of a job as recorded by the Slurm accounting (sacct)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct JobAccounting {
+    /// Final state (COMPLETED, FAILED, TIMEOUT, OUT_OF_MEMORY, CANCELLED, ...)
+    pub(crate) state: String,
+    pub(crate) exit_code: i32,
+    /// Signal which terminated the job (0 if none)
+    pub(crate) signal: i32,
+    /// Requested walltime in seconds (0 if unlimited)
+    pub(crate) time_limit: u64,
+    /// Walltime used in seconds
+    pub(crate) elapsed: u64,
+    /// CPU time used over the CPU time allocated (0 to 1)
+    pub(crate) cpu_efficiency: f64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct JobDesc {
     pub(crate) jobid: String,
@@ -467,6 +369,14 @@ pub(crate) struct JobDesc {
     pub(crate) run_dir: String,
     pub(crate) start_time: u64,
     pub(crate) end_time: u64,
+    #[serde(default)]
+    pub(crate) user: String,
+    /// PID of the client process (0 if unknown)
+    #[serde(default)]
+    pub(crate) pid: u32,
+    /// Scheduler outcome filled once the job has ended
+    #[serde(default)]
+    pub(crate) accounting: Option<JobAccounting>,
 }
 
 impl JobDesc {
@@ -492,6 +402,10 @@ impl JobDesc {
             self.end_time
         };
 
+        if self.accounting.is_none() {
+            self.accounting = other_desc.accounting;
+        }
+
         Ok(())
     }
 }
@@ -529,6 +443,9 @@ impl JobDesc {
         let nodelist = env::var("SLURM_JOB_NODELIST").unwrap_or("".to_string());
         let partition = env::var("SLURM_JOB_PARTITION").unwrap_or("".to_string());
         let cluster = env::var("SLURM_CLUSTER_NAME").unwrap_or("".to_string());
+        let user = env::var("SLURM_JOB_USER")
+            .or_else(|_| env::var("USER"))
+            .unwrap_or("".to_string());
         let run_dir = env::current_dir()
             .map(|v| v.to_string_lossy().to_string())
             .unwrap_or("".to_string());
@@ -552,6 +469,9 @@ impl JobDesc {
             run_dir,
             start_time: unix_ts(),
             end_time: 0,
+            user,
+            pid: std::process::id(),
+            accounting: None,
         }
     }
 }
@@ -640,7 +560,7 @@ impl CounterSnapshot {
         self.ctype.set(&other.ctype)
     }
 
-    fn delta(&mut self, other: &CounterSnapshot) -> Result<(), ProxyErr> {
+    pub(crate) fn delta(&mut self, other: &CounterSnapshot) -> Result<(), ProxyErr> {
         self.ctype.delta(&other.ctype)
     }
 
@@ -784,7 +704,54 @@ impl JobProfile {
         None
     }
 
+    /// Add the accounting of the job as counters of the profile
+    #[allow(unused)]
+    pub(crate) fn set_accounting(&mut self, accounting: JobAccounting) {
+        let counters = [
+            (
+                "slurm_exit_code",
+                "Exit code of the job",
+                accounting.exit_code as f64,
+            ),
+            (
+                "slurm_time_limit",
+                "Requested walltime of the job in seconds (0 if unlimited)",
+                accounting.time_limit as f64,
+            ),
+            (
+                "slurm_elapsed",
+                "Walltime of the job in seconds as accounted by Slurm",
+                accounting.elapsed as f64,
+            ),
+            (
+                "slurm_cpu_efficiency",
+                "CPU time used over the CPU time allocated to the job",
+                accounting.cpu_efficiency,
+            ),
+        ];
+
+        self.counters
+            .retain(|c| !counters.iter().any(|(name, _, _)| c.name == *name));
+
+        for (name, doc, value) in counters {
+            self.counters.push(CounterSnapshot::new(
+                name.to_string(),
+                &[],
+                doc.to_string(),
+                CounterType::Counter { ts: 0, value },
+            ));
+        }
+
+        self.desc.accounting = Some(accounting);
+    }
+
+    /// A job completed if Slurm accounted it as such, without
+    /// accounting the client must have reported its start and end
     pub(crate) fn did_complete(&self) -> bool {
+        if let Some(accounting) = &self.desc.accounting {
+            return accounting.state == "COMPLETED" && accounting.exit_code == 0;
+        }
+
         if let (Some(start), Some(end)) = (self.get("has_started"), self.get("has_finished")) {
             return (start.value().value.value() != 0.0) && (end.value().value.value() != 0.0);
         }
@@ -793,6 +760,75 @@ impl JobProfile {
     }
 }
 
+/// Changes of the jobs of a proxy forwarded to its parent
+#[allow(unused)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct DeltaBatch {
+    /// Address of the proxy sending the batch
+    pub(crate) source: String,
+    /// Incremented for each batch to detect losses, including resent ones.
+    /// A batch not acknowledged is followed by a full snapshot which the
+    /// parent merges as a difference, it is not counted twice if the lost
+    /// batch was applied. Changes with an already applied seq are ignored
+    pub(crate) seq: u64,
+    /// Jobs hold their full values instead of the changes since the previous batch
+    pub(crate) full: bool,
+    pub(crate) jobs: Vec<JobProfile>,
+    /// Jobs which ended since the previous batch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) left: Vec<JobDesc>,
+}
+
+/// Jobs of a proxy changed since a sequence number as returned by /job/delta
+#[allow(unused)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct JobChanges {
+    /// Start of the proxy, sequence numbers of another epoch are meaningless
+    pub(crate) epoch: u64,
+    /// Sequence number to request the next changes
+    pub(crate) seq: u64,
+    /// All the jobs are listed, the missing ones have ended
+    pub(crate) full: bool,
+    /// Jobs with the current values of their changed counters
+    pub(crate) jobs: Vec<JobProfile>,
+    /// Jobs which ended since the sequence number
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) left: Vec<JobDesc>,
+}
+
+/// Status of a proxy of the reduction tree with the ones below it, each
+/// proxy reports its subtree to its parent along with its heartbeats
+#[allow(unused)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct TopoStatus {
+    /// Address of the proxy
+    pub(crate) url: String,
+    pub(crate) version: String,
+    /// Time in seconds since the proxy started
+    pub(crate) uptime: u64,
+    /// Number of running jobs known to the proxy
+    pub(crate) jobs: usize,
+    /// The proxy pushes its changes instead of being scraped
+    pub(crate) push: bool,
+    /// Timestamp in seconds of the last heartbeat received by the parent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_heartbeat: Option<u64>,
+    /// Timestamp in seconds of the last data received by the parent
+    /// (successful scrape or batch of changes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_success: Option<u64>,
+    /// Age in seconds of the data of the proxy in its parent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) lag: Option<u64>,
+    /// The parent receives heartbeats and data in time
+    pub(crate) healthy: bool,
+    /// Why the proxy is not healthy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(default)]
+    pub(crate) children: Vec<TopoStatus>,
+}
+
 /****************
  * IN WEBSERVER *
  ****************/
@@ -0,0 +1,365 @@
+use serde_json::Value;
+use std::error::Error;
+use std::process::Command;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::proxy_common::{self, ProxyErr};
+use crate::proxywireprotocol::JobAccounting;
+
+/// Number of sacct queries before giving up on a job
+const SACCT_ATTEMPTS: u32 = 8;
+/// Delay before the first query, doubled after each attempt
+const SACCT_FIRST_DELAY: Duration = Duration::from_secs(2);
+
+/// States in which the accounting of a job is not final yet
+const PENDING_STATES: [&str; 9] = [
+    "PENDING",
+    "RUNNING",
+    "REQUEUED",
+    "RESIZING",
+    "SUSPENDED",
+    "COMPLETING",
+    "CONFIGURING",
+    "SIGNALING",
+    "STAGE_OUT",
+];
+
+/// Queries the Slurm accounting with `sacct --json`
+pub(crate) struct Sacct {
+    command: String,
+}
+
+impl Sacct {
+    /// None when the accounting is disabled
+    pub(crate) fn new() -> Option<Sacct> {
+        let command = proxy_common::get_sacct_command();
+
+        if command.is_empty() {
+            return None;
+        }
+
+        Some(Sacct { command })
+    }
+
+    /// Numbers are either plain or {"set": bool, "infinite": bool, "number": n}
+    /// depending on the Slurm version
+    fn number(v: &Value) -> Option<f64> {
+        match v {
+            Value::Number(n) => n.as_f64(),
+            Value::Object(o) => {
+                if o.get("set").and_then(|s| s.as_bool()) == Some(false)
+                    || o.get("infinite").and_then(|s| s.as_bool()) == Some(true)
+                {
+                    return None;
+                }
+                o.get("number").and_then(Sacct::number)
+            }
+            _ => None,
+        }
+    }
+
+    /// States are a string, a list of flags or {"current": ...}
+    fn state(v: &Value) -> Option<String> {
+        match v {
+            Value::String(s) => Some(s.to_string()),
+            Value::Array(a) => a.first().and_then(Sacct::state),
+            Value::Object(o) => o.get("current").and_then(Sacct::state),
+            _ => None,
+        }
+    }
+
+    fn cpus(entry: &Value) -> Option<f64> {
+        if let Some(cpus) = Sacct::number(&entry["required"]["CPUs"]) {
+            return Some(cpus);
+        }
+
+        entry["tres"]["allocated"]
+            .as_array()?
+            .iter()
+            .find(|t| t["type"] == "cpu")
+            .and_then(|t| Sacct::number(&t["count"]))
+    }
+
+    /// Accounting of a job or step entry of the sacct output
+    fn accounting(entry: &Value, time_limit: u64) -> Option<JobAccounting> {
+        let state = Sacct::state(&entry["state"])?;
+        let elapsed = Sacct::number(&entry["time"]["elapsed"]).unwrap_or(0.0);
+
+        let cpu_time = Sacct::number(&entry["time"]["total"]["seconds"]).unwrap_or(0.0)
+            + Sacct::number(&entry["time"]["total"]["microseconds"]).unwrap_or(0.0) / 1e6;
+
+        let cpu_efficiency = match Sacct::cpus(entry) {
+            Some(cpus) if cpus > 0.0 && elapsed > 0.0 => cpu_time / (cpus * elapsed),
+            _ => 0.0,
+        };
+
+        let exit = &entry["exit_code"];
+        let signal = &exit["signal"];
+
+        Some(JobAccounting {
+            state,
+            exit_code: Sacct::number(&exit["return_code"]).unwrap_or(0.0) as i32,
+            signal: Sacct::number(&signal["id"])
+                .or_else(|| Sacct::number(&signal["signal_id"]))
+                .unwrap_or(0.0) as i32,
+            time_limit,
+            elapsed: elapsed as u64,
+            cpu_efficiency,
+        })
+    }
+
+    /// Parse the output of sacct for a "job" or "job-step" identifier
+    fn parse(data: &str, jobid: &str) -> Result<Option<JobAccounting>, Box<dyn Error>> {
+        let (job, step) = match jobid.split_once('-') {
+            Some((job, step)) => (job, Some(step)),
+            None => (jobid, None),
+        };
+
+        /* Job ids are numbers in the JSON output */
+        let id = match job.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+
+        let output: Value = serde_json::from_str(data)?;
+
+        let entry = match output["jobs"]
+            .as_array()
+            .and_then(|jobs| jobs.iter().find(|j| j["job_id"].as_u64() == Some(id)))
+        {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        /* The time limit is in minutes */
+        let time_limit = Sacct::number(&entry["time"]["limit"])
+            .map(|m| (m * 60.0) as u64)
+            .unwrap_or(0);
+
+        match step {
+            None => Ok(Sacct::accounting(entry, time_limit)),
+            Some(step) => {
+                let name = format!("{}.{}", job, step);
+                let entry = entry["steps"].as_array().and_then(|steps| {
+                    steps.iter().find(|s| {
+                        let id = &s["step"]["id"];
+                        id.as_str() == Some(name.as_str())
+                            || id.as_u64().is_some_and(|i| step.parse() == Ok(i))
+                    })
+                });
+
+                Ok(entry.and_then(|e| Sacct::accounting(e, time_limit)))
+            }
+        }
+    }
+
+    /// Accounting of a job, None while it is not in a final state
+    pub(crate) fn query(&self, jobid: &str) -> Result<Option<JobAccounting>, Box<dyn Error>> {
+        let job = jobid.split_once('-').map(|(job, _)| job).unwrap_or(jobid);
+
+        let output = Command::new(&self.command)
+            .args(["--json", "-j", job])
+            .output()?;
+
+        if !output.status.success() {
+            return Err(ProxyErr::newboxed(format!(
+                "{} failed : {}",
+                self.command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let accounting = Sacct::parse(&String::from_utf8_lossy(&output.stdout), jobid)?;
+
+        Ok(accounting.filter(|a| !PENDING_STATES.contains(&a.state.as_str())))
+    }
+}
+
+/// Called with the accounting of a job once final
+type AccountingDone = Box<dyn FnOnce(JobAccounting) + Send>;
+
+/// A job waiting for its accounting
+struct SacctPending {
+    jobid: String,
+    done: AccountingDone,
+    attempts: u32,
+    delay: Duration,
+    due: Instant,
+}
+
+/// Polls the accounting of finished jobs from a single thread, each job
+/// is queried with an exponential delay until it reaches a final state
+pub(crate) struct SacctQueue {
+    tx: Sender<(String, AccountingDone)>,
+}
+
+impl SacctQueue {
+    /// None when the accounting is disabled
+    pub(crate) fn new() -> Option<SacctQueue> {
+        let sacct = Sacct::new()?;
+        let (tx, rx) = channel();
+
+        thread::spawn(move || SacctQueue::run(sacct, rx));
+
+        Some(SacctQueue { tx })
+    }
+
+    /// Wait for the accounting of a job in the background
+    pub(crate) fn push<F>(&self, jobid: &str, done: F)
+    where
+        F: FnOnce(JobAccounting) + Send + 'static,
+    {
+        if self.tx.send((jobid.to_string(), Box::new(done))).is_err() {
+            log::error!("Accounting queue is gone, dropping job {}", jobid);
+        }
+    }
+
+    fn run(sacct: Sacct, rx: Receiver<(String, AccountingDone)>) {
+        let mut pending: Vec<SacctPending> = Vec::new();
+        let mut open = true;
+
+        loop {
+            let now = Instant::now();
+            let wait = pending
+                .iter()
+                .map(|p| p.due.saturating_duration_since(now))
+                .min();
+
+            let received = match (open, wait) {
+                (true, Some(wait)) => rx.recv_timeout(wait),
+                (true, None) => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                (false, Some(wait)) => {
+                    sleep(wait);
+                    Err(RecvTimeoutError::Timeout)
+                }
+                (false, None) => return,
+            };
+
+            match received {
+                Ok((jobid, done)) => pending.push(SacctPending {
+                    jobid,
+                    done,
+                    attempts: 0,
+                    delay: SACCT_FIRST_DELAY,
+                    due: Instant::now() + SACCT_FIRST_DELAY,
+                }),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => open = false,
+            }
+
+            let now = Instant::now();
+            let (due, waiting): (Vec<SacctPending>, Vec<SacctPending>) =
+                pending.drain(..).partition(|p| p.due <= now);
+            pending = waiting;
+
+            for mut p in due {
+                match sacct.query(&p.jobid) {
+                    Ok(Some(a)) => (p.done)(a),
+                    Ok(None) if p.attempts + 1 < SACCT_ATTEMPTS => {
+                        p.attempts += 1;
+                        p.delay *= 2;
+                        p.due = now + p.delay;
+                        pending.push(p);
+                    }
+                    Ok(None) => log::debug!("Job {} did not reach a final state in sacct", p.jobid),
+                    Err(e) => log::debug!("No accounting for job {} : {}", p.jobid, e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A job entry with two steps, in the format of recent Slurm versions
+    const OUTPUT: &str = r#"{"jobs": [{
+        "job_id": 42,
+        "state": {"current": ["TIMEOUT"], "reason": "None"},
+        "exit_code": {"status": ["SIGNALED"], "return_code": {"set": false, "number": 0},
+                      "signal": {"id": {"set": true, "number": 9}}},
+        "time": {"limit": {"set": true, "infinite": false, "number": 10}, "elapsed": 600,
+                 "total": {"seconds": 1200, "microseconds": 0}},
+        "required": {"CPUs": 4},
+        "steps": [
+            {"step": {"id": "42.0"}, "state": "COMPLETED",
+             "exit_code": {"return_code": 0}, "time": {"elapsed": 100,
+             "total": {"seconds": 100, "microseconds": 0}},
+             "tres": {"allocated": [{"type": "cpu", "count": 2}]}},
+            {"step": {"id": 1}, "state": ["FAILED"],
+             "exit_code": {"return_code": 3, "signal": {"signal_id": 0}},
+             "time": {"elapsed": 10, "total": {"seconds": 0, "microseconds": 0}}}
+        ]
+    }]}"#;
+
+    #[test]
+    fn job_states_and_exit_codes_are_parsed() {
+        let a = Sacct::parse(OUTPUT, "42").unwrap().unwrap();
+
+        assert_eq!(a.state, "TIMEOUT");
+        assert_eq!(a.exit_code, 0);
+        assert_eq!(a.signal, 9);
+        assert_eq!(a.time_limit, 600);
+        assert_eq!(a.elapsed, 600);
+        assert_eq!(a.cpu_efficiency, 0.5);
+    }
+
+    #[test]
+    fn steps_are_found_by_name_or_id() {
+        let a = Sacct::parse(OUTPUT, "42-0").unwrap().unwrap();
+        assert_eq!(a.state, "COMPLETED");
+        assert_eq!(a.exit_code, 0);
+        /* Steps inherit the time limit of their job */
+        assert_eq!(a.time_limit, 600);
+        assert_eq!(a.cpu_efficiency, 0.5);
+
+        let a = Sacct::parse(OUTPUT, "42-1").unwrap().unwrap();
+        assert_eq!(a.state, "FAILED");
+        assert_eq!(a.exit_code, 3);
+        assert_eq!(a.signal, 0);
+        assert_eq!(a.cpu_efficiency, 0.0);
+
+        assert!(Sacct::parse(OUTPUT, "42-2").unwrap().is_none());
+        assert!(Sacct::parse(OUTPUT, "43").unwrap().is_none());
+        assert!(Sacct::parse(OUTPUT, "notajob").unwrap().is_none());
+        assert!(Sacct::parse("not json", "42").is_err());
+    }
+
+    #[test]
+    fn unfinished_jobs_have_no_accounting() {
+        let root = std::env::temp_dir().join(format!("proxy-sacct-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let output = root.join("output.json");
+        let command = root.join("sacct");
+        fs::write(&command, format!("#!/bin/sh\ncat {}\n", output.display())).unwrap();
+        fs::set_permissions(&command, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let sacct = Sacct {
+            command: command.to_string_lossy().to_string(),
+        };
+
+        fs::write(&output, r#"{"jobs": [{"job_id": 7, "state": "RUNNING"}]}"#).unwrap();
+        assert!(sacct.query("7").unwrap().is_none());
+
+        fs::write(
+            &output,
+            r#"{"jobs": [{"job_id": 7, "state": "COMPLETED"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(sacct.query("7").unwrap().unwrap().state, "COMPLETED");
+
+        let failing = Sacct {
+            command: "false".to_string(),
+        };
+        assert!(failing.query("7").is_err());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,26 +1,57 @@
+use crate::alarms::ValueAlarmTrigger;
+use crate::auth::Auth;
+use crate::cgroupmetrics::CgroupMetrics;
+use crate::config::{RelabelRule, ScrapeAuth, ScrapeTargetConfig, ScrapeTargetType};
+use crate::counterfiles::CounterFiles;
 use crate::exporter::Exporter;
+use crate::filetail::FileTail;
 use crate::proxy_common::{self, is_url_live, unix_ts};
 use crate::proxy_common::{unix_ts_us, ProxyErr};
-use crate::proxywireprotocol::{CounterSnapshot, CounterType, JobDesc, JobProfile};
+use crate::proxywireprotocol::{
+    ApiResponse, CounterSnapshot, CounterType, JobChanges, JobDesc, JobProfile,
+};
 use crate::trace::{Trace, TraceView};
 use crate::ExporterFactory;
 use core::fmt;
-use reqwest::blocking::Client;
+use reqwest::blocking::RequestBuilder;
+use reqwest::{Method, StatusCode};
 use serde::Serialize;
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::write;
-use std::sync::Arc;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec;
 
+use crate::procmetrics::ProcMetrics;
 use crate::systemmetrics::SystemMetrics;
 
 enum ScraperType {
-    Proxy,
+    Proxy {
+        base_url: String,
+    },
     Prometheus,
     SystemMetrics {
         sys: Box<SystemMetrics>,
     },
+    Processes {
+        procs: Box<ProcMetrics>,
+    },
+    Cgroup {
+        cgroups: Box<CgroupMetrics>,
+    },
+    Sysfs {
+        files: Box<CounterFiles>,
+    },
+    File {
+        tail: Box<FileTail>,
+        jobid: String,
+    },
     Trace {
         exporter: Arc<Exporter>,
         trace: Arc<Trace>,
@@ -34,9 +65,13 @@ enum ScraperType {
 impl fmt::Display for ScraperType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ScraperType::Proxy => write!(f, "Proxy"),
+            ScraperType::Proxy { .. } => write!(f, "Proxy"),
             ScraperType::Prometheus => write!(f, "Prometheus"),
             ScraperType::SystemMetrics { .. } => write!(f, "System"),
+            ScraperType::Processes { .. } => write!(f, "Processes"),
+            ScraperType::Cgroup { .. } => write!(f, "Cgroup"),
+            ScraperType::Sysfs { .. } => write!(f, "Sysfs"),
+            ScraperType::File { jobid, .. } => write!(f, "File for job {}", jobid),
             ScraperType::Trace { exporter: _, trace } => {
                 write!(f, "Trace job {} in {}", trace.desc().jobid, trace.path())
             }
@@ -47,6 +82,25 @@ impl fmt::Display for ScraperType {
         }
     }
 }
+/// Deadline of scrape requests when no timeout is configured
+const DEFAULT_SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest delay between two attempts to scrape a failing target
+const MAX_SCRAPE_BACKOFF: u64 = 5 * 60 * 1000;
+
+/// Outcome of the last scrapes of a target
+#[derive(Serialize, Clone, Default)]
+pub struct ScrapeHealth {
+    /// Duration of the last scrape in seconds
+    duration: f64,
+    last_error: Option<String>,
+    consecutive_failures: u64,
+    scrapes: u64,
+    failures: u64,
+    /// Timestamp in seconds of the last successful scrape
+    last_success: u64,
+}
+
 pub struct ProxyScraper {
     target_url: String,
     state: HashMap<String, JobProfile>,
@@ -54,44 +108,133 @@ pub struct ProxyScraper {
     period: u64,
     last_scrape: u64,
     ttype: ScraperType,
+    /// Timeout of scrape requests
+    timeout: Option<Duration>,
+    /// Relabel rules applied to all scraped series
+    relabel: Vec<RelabelRule>,
+    health: ScrapeHealth,
+    /// Consecutive failures before the target is dropped (0 for never)
+    failure_budget: u64,
+    /// Credentials sent along the scrape requests
+    auth: Option<ScrapeAuth>,
+    /// Use the sample timestamps of Prometheus targets
+    honor_timestamps: bool,
+    /// Timestamp in us of the last value of each Prometheus series
+    sample_ts: HashMap<String, u64>,
+    /// Epoch and sequence number of the last changes of a child proxy
+    cursor: Option<(u64, u64)>,
+    /// The child proxy provides /job/delta, None until known
+    incremental: Option<bool>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct ProxyScraperSnapshot {
     target_url: String,
     ttype: String,
     period: u64,
     last_scrape: u64,
+    /// Timestamp in seconds of the next scrape
+    next_scrape: u64,
+    healthy: bool,
+    failure_budget: u64,
+    #[serde(flatten)]
+    health: ScrapeHealth,
+}
+
+impl ProxyScraperSnapshot {
+    /// Timestamp in seconds of the last successful scrape (0 if none)
+    #[allow(unused)]
+    pub(crate) fn last_success(&self) -> u64 {
+        self.health.last_success
+    }
+
+    #[allow(unused)]
+    pub(crate) fn last_error(&self) -> Option<&String> {
+        self.health.last_error.as_ref()
+    }
 }
 
 impl ProxyScraper {
-    fn detect_type(target_url: &String) -> Result<(String, ScraperType), ProxyErr> {
-        if target_url == "/system" {
-            return Ok((
-                target_url.to_string(),
-                ScraperType::SystemMetrics {
-                    sys: Box::new(SystemMetrics::new()),
-                },
-            ));
-        }
+    fn with_scheme(target_url: &str) -> String {
+        Auth::url(target_url)
+    }
 
-        let url: String = if !target_url.starts_with("http") {
-            "http://".to_string() + target_url.as_str()
-        } else {
-            target_url.to_string()
+    /// Type of the builtin local scrapes with their optional configuration
+    fn local_type(
+        target_url: &str,
+        config: Option<&ScrapeTargetConfig>,
+    ) -> Option<(String, ScraperType)> {
+        let ttype = match target_url {
+            "/system" => ScraperType::SystemMetrics {
+                sys: Box::new(SystemMetrics::new(
+                    config.and_then(|c| c.root.as_ref()),
+                    config.and_then(|c| c.collectors.as_ref()),
+                )),
+            },
+            "/processes" => ScraperType::Processes {
+                procs: Box::new(ProcMetrics::new(config.and_then(|c| c.root.as_ref()))),
+            },
+            "/cgroup" => ScraperType::Cgroup {
+                cgroups: Box::new(CgroupMetrics::new(
+                    config.and_then(|c| c.root.as_ref()),
+                    config.and_then(|c| c.path.as_ref()),
+                )),
+            },
+            _ => return None,
         };
 
+        Some((target_url.to_string(), ttype))
+    }
+
+    /// True if the URL has a path after its host:port
+    fn has_path(url: &str) -> bool {
+        url.splitn(4, '/').nth(3).is_some_and(|p| !p.is_empty())
+    }
+
+    fn metrics_path(path: Option<&String>) -> String {
+        match path {
+            Some(p) if p.starts_with('/') => p.to_string(),
+            Some(p) => format!("/{}", p),
+            None => "/metrics".to_string(),
+        }
+    }
+
+    fn detect_type(
+        target_url: &String,
+        metrics_path: &str,
+    ) -> Result<(String, ScraperType), ProxyErr> {
+        if let Some(local) = ProxyScraper::local_type(target_url, None) {
+            return Ok(local);
+        }
+
+        let url = ProxyScraper::with_scheme(target_url);
+
+        let client = Auth::client(DEFAULT_SCRAPE_TIMEOUT)
+            .map_err(|e| ProxyErr::new(format!("Failed to create a client : {}", e)))?;
+
+        /* Only Prometheus exporters are given with a path */
+        if ProxyScraper::has_path(&url) {
+            if let Err(e) = is_url_live(&client, &url, false) {
+                return Err(ProxyErr::new(format!(
+                    "Failed to determine type of {} : {}",
+                    target_url, e
+                )));
+            }
+            log::info!("{} is a Prometheus Exporter", url);
+            return Ok((url, ScraperType::Prometheus));
+        }
+
         /* Now determine the type first as a Proxy Exporter */
         let test_page_url = url.clone() + "/is_admire_proxy.html";
-        if is_url_live(&test_page_url, true).is_ok() {
+        if is_url_live(&client, &test_page_url, true).is_ok() {
             log::info!("{} is a Proxy Exporter", url);
             let joburl = url.clone() + "/job";
-            return Ok((joburl, ScraperType::Proxy));
+            return Ok((joburl, ScraperType::Proxy { base_url: url }));
         }
 
         /* First as a prometheus exporter */
-        let promurl = url.to_string() + "/metrics";
-        if is_url_live(&promurl, false).is_ok() {
+        let promurl = url.to_string() + metrics_path;
+        if is_url_live(&client, &promurl, false).is_ok() {
             log::info!("{} is a Prometheus Exporter", url);
             return Ok((promurl, ScraperType::Prometheus));
         }
@@ -106,7 +249,7 @@ impl ProxyScraper {
         period: u64,
         factory: Arc<ExporterFactory>,
     ) -> Result<ProxyScraper, ProxyErr> {
-        let (url, ttype) = ProxyScraper::detect_type(target_url)?;
+        let (url, ttype) = ProxyScraper::detect_type(target_url, "/metrics")?;
         log::info!("Creating a scrapper to {} for a period of {}", url, period);
         Ok(ProxyScraper {
             target_url: url,
@@ -115,6 +258,183 @@ impl ProxyScraper {
             period,
             last_scrape: 0,
             ttype,
+            timeout: None,
+            relabel: Vec::new(),
+            health: ScrapeHealth::default(),
+            failure_budget: proxy_common::get_scrape_failure_budget(),
+            auth: None,
+            honor_timestamps: true,
+            sample_ts: HashMap::new(),
+            cursor: None,
+            incremental: None,
+        })
+    }
+
+    /// Create a scraper for a target of the configuration file
+    /// the type is only detected when not given
+    pub(crate) fn from_config(
+        config: &ScrapeTargetConfig,
+        factory: Arc<ExporterFactory>,
+    ) -> Result<ProxyScraper, ProxyErr> {
+        let metrics_path = ProxyScraper::metrics_path(config.metrics_path.as_ref());
+
+        /* Detection does not authenticate, only exporters have credentials */
+        let ttype = match (config.ttype, config.auth()?) {
+            (None, Some(_)) => Some(ScrapeTargetType::Prometheus),
+            (ttype, _) => ttype,
+        };
+
+        let (url, ttype) = match ttype {
+            None => ProxyScraper::detect_type(&config.key(), &metrics_path)?,
+            Some(ScrapeTargetType::System) => {
+                ProxyScraper::local_type("/system", Some(config)).unwrap()
+            }
+            Some(ScrapeTargetType::Cgroup) => {
+                ProxyScraper::local_type("/cgroup", Some(config)).unwrap()
+            }
+            Some(ScrapeTargetType::Processes) => {
+                ProxyScraper::local_type("/processes", Some(config)).unwrap()
+            }
+            Some(ScrapeTargetType::Sysfs) => (
+                config.key(),
+                ScraperType::Sysfs {
+                    files: Box::new(CounterFiles::new(config.root.as_ref(), &config.files)?),
+                },
+            ),
+            Some(ScrapeTargetType::File) => {
+                let jobid = config
+                    .job
+                    .clone()
+                    .ok_or(ProxyErr::new("File targets need a 'job'"))?;
+                let tail = FileTail::new(&config.target, config.format, config.prefix.clone())?;
+                (
+                    config.target.to_string(),
+                    ScraperType::File {
+                        tail: Box::new(tail),
+                        jobid,
+                    },
+                )
+            }
+            Some(ScrapeTargetType::Proxy) => {
+                let url = ProxyScraper::with_scheme(&config.target);
+                (url.clone() + "/job", ScraperType::Proxy { base_url: url })
+            }
+            Some(ScrapeTargetType::Prometheus) => {
+                let url = ProxyScraper::with_scheme(&config.target);
+                /* Only add the metrics path when given host:port */
+                if ProxyScraper::has_path(&url) {
+                    (url, ScraperType::Prometheus)
+                } else {
+                    (url + metrics_path.as_str(), ScraperType::Prometheus)
+                }
+            }
+        };
+
+        log::info!("Creating a configured scrapper to {}", url);
+
+        let mut ret = ProxyScraper {
+            target_url: url,
+            state: HashMap::new(),
+            factory: Some(factory),
+            period: proxy_common::get_proxy_period(),
+            last_scrape: 0,
+            ttype,
+            timeout: None,
+            relabel: Vec::new(),
+            health: ScrapeHealth::default(),
+            failure_budget: proxy_common::get_scrape_failure_budget(),
+            auth: None,
+            honor_timestamps: true,
+            sample_ts: HashMap::new(),
+            cursor: None,
+            incremental: None,
+        };
+
+        ret.configure(config)?;
+
+        Ok(ret)
+    }
+
+    /// Add fixed labels to all the values of the target
+    pub(crate) fn set_labels(&mut self, labels: &[(String, String)]) -> Result<(), ProxyErr> {
+        self.relabel = labels
+            .iter()
+            .map(|(k, v)| RelabelRule::static_label(k, v))
+            .collect::<Result<Vec<RelabelRule>, ProxyErr>>()?;
+        Ok(())
+    }
+
+    /// Update the scrape parameters from the configuration
+    pub(crate) fn configure(&mut self, config: &ScrapeTargetConfig) -> Result<(), ProxyErr> {
+        self.relabel = config.relabel_rules()?;
+
+        self.period = if config.period > 0.0 {
+            (config.period * 1000.0) as u64
+        } else {
+            proxy_common::get_proxy_period()
+        };
+
+        self.timeout = if config.timeout > 0.0 {
+            Some(Duration::from_secs_f64(config.timeout))
+        } else {
+            None
+        };
+
+        self.failure_budget = config
+            .failure_budget
+            .unwrap_or_else(proxy_common::get_scrape_failure_budget);
+
+        self.auth = config.auth()?;
+        self.honor_timestamps = config.honor_timestamps;
+
+        Ok(())
+    }
+
+    /// Release the jobs learned from a child proxy leaving the tree
+    pub(crate) fn forget(&mut self) {
+        let factory = match &self.factory {
+            Some(factory) => factory.clone(),
+            None => return,
+        };
+
+        self.cursor = None;
+
+        for (_, p) in self.state.drain() {
+            if let Err(e) = factory.relax_job(&p.desc) {
+                log::debug!(
+                    "Failed to relax {} from {} : {}",
+                    p.desc.jobid,
+                    self.target_url,
+                    e
+                );
+            }
+        }
+    }
+
+    fn deadline(&self) -> Duration {
+        match self.ttype {
+            ScraperType::Proxy { .. } | ScraperType::Prometheus => {
+                self.timeout.unwrap_or(DEFAULT_SCRAPE_TIMEOUT)
+            }
+            /* Local scrapes may legitimately take up to their period */
+            _ => self
+                .timeout
+                .unwrap_or(DEFAULT_SCRAPE_TIMEOUT.max(Duration::from_millis(self.period))),
+        }
+    }
+
+    /// GET request to the target with its deadline and credentials,
+    /// other proxies get the credentials of this one
+    fn get(&self, url: &str) -> Result<RequestBuilder, reqwest::Error> {
+        let request = Auth::client(self.deadline())?.get(url);
+
+        Ok(match (&self.auth, &self.ttype) {
+            (Some(ScrapeAuth::Basic { username, password }), _) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            (Some(ScrapeAuth::Bearer(token)), _) => request.bearer_auth(token),
+            (None, ScraperType::Proxy { .. }) => Auth::sign(request, &Method::GET, url, &[]),
+            (None, _) => request,
         })
     }
 
@@ -129,6 +449,15 @@ impl ProxyScraper {
             period: proxy_common::get_proxy_period(),
             last_scrape: 0,
             ttype: ScraperType::Trace { exporter, trace },
+            timeout: None,
+            relabel: Vec::new(),
+            health: ScrapeHealth::default(),
+            failure_budget: 1,
+            auth: None,
+            honor_timestamps: true,
+            sample_ts: HashMap::new(),
+            cursor: None,
+            incremental: None,
         })
     }
 
@@ -146,6 +475,15 @@ impl ProxyScraper {
                 traces,
                 jobid: jobid.to_string(),
             },
+            timeout: None,
+            relabel: Vec::new(),
+            health: ScrapeHealth::default(),
+            failure_budget: 1,
+            auth: None,
+            honor_timestamps: true,
+            sample_ts: HashMap::new(),
+            cursor: None,
+            incremental: None,
         })
     }
 
@@ -156,6 +494,10 @@ impl ProxyScraper {
             ttype: self.ttype.to_string(),
             period: self.period,
             last_scrape: self.last_scrape / 1000,
+            next_scrape: 0,
+            healthy: self.health.consecutive_failures == 0,
+            failure_budget: self.failure_budget,
+            health: self.health.clone(),
         }
     }
 
@@ -163,68 +505,175 @@ impl ProxyScraper {
         &self.target_url
     }
 
-    fn scrape_proxy(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut deleted: Vec<JobDesc> = Vec::new();
+    /// Base URL of the target when it is a child proxy
+    pub(crate) fn proxy_url(&self) -> Option<&String> {
+        match &self.ttype {
+            ScraperType::Proxy { base_url } => Some(base_url),
+            _ => None,
+        }
+    }
+
+    fn scrape_proxy_alarms(&mut self, base_url: &String) -> Result<(), Box<dyn Error>> {
+        let response = self.get(&format!("{}/alarms/list", base_url))?.send()?;
 
-        let client = Client::new();
-        let response = client.get(&self.target_url).send()?;
+        if !response.status().is_success() {
+            return Err(ProxyErr::newboxed("Failed to make alarm listing request"));
+        }
 
-        // Check if the response was successful (status code 200 OK)
-        if response.status().is_success() {
-            // Deserialize the JSON response into your data structure
-            let mut profiles: Vec<JobProfile> = response.json()?;
-            let new_keys: HashSet<String> = profiles.iter().map(|v| v.desc.jobid.clone()).collect();
-
-            /* First detect if a job has left */
-            for (k, v) in self.state.iter() {
-                if !new_keys.contains(k) {
-                    /* Key has been dropped save name in list for notify */
-                    deleted.push(v.desc.clone());
-                    if let Some(factory) = &self.factory {
-                        factory.relax_job(&v.desc)?;
-                    } else {
-                        unreachable!("Proxy scrapes should have a factory");
-                    }
-                }
-            }
+        let alarms: HashMap<String, Vec<ValueAlarmTrigger>> = response.json()?;
+
+        if let Some(factory) = &self.factory {
+            factory.update_remote_alarms(&self.target_url, alarms);
+        } else {
+            unreachable!("Proxy scrapes should have a factory");
+        }
 
-            /* Remove all deleted from the shadow state */
-            for k in deleted.iter() {
-                self.state.remove(&k.jobid);
+        Ok(())
+    }
+
+    /// Merge the values of the jobs of a child proxy, when `complete` the
+    /// jobs missing from `profiles` have ended
+    fn update_jobs(
+        &mut self,
+        profiles: Vec<JobProfile>,
+        complete: bool,
+        mut left: Vec<JobDesc>,
+    ) -> Result<(), Box<dyn Error>> {
+        let factory = if let Some(factory) = &self.factory {
+            factory.clone()
+        } else {
+            unreachable!("Proxy scrapes should have a factory");
+        };
+
+        /* First detect if a job has left */
+        if complete {
+            let new_keys: HashSet<&String> = profiles.iter().map(|v| &v.desc.jobid).collect();
+            left.extend(
+                self.state
+                    .values()
+                    .filter(|v| !new_keys.contains(&v.desc.jobid))
+                    .map(|v| v.desc.clone()),
+            );
+        }
+
+        for desc in left.iter() {
+            if self.state.remove(&desc.jobid).is_some() {
+                factory.relax_job(desc)?;
             }
+        }
 
-            /* Now Update Values */
-            let factory = if let Some(factory) = &self.factory {
-                factory
-            } else {
-                unreachable!("Proxy scrapes should have a factory");
-            };
+        /* Now Update Values */
+        for p in profiles {
+            log::trace!("Scraping {} from {}", p.desc.jobid, self.target_url);
 
-            for p in profiles.iter_mut() {
-                log::trace!("Scraping {} from {}", p.desc.jobid, self.target_url);
-                let cur: JobProfile;
-                if let Some(previous) = self.state.get_mut(&p.desc.jobid) {
-                    /* We clone previous snapshot before substracting */
-                    cur = p.clone();
-                    p.substract(previous)?;
-                } else {
+            let delta = match self.state.get_mut(&p.desc.jobid) {
+                Some(previous) => {
+                    let mut delta = JobProfile {
+                        desc: p.desc.clone(),
+                        counters: Vec::new(),
+                    };
+                    /* Keep the current values for next call state */
+                    for cnt in p.counters {
+                        match previous.counters.iter_mut().find(|c| c.name == cnt.name) {
+                            Some(prev) => {
+                                let mut d = cnt.clone();
+                                d.delta(prev)?;
+                                *prev = cnt;
+                                delta.counters.push(d);
+                            }
+                            None => {
+                                previous.counters.push(cnt.clone());
+                                delta.counters.push(cnt);
+                            }
+                        }
+                    }
+                    previous.desc = p.desc;
+                    delta
+                }
+                None => {
                     /* New Job Register in Job List */
                     let _ = factory.resolve_job(&p.desc, false);
-                    cur = p.to_owned();
+                    self.state.insert(p.desc.jobid.to_string(), p.clone());
+                    p
                 }
+            };
 
-                if let Some(exporter) = factory.resolve_by_id(&p.desc.jobid) {
-                    for cnt in p.counters.iter() {
-                        exporter.push(cnt)?;
-                        exporter.accumulate(cnt, true)?;
-                    }
-                } else {
-                    return Err(ProxyErr::newboxed("No such JobID"));
+            factory.merge_profile(&delta, &self.relabel)?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes since the previous scrape, None if the child does not provide them
+    /// Changes of the jobs of a child proxy, None when it does not provide
+    /// /job/delta: it replies 404 or, for older proxies, rejects the route
+    fn scrape_proxy_changes(
+        &mut self,
+        base_url: &String,
+    ) -> Result<Option<JobChanges>, Box<dyn Error>> {
+        let url = match self.cursor {
+            Some((epoch, seq)) => format!("{}/job/delta?since={}&epoch={}", base_url, seq, epoch),
+            None => format!("{}/job/delta", base_url),
+        };
+
+        let response = self.get(&url)?.send()?;
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(Some(response.json::<JobChanges>()?));
+        }
+
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        /* Older proxies reply to unknown routes with their path as operation */
+        if status == StatusCode::BAD_REQUEST {
+            if let Ok(reply) = response.json::<ApiResponse>() {
+                if reply.operation == "/job/delta" {
+                    return Ok(None);
                 }
+                return Err(ProxyErr::newboxed(format!(
+                    "Failed to get the changes of {} : {}",
+                    base_url, reply.operation
+                )));
+            }
+        }
+
+        Err(ProxyErr::newboxed(format!(
+            "Failed to get the changes of {} : {}",
+            base_url, status
+        )))
+    }
 
-                /* Now insert the non-substracted for next call state */
-                self.state.insert(p.desc.jobid.to_string(), cur);
+    fn scrape_proxy(&mut self, base_url: &String) -> Result<(), Box<dyn Error>> {
+        if self.incremental != Some(false) {
+            match self.scrape_proxy_changes(base_url)? {
+                Some(changes) => {
+                    self.incremental = Some(true);
+                    self.cursor = None;
+                    self.update_jobs(changes.jobs, changes.full, changes.left)?;
+                    /* Only move forward once the changes are applied */
+                    self.cursor = Some((changes.epoch, changes.seq));
+                    return Ok(());
+                }
+                None => {
+                    log::info!(
+                        "{} does not provide /job/delta, scraping all its jobs",
+                        base_url
+                    );
+                    self.incremental = Some(false);
+                }
             }
+        }
+
+        let response = self.get(&self.target_url)?.send()?;
+
+        // Check if the response was successful (status code 200 OK)
+        if response.status().is_success() {
+            // Deserialize the JSON response into your data structure
+            let profiles: Vec<JobProfile> = response.json()?;
+            self.update_jobs(profiles, true, Vec::new())?;
         } else {
             return Err(ProxyErr::newboxed("Failed to make scraping request"));
         }
@@ -232,30 +681,182 @@ impl ProxyScraper {
         Ok(())
     }
 
-    fn prometheus_sample_name(s: &prometheus_parse::Sample) -> String {
-        let mut name = s.metric.to_string();
+    fn prometheus_float(v: f64) -> String {
+        if v.is_infinite() {
+            if v > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+        } else {
+            v.to_string()
+        }
+    }
+
+    /// Map a Prometheus sample on proxy counters, histograms are exported
+    /// as their cumulative _bucket counters and summaries as quantile gauges
+    fn prometheus_counters(
+        s: &prometheus_parse::Sample,
+        doc: &str,
+        ts: u64,
+        cumulative: &HashSet<String>,
+    ) -> Vec<CounterSnapshot> {
+        let mut labels: Vec<(String, String)> = s
+            .labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        labels.sort();
+
+        let with = |name: &str, v: f64| {
+            let mut ret = labels.clone();
+            ret.push((name.to_string(), ProxyScraper::prometheus_float(v)));
+            ret
+        };
+
+        let counter = |value: f64| CounterType::Counter { ts, value };
+        let gauge = |value: f64| CounterType::Gauge {
+            min: value,
+            max: value,
+            hits: 1.0,
+            total: value,
+        };
+
+        match &s.value {
+            prometheus_parse::Value::Counter(v) => vec![CounterSnapshot::new(
+                s.metric.to_string(),
+                &labels,
+                doc.to_string(),
+                counter(*v),
+            )],
+            prometheus_parse::Value::Gauge(v) => vec![CounterSnapshot::new(
+                s.metric.to_string(),
+                &labels,
+                doc.to_string(),
+                gauge(*v),
+            )],
+            /* The _sum and _count of histograms and summaries come untyped */
+            prometheus_parse::Value::Untyped(v) => {
+                let value = if cumulative.contains(&s.metric) || s.metric.ends_with("_total") {
+                    counter(*v)
+                } else {
+                    gauge(*v)
+                };
+                vec![CounterSnapshot::new(
+                    s.metric.to_string(),
+                    &labels,
+                    doc.to_string(),
+                    value,
+                )]
+            }
+            prometheus_parse::Value::Histogram(buckets) => buckets
+                .iter()
+                .map(|b| {
+                    CounterSnapshot::new(
+                        format!("{}_bucket", s.metric),
+                        &with("le", b.less_than),
+                        doc.to_string(),
+                        counter(b.count),
+                    )
+                })
+                .collect(),
+            prometheus_parse::Value::Summary(quantiles) => quantiles
+                .iter()
+                .map(|q| {
+                    CounterSnapshot::new(
+                        s.metric.to_string(),
+                        &with("quantile", q.quantile),
+                        doc.to_string(),
+                        gauge(q.count),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Parse a Prometheus text exposition as proxy counters along
+    /// the timestamp in us of each series
+    pub(crate) fn parse_prometheus(
+        data: &str,
+        honor_timestamps: bool,
+    ) -> Result<Vec<(u64, CounterSnapshot)>, Box<dyn Error>> {
+        let lines: Vec<_> = data.lines().map(|s| Ok(s.to_string())).collect();
+        let metrics = prometheus_parse::Scrape::parse(lines.into_iter())?;
+
+        let cumulative: HashSet<String> = metrics
+            .samples
+            .iter()
+            .filter(|s| {
+                matches!(
+                    s.value,
+                    prometheus_parse::Value::Histogram(_) | prometheus_parse::Value::Summary(_)
+                )
+            })
+            .flat_map(|s| [format!("{}_sum", s.metric), format!("{}_count", s.metric)])
+            .collect();
 
-        if !s.labels.is_empty() {
-            name = format!("{}{{{}\"}}", name, s.labels);
+        let now = unix_ts_us();
+        let mut ret: Vec<(u64, CounterSnapshot)> = Vec::new();
+
+        for v in metrics.samples.iter() {
+            let base = v.metric.trim_end_matches("_sum").trim_end_matches("_count");
+
+            let doc: String = metrics
+                .docs
+                .get(&v.metric)
+                .or_else(|| metrics.docs.get(base))
+                .cloned()
+                .unwrap_or_default();
+
+            /* Samples without timestamp are stamped with the scrape time */
+            let ts = if honor_timestamps {
+                v.timestamp.timestamp_micros().max(0) as u64
+            } else {
+                now
+            };
+
+            for m in ProxyScraper::prometheus_counters(v, &doc, ts, &cumulative) {
+                ret.push((ts, m));
+            }
         }
 
-        name
+        Ok(ret)
     }
 
     fn scrape_prometheus(&mut self) -> Result<(), Box<dyn Error>> {
-        let client = Client::new();
-        let response = client.get(&self.target_url).send()?;
-        let data = response.text()?;
+        let response = self.get(&self.target_url)?.send()?;
 
-        let lines: Vec<_> = data.lines().map(|s| Ok(s.to_string())).collect();
-        let metrics = prometheus_parse::Scrape::parse(lines.into_iter())?;
+        if !response.status().is_success() {
+            return Err(ProxyErr::newboxed(format!(
+                "Failed to scrape {} got response {}",
+                self.target_url,
+                response.status()
+            )));
+        }
+
+        let data = response.text()?;
 
         let factory = if let Some(factory) = &self.factory {
-            factory
+            factory.clone()
         } else {
             unreachable!("Proxy scrapes should have a factory");
         };
 
+        let mut sample_ts: HashMap<String, u64> = HashMap::new();
+        let mut entries: Vec<CounterSnapshot> = Vec::new();
+
+        for (ts, m) in ProxyScraper::parse_prometheus(&data, self.honor_timestamps)? {
+            let last = self.sample_ts.get(&m.name).copied().unwrap_or(0);
+            sample_ts.insert(m.name.to_string(), ts.max(last));
+
+            /* Skip the series the target did not update since last scrape */
+            if ts <= last {
+                continue;
+            }
+
+            if let Some(m) = RelabelRule::relabel(&self.relabel, &m) {
+                entries.push(m);
+            }
+        }
+
+        self.sample_ts = sample_ts;
+
         // We push in MAIN, NODE and All exporters which may generate profiles
         // THese exporters are the one attached locally and thus bound to
         // node local performance
@@ -264,40 +865,10 @@ impl ProxyScraper {
         if let Ok(mut locals) = factory.get_local_job_exporters() {
             target_exporters.append(&mut locals);
 
-            for v in metrics.samples {
-                let doc: String = metrics
-                    .docs
-                    .get(&v.metric)
-                    .unwrap_or(&"".to_string())
-                    .clone();
-
-                let entry: Option<CounterSnapshot> = match v.value {
-                    prometheus_parse::Value::Counter(value) => Some(CounterSnapshot {
-                        name: ProxyScraper::prometheus_sample_name(&v),
-                        ctype: CounterType::Counter {
-                            ts: proxy_common::unix_ts_us(),
-                            value,
-                        },
-                        doc,
-                    }),
-                    prometheus_parse::Value::Gauge(value) => Some(CounterSnapshot {
-                        name: ProxyScraper::prometheus_sample_name(&v),
-                        ctype: CounterType::Gauge {
-                            min: 0.0,
-                            max: 0.0,
-                            hits: 1.0,
-                            total: value,
-                        },
-                        doc,
-                    }),
-                    _ => None,
-                };
-
+            for m in entries.iter() {
                 for e in target_exporters.iter() {
-                    if let Some(m) = entry.clone() {
-                        e.push(&m)?;
-                        e.accumulate(&m, false)?;
-                    }
+                    e.push(m)?;
+                    e.accumulate(m, false)?;
                 }
             }
         }
@@ -319,8 +890,22 @@ impl ProxyScraper {
             unreachable!("Proxy scrapes should have a factory");
         };
 
-        let metrics = sys.scrape()?;
+        let metrics: Vec<CounterSnapshot> = sys
+            .scrape()?
+            .iter()
+            .filter_map(|m| RelabelRule::relabel(&self.relabel, m))
+            .collect();
+
+        ProxyScraper::push_node_metrics(factory, &metrics, false)
+    }
 
+    /// Push metrics of the node in the main, node and local job exporters,
+    /// running totals replace the previous values instead of being added
+    fn push_node_metrics(
+        factory: &ExporterFactory,
+        metrics: &[CounterSnapshot],
+        totals: bool,
+    ) -> Result<(), Box<dyn Error>> {
         // We push in MAIN, NODE and All exporters which may generate profiles
         // THese exporters are the one attached locally and thus bound to
         // node local performance
@@ -330,6 +915,11 @@ impl ProxyScraper {
             target_exporters.append(&mut locals);
 
             for e in target_exporters {
+                if totals {
+                    ProxyScraper::set_totals(&e, metrics)?;
+                    continue;
+                }
+
                 for m in metrics.iter() {
                     e.push(m)?;
                     e.accumulate(m, false)?;
@@ -340,6 +930,144 @@ impl ProxyScraper {
         Ok(())
     }
 
+    /// Store running totals read from the system, they replace the
+    /// previous values of the exporter instead of being added to them
+    fn set_totals(exporter: &Exporter, metrics: &[CounterSnapshot]) -> Result<(), ProxyErr> {
+        for m in metrics.iter() {
+            exporter.push(m)?;
+            exporter.set(m.clone())?;
+        }
+
+        Ok(())
+    }
+
+    fn scrape_sysfs(&mut self) -> Result<(), Box<dyn Error>> {
+        let files = match &mut self.ttype {
+            ScraperType::Sysfs { files } => files,
+
+            _ => {
+                unreachable!();
+            }
+        };
+
+        let factory = if let Some(factory) = &self.factory {
+            factory
+        } else {
+            unreachable!("Sysfs scrapes should have a factory");
+        };
+
+        let metrics: Vec<CounterSnapshot> = files
+            .scrape()
+            .iter()
+            .filter_map(|m| RelabelRule::relabel(&self.relabel, m))
+            .collect();
+
+        ProxyScraper::push_node_metrics(factory, &metrics, true)
+    }
+
+    fn scrape_processes(&mut self) -> Result<(), Box<dyn Error>> {
+        let procs = match &mut self.ttype {
+            ScraperType::Processes { procs } => procs,
+            _ => {
+                unreachable!();
+            }
+        };
+
+        let factory = if let Some(factory) = &self.factory {
+            factory
+        } else {
+            unreachable!("Process scrapes should have a factory");
+        };
+
+        let jobs = factory.get_local_job_pids();
+
+        let pids: Vec<(String, Vec<u32>)> = jobs
+            .iter()
+            .map(|(jobid, _, pids)| (jobid.to_string(), pids.clone()))
+            .collect();
+
+        let mut counters = procs.scrape(&pids);
+
+        /* Unlike node metrics these are only pushed in their own job */
+        for (jobid, exporter, _) in jobs {
+            let metrics: Vec<CounterSnapshot> = counters
+                .remove(&jobid)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|m| RelabelRule::relabel(&self.relabel, m))
+                .collect();
+
+            ProxyScraper::set_totals(&exporter, &metrics)?;
+        }
+
+        Ok(())
+    }
+
+    fn scrape_cgroups(&mut self) -> Result<(), Box<dyn Error>> {
+        let cgroups = match &self.ttype {
+            ScraperType::Cgroup { cgroups } => cgroups,
+            _ => {
+                unreachable!();
+            }
+        };
+
+        let factory = if let Some(factory) = &self.factory {
+            factory
+        } else {
+            unreachable!("Cgroup scrapes should have a factory");
+        };
+
+        for (jobid, exporter) in factory.get_local_jobs() {
+            let counters = match cgroups.scrape(&jobid) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let metrics: Vec<CounterSnapshot> = counters
+                .iter()
+                .filter_map(|m| RelabelRule::relabel(&self.relabel, m))
+                .collect();
+
+            ProxyScraper::set_totals(&exporter, &metrics)?;
+        }
+
+        Ok(())
+    }
+
+    fn scrape_file(&mut self) -> Result<(), Box<dyn Error>> {
+        let (tail, jobid) = match &mut self.ttype {
+            ScraperType::File { tail, jobid } => (tail, jobid),
+            _ => {
+                unreachable!();
+            }
+        };
+
+        let factory = if let Some(factory) = &self.factory {
+            factory
+        } else {
+            unreachable!("File scrapes should have a factory");
+        };
+
+        /* Keep the content for later if the job did not start yet */
+        let exporter = match factory.resolve_by_id(jobid) {
+            Some(e) => e,
+            None => {
+                log::debug!("Job {} of {} is not running yet", jobid, self.target_url);
+                return Ok(());
+            }
+        };
+
+        let metrics: Vec<CounterSnapshot> = tail
+            .scrape()?
+            .iter()
+            .filter_map(|m| RelabelRule::relabel(&self.relabel, m))
+            .collect();
+
+        ProxyScraper::set_totals(&exporter, &metrics)?;
+
+        Ok(())
+    }
+
     fn scrape_trace(
         &mut self,
         exporter: Arc<Exporter>,
@@ -364,16 +1092,16 @@ impl ProxyScraper {
     }
 
     pub(crate) fn scrape(&mut self) -> Result<(), Box<dyn Error>> {
-        if unix_ts() - self.last_scrape < self.period {
-            /* Not to be scraped yet */
-            return Ok(());
-        }
-
         log::debug!("Scraping {}", self.target_url);
 
         match &self.ttype {
-            ScraperType::Proxy => {
-                self.scrape_proxy()?;
+            ScraperType::Proxy { base_url } => {
+                let base_url = base_url.clone();
+                self.scrape_proxy(&base_url)?;
+                /* Children alarms are only informative, do not drop the child */
+                if let Err(e) = self.scrape_proxy_alarms(&base_url) {
+                    log::debug!("Failed to scrape alarms of {} : {}", base_url, e);
+                }
             }
             ScraperType::Prometheus => {
                 self.scrape_prometheus()?;
@@ -381,6 +1109,18 @@ impl ProxyScraper {
             ScraperType::SystemMetrics { .. } => {
                 self.scrape_system_metrics()?;
             }
+            ScraperType::Processes { .. } => {
+                self.scrape_processes()?;
+            }
+            ScraperType::Cgroup { .. } => {
+                self.scrape_cgroups()?;
+            }
+            ScraperType::Sysfs { .. } => {
+                self.scrape_sysfs()?;
+            }
+            ScraperType::File { .. } => {
+                self.scrape_file()?;
+            }
             ScraperType::Trace { exporter, trace } => {
                 self.scrape_trace(exporter.clone(), trace.clone())?;
             }
@@ -393,4 +1133,588 @@ impl ProxyScraper {
 
         Ok(())
     }
+
+    /// Scrape the target and record the outcome in its health
+    pub(crate) fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let ret = self.scrape();
+
+        self.health.duration = start.elapsed().as_secs_f64();
+        self.health.scrapes += 1;
+
+        match &ret {
+            Ok(_) => {
+                self.health.last_error = None;
+                self.health.consecutive_failures = 0;
+                self.health.last_success = unix_ts() / 1000;
+            }
+            Err(e) => {
+                self.health.last_error = Some(e.to_string());
+                self.health.consecutive_failures += 1;
+                self.health.failures += 1;
+            }
+        }
+
+        if let Err(e) = self.publish_health() {
+            log::debug!("Failed to publish health of {} : {}", self.target_url, e);
+        }
+
+        ret
+    }
+
+    /// Expose the health of external targets in the node exporter
+    fn publish_health(&self) -> Result<(), ProxyErr> {
+        let factory = match (&self.factory, &self.ttype) {
+            (Some(factory), ScraperType::Proxy { .. })
+            | (Some(factory), ScraperType::Prometheus)
+            | (Some(factory), ScraperType::SystemMetrics { .. })
+            | (Some(factory), ScraperType::Sysfs { .. })
+            | (Some(factory), ScraperType::File { .. }) => factory,
+            _ => return Ok(()),
+        };
+
+        let labels = [("target".to_string(), self.target_url.to_string())];
+
+        let up = if self.health.consecutive_failures == 0 {
+            1.0
+        } else {
+            0.0
+        };
+
+        let gauges = [
+            (
+                "proxy_scrape_up",
+                "1 if the last scrape of the target succeeded 0 otherwise",
+                up,
+            ),
+            (
+                "proxy_scrape_duration_seconds",
+                "Duration of the last scrape of the target",
+                self.health.duration,
+            ),
+            (
+                "proxy_scrape_consecutive_failures",
+                "Number of scrapes of the target which failed in a row",
+                self.health.consecutive_failures as f64,
+            ),
+        ];
+
+        let node = factory.get_node();
+
+        for (name, doc, value) in gauges {
+            let snap = CounterSnapshot::new(
+                name.to_string(),
+                &labels,
+                doc.to_string(),
+                CounterType::Gauge {
+                    min: value,
+                    max: value,
+                    hits: 1.0,
+                    total: value,
+                },
+            );
+            node.push(&snap)?;
+            node.accumulate(&snap, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+/*******************
+ * SCRAPING ENGINE *
+ *******************/
+
+/// A scraper shared between the scheduler which dispatches it when
+/// due and the pool of workers running the scrapes concurrently
+pub(crate) struct ScrapeTask {
+    key: String,
+    proxy_url: Option<String>,
+    scraper: Mutex<ProxyScraper>,
+    /// Snapshot refreshed after each scrape so that listing
+    /// scrapes does not wait for running ones
+    snapshot: RwLock<ProxyScraperSnapshot>,
+    running: AtomicBool,
+    overrun_reported: AtomicBool,
+    /// Timestamp in ms of the start of the running scrape
+    started: AtomicU64,
+    /// Timestamp in ms of the next scrape
+    next_scrape: AtomicU64,
+    /// Deadline of the scrape in ms
+    deadline: AtomicU64,
+}
+
+impl ScrapeTask {
+    pub(crate) fn new(scraper: ProxyScraper) -> ScrapeTask {
+        /* Spread targets over their period with a random per target offset */
+        let offset = RandomState::new().hash_one(scraper.url()) % scraper.period.max(1);
+
+        let ret = ScrapeTask {
+            key: scraper.url().to_string(),
+            proxy_url: scraper.proxy_url().cloned(),
+            snapshot: RwLock::new(scraper.snapshot()),
+            running: AtomicBool::new(false),
+            overrun_reported: AtomicBool::new(false),
+            started: AtomicU64::new(0),
+            next_scrape: AtomicU64::new(unix_ts() + offset),
+            deadline: AtomicU64::new(scraper.deadline().as_millis() as u64),
+            scraper: Mutex::new(scraper),
+        };
+
+        ret.refresh(&ret.scraper.lock().unwrap());
+
+        ret
+    }
+
+    fn refresh(&self, scraper: &ProxyScraper) {
+        let mut snap = scraper.snapshot();
+        snap.next_scrape = self.next_scrape.load(Ordering::SeqCst) / 1000;
+        *self.snapshot.write().unwrap() = snap;
+    }
+
+    pub(crate) fn url(&self) -> &String {
+        &self.key
+    }
+
+    /// Base URL of the target when it is a child proxy
+    pub(crate) fn proxy_url(&self) -> Option<&String> {
+        self.proxy_url.as_ref()
+    }
+
+    pub(crate) fn snapshot(&self) -> ProxyScraperSnapshot {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Update the configuration of the scraper (waits for a running scrape)
+    pub(crate) fn configure(&self, config: &ScrapeTargetConfig) -> Result<(), ProxyErr> {
+        let mut scraper = self.scraper.lock().unwrap();
+        scraper.configure(config)?;
+        self.deadline
+            .store(scraper.deadline().as_millis() as u64, Ordering::SeqCst);
+        self.refresh(&scraper);
+        Ok(())
+    }
+
+    /// Mark the task as running if it is due, the caller must then run it
+    pub(crate) fn claim(&self, now: u64) -> bool {
+        if now < self.next_scrape.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        if self
+            .running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return false;
+        }
+
+        self.started.store(now, Ordering::SeqCst);
+        self.overrun_reported.store(false, Ordering::SeqCst);
+        true
+    }
+
+    /// Time in ms a running scrape is over its deadline, only reported once
+    pub(crate) fn overrun(&self, now: u64) -> Option<u64> {
+        if !self.running.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let late = now
+            .saturating_sub(self.started.load(Ordering::SeqCst))
+            .saturating_sub(self.deadline.load(Ordering::SeqCst));
+
+        if late == 0 || self.overrun_reported.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        Some(late)
+    }
+
+    /// Scrape the target in a thread of its own, the worker stops waiting
+    /// at the deadline so that a hung target (eg. a stuck filesystem) only
+    /// holds that thread, the task is not run again until it returns
+    pub(crate) fn run(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = channel();
+        let task = self.clone();
+
+        thread::spawn(move || {
+            let _ = tx.send(task.scrape().map_err(|e| e.to_string()));
+        });
+
+        let deadline = Duration::from_millis(self.deadline.load(Ordering::SeqCst));
+
+        match rx.recv_timeout(deadline) {
+            Ok(ret) => Ok(ret.map_err(ProxyErr::new)?),
+
+            Err(_) => {
+                let err = format!("Scrape is over its deadline of {} ms", deadline.as_millis());
+                self.snapshot.write().unwrap().health.last_error = Some(err.to_string());
+                Err(ProxyErr::newboxed(err))
+            }
+        }
+    }
+
+    /// Scrape the target and schedule the next scrape
+    fn scrape(&self) -> Result<(), Box<dyn Error>> {
+        let mut scraper = self.scraper.lock().unwrap();
+
+        let ret = scraper.run();
+
+        let period = scraper.period.max(1);
+        let now = unix_ts();
+        let failures = scraper.health.consecutive_failures;
+
+        let next = if failures > 0 {
+            /* Exponential backoff on failing targets */
+            let backoff = period.saturating_mul(1 << (failures - 1).min(16));
+            now + backoff.min(MAX_SCRAPE_BACKOFF.max(period))
+        } else {
+            /* Stay in phase skipping the periods we overran */
+            let mut next = self.next_scrape.load(Ordering::SeqCst) + period;
+            if next <= now {
+                next += (now - next) / period * period + period;
+            }
+            next
+        };
+
+        self.next_scrape.store(next, Ordering::SeqCst);
+
+        self.refresh(&scraper);
+        self.running.store(false, Ordering::SeqCst);
+
+        ret
+    }
+
+    /// True when the target failed more times in a row than its budget
+    pub(crate) fn exhausted(&self) -> bool {
+        let snap = self.snapshot.read().unwrap();
+        snap.failure_budget > 0 && snap.health.consecutive_failures >= snap.failure_budget
+    }
+
+    /// Release the jobs learned from the target (waits for a running scrape)
+    pub(crate) fn forget(&self) {
+        self.scraper.lock().unwrap().forget();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CounterFileConfig;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("proxy-scrapper-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn value(exporter: &Exporter, name: &str) -> f64 {
+        exporter
+            .get(&name.to_string())
+            .unwrap()
+            .read()
+            .unwrap()
+            .float_value()
+    }
+
+    #[test]
+    fn exported_counters_scrape_back() {
+        let counter = CounterSnapshot::new(
+            "proxy_io".to_string(),
+            &[],
+            "".to_string(),
+            CounterType::Counter {
+                ts: unix_ts_us(),
+                value: 42.0,
+            },
+        );
+
+        let data = format!("# TYPE proxy_io counter\n{}", counter.serialize());
+        let parsed = ProxyScraper::parse_prometheus(&data, true).unwrap();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].1.name, "proxy_io");
+        assert_eq!(parsed[0].1.float_value(), 42.0);
+    }
+
+    #[test]
+    fn process_totals_are_not_added_again() {
+        let root = fake_root("proc");
+        let dir = root.join("4242");
+        fs::create_dir_all(&dir).unwrap();
+
+        /* Fields 3 to 22 of stat(5) with utime, stime, threads and starttime */
+        let mut fields = vec!["0"; 20];
+        fields[0] = "S";
+        fields[1] = "1";
+        fields[11] = "300";
+        fields[12] = "100";
+        fields[17] = "2";
+        fields[19] = "1000";
+        fs::write(
+            dir.join("stat"),
+            format!("4242 (solver app) {}\n", fields.join(" ")),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("status"),
+            "VmHWM:\t2048 kB\nVmRSS:\t1024 kB\nvoluntary_ctxt_switches:\t10\nnonvoluntary_ctxt_switches:\t5\n",
+        )
+        .unwrap();
+        fs::write(dir.join("io"), "read_bytes: 4096\nwrite_bytes: 8192\n").unwrap();
+
+        let mut procs = ProcMetrics::new(Some(&root));
+        let exporter = Exporter::new();
+        let jobs = vec![("1234".to_string(), vec![4242])];
+
+        let mut cpu = None;
+
+        for _ in 0..2 {
+            let counters = procs.scrape(&jobs).remove("1234").unwrap();
+            ProxyScraper::set_totals(&exporter, &counters).unwrap();
+
+            assert_eq!(value(&exporter, "proxy_job_io_read_bytes_total"), 4096.0);
+            assert_eq!(value(&exporter, "proxy_job_io_write_bytes_total"), 8192.0);
+            assert_eq!(
+                value(
+                    &exporter,
+                    "proxy_job_context_switches_total{kind=\"voluntary\"}"
+                ),
+                10.0
+            );
+            assert_eq!(
+                value(&exporter, "proxy_job_memory_rss_bytes"),
+                1024.0 * 1024.0
+            );
+
+            let user = value(&exporter, "proxy_job_cpu_seconds_total{mode=\"user\"}");
+            assert!(user > 0.0);
+            assert_eq!(*cpu.get_or_insert(user), user);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cgroup_totals_are_not_added_again() {
+        let root = fake_root("cgroup");
+        let dir = root.join("slurm").join("job_1234");
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            dir.join("cpu.stat"),
+            "usage_usec 3000000\nuser_usec 2000000\nsystem_usec 1000000\n",
+        )
+        .unwrap();
+        fs::write(dir.join("memory.events"), "low 0\nhigh 0\nmax 3\noom 1\n").unwrap();
+        fs::write(
+            dir.join("io.stat"),
+            "8:0 rbytes=4096 wbytes=8192 rios=2 wios=4\n",
+        )
+        .unwrap();
+        fs::write(dir.join("memory.current"), "65536\n").unwrap();
+
+        let cgroups = CgroupMetrics::new(Some(&root), Some(&"slurm/job_{job}".to_string()));
+        let exporter = Exporter::new();
+
+        for _ in 0..2 {
+            let counters = cgroups.scrape("1234").unwrap();
+            ProxyScraper::set_totals(&exporter, &counters).unwrap();
+
+            assert_eq!(
+                value(&exporter, "proxy_cgroup_cpu_seconds_total{mode=\"user\"}"),
+                2.0
+            );
+            assert_eq!(
+                value(&exporter, "proxy_cgroup_memory_events_total{event=\"max\"}"),
+                3.0
+            );
+            assert_eq!(
+                value(
+                    &exporter,
+                    "proxy_cgroup_io_write_bytes_total{device=\"8:0\"}"
+                ),
+                8192.0
+            );
+            assert_eq!(value(&exporter, "proxy_cgroup_memory_bytes"), 65536.0);
+        }
+
+        assert!(cgroups.scrape("5678").is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sysfs_counters_follow_wraps() {
+        let root = fake_root("sysfs");
+        let rapl = root.join("powercap").join("intel-rapl:0");
+        let ib = root.join("infiniband").join("mlx5_0");
+        fs::create_dir_all(&rapl).unwrap();
+        fs::create_dir_all(&ib).unwrap();
+
+        fs::write(rapl.join("max_energy_range_uj"), "1000\n").unwrap();
+
+        let config: Vec<CounterFileConfig> = serde_json::from_str(
+            r#"[
+                {"glob": "/powercap/*/energy_uj", "name": "energy_total", "range": "max_energy_range_uj"},
+                {"glob": "/infiniband/*/port_xmit_data", "name": "xmit_total"}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut files = CounterFiles::new(Some(&root), &config).unwrap();
+        let exporter = Exporter::new();
+
+        /* Energy wraps at its range, the port counter is reset */
+        for (energy, xmit, expected_energy, expected_xmit) in [
+            ("900", "50", 900.0, 50.0),
+            ("900", "50", 900.0, 50.0),
+            ("100", "20", 1100.0, 70.0),
+            ("300", "30", 1300.0, 80.0),
+        ] {
+            fs::write(rapl.join("energy_uj"), energy).unwrap();
+            fs::write(ib.join("port_xmit_data"), xmit).unwrap();
+
+            ProxyScraper::set_totals(&exporter, &files.scrape()).unwrap();
+
+            assert_eq!(value(&exporter, "energy_total"), expected_energy);
+            assert_eq!(value(&exporter, "xmit_total"), expected_xmit);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn file_totals_are_not_added_again() {
+        let root = fake_root("file");
+        let path = root.join("solver.jsonl");
+        fs::write(&path, "").unwrap();
+
+        let mut tail = FileTail::new(&path.to_string_lossy(), None, None).unwrap();
+        let exporter = Exporter::new();
+
+        let mut content = String::new();
+
+        for step in 1..4 {
+            content.push_str(&format!("{{\"steps_total\": {}}}\n", step));
+            fs::write(&path, &content).unwrap();
+
+            ProxyScraper::set_totals(&exporter, &tail.scrape().unwrap()).unwrap();
+
+            assert_eq!(
+                value(&exporter, "steps_total{file=\"solver.jsonl\"}"),
+                step as f64
+            );
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn hung_scrapes_do_not_hold_workers() {
+        let root = fake_root("hung");
+        let factory = ExporterFactory::new(root.join("prefix"), false, 1024 * 1024, None).unwrap();
+
+        let mut desc = JobDesc::new();
+        desc.jobid = "1234".to_string();
+        factory.resolve_job(&desc, false);
+
+        /* Opening a FIFO blocks until it has a writer */
+        let fifo = root.join("hung.jsonl");
+        let path = std::ffi::CString::new(fifo.to_string_lossy().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+
+        let config: ScrapeTargetConfig = serde_json::from_value(serde_json::json!({
+            "target": fifo,
+            "type": "file",
+            "job": "1234",
+            "period": 1,
+            "timeout": "200ms"
+        }))
+        .unwrap();
+
+        let task = Arc::new(ScrapeTask::new(
+            ProxyScraper::from_config(&config, factory).unwrap(),
+        ));
+
+        let now = unix_ts() + 1000;
+        assert!(task.claim(now));
+
+        let start = Instant::now();
+        assert!(task.run().is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(task.snapshot().health.last_error.is_some());
+
+        /* The hung scrape is reported and not run again until it returns */
+        assert!(task.overrun(now + 1000).is_some());
+        assert!(!task.claim(now + 1000));
+
+        /* Only unblocks the scrape which may close the FIFO before the write */
+        let _ = fs::write(&fifo, "{\"steps_total\": 1}\n");
+
+        let start = Instant::now();
+        while !task.claim(unix_ts() + 10000) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn only_missing_delta_routes_are_unsupported() {
+        let root = fake_root("delta");
+        let factory = ExporterFactory::new(root.clone(), false, 1024 * 1024, None).unwrap();
+
+        let server = rouille::Server::new("127.0.0.1:0", |req| match req.url().as_str() {
+            "/404/job/delta" => rouille::Response::empty_404(),
+            "/old/job/delta" => rouille::Response::json(&ApiResponse {
+                operation: "/job/delta".to_string(),
+                success: false,
+            })
+            .with_status_code(400),
+            "/bad/job/delta" => rouille::Response::json(&ApiResponse {
+                operation: "Failed to parse since".to_string(),
+                success: false,
+            })
+            .with_status_code(400),
+            "/json/job/delta" => rouille::Response::text("not json"),
+            _ => rouille::Response::text("").with_status_code(500),
+        })
+        .unwrap();
+        let addr = server.server_addr();
+        thread::spawn(move || server.run());
+
+        let config: ScrapeTargetConfig = serde_json::from_value(serde_json::json!({
+            "target": format!("http://{}", addr),
+            "type": "proxy"
+        }))
+        .unwrap();
+        let mut scraper = ProxyScraper::from_config(&config, factory).unwrap();
+
+        for (path, unsupported) in [
+            ("404", true),
+            ("old", true),
+            ("bad", false),
+            ("json", false),
+            ("500", false),
+        ] {
+            let base = format!("http://{}/{}", addr, path);
+            match scraper.scrape_proxy_changes(&base) {
+                Ok(changes) => {
+                    assert!(unsupported, "{}", path);
+                    assert!(changes.is_none());
+                }
+                Err(_) => assert!(!unsupported, "{}", path),
+            }
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
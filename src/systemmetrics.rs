@@ -1,23 +1,373 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
 use sysinfo::{ComponentExt, CpuExt, DiskExt, NetworkExt, System, SystemExt};
 
 use crate::{
+    config::SystemCollector,
     proxy_common::{unix_ts, unix_ts_us, ProxyErr},
     proxywireprotocol::{CounterSnapshot, CounterType},
 };
 
+/// Resources of /proc/pressure
+const PRESSURE_RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+/// Counters of /proc/vmstat which are exported
+const VMSTAT_COUNTERS: [(&str, &str, &str); 5] = [
+    (
+        "pgfault",
+        "proxy_vmstat_page_faults_total",
+        "Total number of page faults (minor and major)",
+    ),
+    (
+        "pgmajfault",
+        "proxy_vmstat_major_page_faults_total",
+        "Total number of major page faults (requiring an I/O)",
+    ),
+    (
+        "pswpin",
+        "proxy_vmstat_swap_in_pages_total",
+        "Total number of pages swapped in",
+    ),
+    (
+        "pswpout",
+        "proxy_vmstat_swap_out_pages_total",
+        "Total number of pages swapped out",
+    ),
+    (
+        "oom_kill",
+        "proxy_vmstat_oom_kills_total",
+        "Total number of processes killed by the OOM killer",
+    ),
+];
+
+/// Size of the sectors counted in /proc/diskstats
+const DISKSTATS_SECTOR_SIZE: f64 = 512.0;
+
+/// A line of /proc/diskstats
+#[derive(Clone, Default)]
+struct DiskStats {
+    reads: f64,
+    read_sectors: f64,
+    read_ms: f64,
+    writes: f64,
+    write_sectors: f64,
+    write_ms: f64,
+    in_progress: f64,
+    /// Time spent doing I/Os
+    io_ms: f64,
+    /// Time spent doing I/Os weighted by the number of pending I/Os
+    weighted_io_ms: f64,
+}
+
+impl DiskStats {
+    fn parse(line: &str) -> Option<(String, DiskStats)> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        let field = |n: usize| -> Option<f64> { fields.get(n)?.parse::<f64>().ok() };
+
+        Some((
+            fields.get(2)?.to_string(),
+            DiskStats {
+                reads: field(3)?,
+                read_sectors: field(5)?,
+                read_ms: field(6)?,
+                writes: field(7)?,
+                write_sectors: field(9)?,
+                write_ms: field(10)?,
+                in_progress: field(11)?,
+                io_ms: field(12)?,
+                weighted_io_ms: field(13)?,
+            },
+        ))
+    }
+}
+
 pub struct SystemMetrics {
     sys: System,
     last_scrape: f64,
+    /// Root of the procfs to allow reading a fake tree
+    procfs: PathBuf,
+    /// Previous /proc/diskstats sample to compute rates
+    diskstats: Option<(Instant, HashMap<String, DiskStats>)>,
+    /// Enabled metric groups, all when None
+    collectors: Option<Vec<SystemCollector>>,
 }
 
 impl SystemMetrics {
-    pub fn new() -> SystemMetrics {
+    pub fn new(
+        procfs: Option<&PathBuf>,
+        collectors: Option<&Vec<SystemCollector>>,
+    ) -> SystemMetrics {
         SystemMetrics {
             sys: System::new_all(),
             last_scrape: unix_ts() as f64 / 1000.0,
+            procfs: procfs.cloned().unwrap_or_else(|| PathBuf::from("/proc")),
+            diskstats: None,
+            collectors: collectors.cloned(),
         }
     }
 
+    fn enabled(&self, collector: SystemCollector) -> bool {
+        match &self.collectors {
+            Some(c) => c.contains(&collector),
+            None => true,
+        }
+    }
+
+    /// Parse the "some|full avg10=X avg60=X avg300=X total=X" lines of /proc/pressure
+    fn scrape_pressure(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
+        for resource in PRESSURE_RESOURCES {
+            /* Missing when the kernel has no PSI support */
+            let data = match fs::read_to_string(self.procfs.join("pressure").join(resource)) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            for l in data.lines() {
+                let mut fields = l.split_whitespace();
+
+                let kind = match fields.next() {
+                    Some(k) => k,
+                    None => continue,
+                };
+
+                let attrs: Vec<(String, String)> = vec![
+                    ("resource".to_string(), resource.to_string()),
+                    ("kind".to_string(), kind.to_string()),
+                ];
+
+                for (key, v) in fields.filter_map(|f| f.split_once('=')) {
+                    let v = match v.parse::<f64>() {
+                        Ok(v) => v,
+                        Err(_) => continue,
+                    };
+
+                    let (name, doc) = match key {
+                        "avg10" => (
+                            "proxy_pressure_avg10_percent",
+                            "Share of the last 10 seconds tasks were stalled on the resource",
+                        ),
+                        "avg60" => (
+                            "proxy_pressure_avg60_percent",
+                            "Share of the last 60 seconds tasks were stalled on the resource",
+                        ),
+                        "total" => {
+                            /* Total stall time is in us */
+                            counters.push(CounterSnapshot::new(
+                                "proxy_pressure_stalled_seconds_total".to_string(),
+                                attrs.as_slice(),
+                                "Total time tasks were stalled on the resource".to_string(),
+                                CounterType::Counter {
+                                    ts: unix_ts_us(),
+                                    value: v / 1e6,
+                                },
+                            ));
+                            continue;
+                        }
+                        _ => continue,
+                    };
+
+                    counters.push(CounterSnapshot::new(
+                        name.to_string(),
+                        attrs.as_slice(),
+                        doc.to_string(),
+                        CounterType::Gauge {
+                            min: 0.0,
+                            max: v,
+                            hits: 1.0,
+                            total: v,
+                        },
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scrape_vmstat(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
+        let vmstat: HashMap<String, f64> = fs::read_to_string(self.procfs.join("vmstat"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| {
+                let (k, v) = l.split_once(' ')?;
+                Some((k.to_string(), v.trim().parse::<f64>().ok()?))
+            })
+            .collect();
+
+        for (key, name, doc) in VMSTAT_COUNTERS {
+            if let Some(v) = vmstat.get(key) {
+                counters.push(CounterSnapshot::new(
+                    name.to_string(),
+                    &[],
+                    doc.to_string(),
+                    CounterType::Counter {
+                        ts: unix_ts_us(),
+                        value: *v,
+                    },
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_diskstats(&self) -> HashMap<String, DiskStats> {
+        fs::read_to_string(self.procfs.join("diskstats"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(DiskStats::parse)
+            /* Memory backed devices are not of interest */
+            .filter(|(name, _)| !name.starts_with("ram") && !name.starts_with("loop"))
+            .collect()
+    }
+
+    fn scrape_diskstats(&mut self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
+        let now = Instant::now();
+        let current = self.read_diskstats();
+
+        let gauge = |v: f64| CounterType::Gauge {
+            min: 0.0,
+            max: v,
+            hits: 1.0,
+            total: v,
+        };
+
+        for (device, d) in current.iter() {
+            let attrs: Vec<(String, String)> = vec![("device".to_string(), device.to_string())];
+
+            let totals = [
+                (
+                    "proxy_disk_reads_total",
+                    "Total number of reads completed on the given device",
+                    d.reads,
+                ),
+                (
+                    "proxy_disk_writes_total",
+                    "Total number of writes completed on the given device",
+                    d.writes,
+                ),
+                (
+                    "proxy_disk_read_bytes_total",
+                    "Total number of bytes read on the given device",
+                    d.read_sectors * DISKSTATS_SECTOR_SIZE,
+                ),
+                (
+                    "proxy_disk_written_bytes_total",
+                    "Total number of bytes written on the given device",
+                    d.write_sectors * DISKSTATS_SECTOR_SIZE,
+                ),
+                (
+                    "proxy_disk_io_time_seconds_total",
+                    "Total time spent doing I/Os on the given device",
+                    d.io_ms / 1000.0,
+                ),
+            ];
+
+            for (name, doc, value) in totals {
+                counters.push(CounterSnapshot::new(
+                    name.to_string(),
+                    attrs.as_slice(),
+                    doc.to_string(),
+                    CounterType::Counter {
+                        ts: unix_ts_us(),
+                        value,
+                    },
+                ));
+            }
+
+            counters.push(CounterSnapshot::new(
+                "proxy_disk_io_in_progress".to_string(),
+                attrs.as_slice(),
+                "Number of I/Os currently in progress on the given device".to_string(),
+                gauge(d.in_progress),
+            ));
+
+            /* Rates need a previous sample of the same device */
+            let (prev_ts, prev) = match &self.diskstats {
+                Some((ts, stats)) => match stats.get(device) {
+                    Some(p) => (ts, p),
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            let elapsed_ms = now.duration_since(*prev_ts).as_secs_f64() * 1000.0;
+
+            /* Skip counters which wrapped or were reset */
+            if elapsed_ms <= 0.0 || d.reads < prev.reads || d.writes < prev.writes {
+                continue;
+            }
+
+            let per_s = |cur: f64, prev: f64| (cur - prev) * 1000.0 / elapsed_ms;
+            let await_s = |ms: f64, prev_ms: f64, ios: f64, prev_ios: f64| {
+                if ios > prev_ios {
+                    (ms - prev_ms) / (ios - prev_ios) / 1000.0
+                } else {
+                    0.0
+                }
+            };
+
+            let rates = [
+                (
+                    "proxy_disk_read_iops",
+                    "Reads per second during the refresh interval on the given device",
+                    per_s(d.reads, prev.reads),
+                ),
+                (
+                    "proxy_disk_write_iops",
+                    "Writes per second during the refresh interval on the given device",
+                    per_s(d.writes, prev.writes),
+                ),
+                (
+                    "proxy_disk_read_bandwidth_bytes",
+                    "Bytes read per second during the refresh interval on the given device",
+                    per_s(d.read_sectors, prev.read_sectors) * DISKSTATS_SECTOR_SIZE,
+                ),
+                (
+                    "proxy_disk_write_bandwidth_bytes",
+                    "Bytes written per second during the refresh interval on the given device",
+                    per_s(d.write_sectors, prev.write_sectors) * DISKSTATS_SECTOR_SIZE,
+                ),
+                (
+                    "proxy_disk_utilization_percent",
+                    "Percentage of the refresh interval the given device was busy",
+                    ((d.io_ms - prev.io_ms) * 100.0 / elapsed_ms).min(100.0),
+                ),
+                (
+                    "proxy_disk_queue_depth",
+                    "Average number of pending I/Os during the refresh interval on the given device",
+                    (d.weighted_io_ms - prev.weighted_io_ms) / elapsed_ms,
+                ),
+                (
+                    "proxy_disk_read_await_seconds",
+                    "Average time to complete a read during the refresh interval on the given device",
+                    await_s(d.read_ms, prev.read_ms, d.reads, prev.reads),
+                ),
+                (
+                    "proxy_disk_write_await_seconds",
+                    "Average time to complete a write during the refresh interval on the given device",
+                    await_s(d.write_ms, prev.write_ms, d.writes, prev.writes),
+                ),
+            ];
+
+            for (name, doc, value) in rates {
+                counters.push(CounterSnapshot::new(
+                    name.to_string(),
+                    attrs.as_slice(),
+                    doc.to_string(),
+                    gauge(value),
+                ));
+            }
+        }
+
+        self.diskstats = Some((now, current));
+
+        Ok(())
+    }
+
     fn scrape_disks(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
         for d in self.sys.disks() {
             let attrs: Vec<(String, String)> = vec![
@@ -182,7 +532,7 @@ impl SystemMetrics {
                 attrs.as_slice(),
                 "Total number of packets sent on the given device".to_string(),
                 CounterType::Counter {
-                    ts: unix_ts_us() as u64,
+                    ts: unix_ts_us(),
                     value: transmitted,
                 },
             ));
@@ -193,7 +543,7 @@ impl SystemMetrics {
                 attrs.as_slice(),
                 "Total number of packets received on the given device".to_string(),
                 CounterType::Counter {
-                    ts: unix_ts_us() as u64,
+                    ts: unix_ts_us(),
                     value: received,
                 },
             ));
@@ -204,7 +554,7 @@ impl SystemMetrics {
                 attrs.as_slice(),
                 "Total number of erroneous packets sent on the given device".to_string(),
                 CounterType::Counter {
-                    ts: unix_ts_us() as u64,
+                    ts: unix_ts_us(),
                     value: transmitted,
                 },
             ));
@@ -215,7 +565,7 @@ impl SystemMetrics {
                 attrs.as_slice(),
                 "Total number of erroneous  packets received on the given device".to_string(),
                 CounterType::Counter {
-                    ts: unix_ts_us() as u64,
+                    ts: unix_ts_us(),
                     value: received,
                 },
             ));
@@ -480,25 +830,49 @@ impl SystemMetrics {
     pub(crate) fn scrape(&mut self) -> Result<Vec<CounterSnapshot>, ProxyErr> {
         let mut ret: Vec<CounterSnapshot> = Vec::new();
 
-        self.sys.refresh_disks_list();
-        self.sys.refresh_disks();
-        self.scrape_disks(&mut ret)?;
+        if self.enabled(SystemCollector::Disks) {
+            self.sys.refresh_disks_list();
+            self.sys.refresh_disks();
+            self.scrape_disks(&mut ret)?;
+        }
+
+        if self.enabled(SystemCollector::Diskstats) {
+            self.scrape_diskstats(&mut ret)?;
+        }
+
+        if self.enabled(SystemCollector::Network) {
+            self.sys.refresh_networks_list();
+            self.sys.refresh_networks();
+            self.scrape_network_cards(&mut ret)?;
+        }
+
+        if self.enabled(SystemCollector::Temperatures) {
+            self.sys.refresh_components_list();
+            self.sys.refresh_components();
+            self.scrape_temperatures(&mut ret)?;
+        }
 
-        self.sys.refresh_networks_list();
-        self.sys.refresh_networks();
-        self.scrape_network_cards(&mut ret)?;
+        if self.enabled(SystemCollector::Memory) {
+            self.sys.refresh_memory();
+            self.scrape_memory(&mut ret)?;
+        }
 
-        self.sys.refresh_components_list();
-        self.sys.refresh_components();
-        self.scrape_temperatures(&mut ret)?;
+        if self.enabled(SystemCollector::Info) {
+            self.scrape_system_info(&mut ret)?;
+        }
 
-        self.sys.refresh_memory();
-        self.scrape_memory(&mut ret)?;
+        if self.enabled(SystemCollector::Cpu) {
+            self.sys.refresh_cpu();
+            self.scrape_cpu(&mut ret)?;
+        }
 
-        self.scrape_system_info(&mut ret)?;
+        if self.enabled(SystemCollector::Pressure) {
+            self.scrape_pressure(&mut ret)?;
+        }
 
-        self.sys.refresh_cpu();
-        self.scrape_cpu(&mut ret)?;
+        if self.enabled(SystemCollector::Vmstat) {
+            self.scrape_vmstat(&mut ret)?;
+        }
 
         /* Flag the last scrape TS */
         self.last_scrape = unix_ts() as f64 / 1000.0;
@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use reqwest::Method;
+
+use crate::auth::Auth;
+use crate::exporter::ExporterFactory;
+use crate::proxy_common::{unix_ts, ProxyErr};
+use crate::proxywireprotocol::{ApiResponse, TopoStatus};
+
+/// Period of the heartbeats sent by children to their parent in ms
+const HEARTBEAT_PERIOD: u64 = 5000;
+/// Missed heartbeats before a parent or a child is considered gone
+const HEARTBEAT_MISSES: u64 = 3;
+/// Deadline of the requests between proxies of the tree
+const TOPOLOGY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A proxy in the tree built by the root
+struct TopoNode {
+    /// None for the root and for orphans waiting to pivot again
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+/// Position of this proxy when it joined a tree
+struct Upstream {
+    root: String,
+    parent: String,
+    period: u64,
+    missed: u64,
+}
+
+/// A child of this proxy which sends heartbeats
+struct Child {
+    /// Key of the scrape of the child, None if it pushes its changes
+    scrape: Option<String>,
+    /// Period in ms of the scrapes or of the batches of the child
+    period: u64,
+    last_seen: u64,
+    /// Status of the subtree of the child sent with its last heartbeat
+    report: Option<TopoStatus>,
+}
+
+/// Builds and maintains the reduction tree of proxies, the root assigns
+/// the parents while each proxy watches its parent and its children
+pub(crate) struct Topology {
+    url: String,
+    /// Maximum number of children of a proxy
+    fanout: usize,
+    /// Maximum depth of the tree (0 for unlimited)
+    max_depth: usize,
+    /// Push changes to the parent instead of being scraped
+    push: bool,
+    factory: Arc<ExporterFactory>,
+    /// Proxies which pivoted on this one (only filled on the root)
+    tree: Mutex<HashMap<String, TopoNode>>,
+    children: Mutex<HashMap<String, Child>>,
+    upstream: Mutex<Option<Upstream>>,
+    /// Timestamp in ms of the start of the proxy
+    start: u64,
+}
+
+impl Topology {
+    pub(crate) fn new(
+        url: String,
+        fanout: usize,
+        max_depth: usize,
+        push: bool,
+        factory: Arc<ExporterFactory>,
+    ) -> Arc<Topology> {
+        let mut tree = HashMap::new();
+        tree.insert(
+            url.to_string(),
+            TopoNode {
+                parent: None,
+                children: Vec::new(),
+            },
+        );
+
+        let ret = Arc::new(Topology {
+            url,
+            fanout: fanout.max(1),
+            max_depth,
+            push,
+            factory,
+            tree: Mutex::new(tree),
+            children: Mutex::new(HashMap::new()),
+            upstream: Mutex::new(None),
+            start: unix_ts(),
+        });
+
+        let monitor = ret.clone();
+        thread::spawn(move || loop {
+            sleep(Duration::from_millis(HEARTBEAT_PERIOD));
+            monitor.check_upstream();
+            monitor.check_children();
+        });
+
+        ret
+    }
+
+    /// Response of another proxy, failing only if it cannot be reached
+    fn get(url: &str) -> Result<ApiResponse, ProxyErr> {
+        Auth::request(Method::GET, url, TOPOLOGY_TIMEOUT)
+            .and_then(|r| r.send())
+            .and_then(|r| r.json::<ApiResponse>())
+            .map_err(|e| ProxyErr::new(format!("Failed to query {} : {}", url, e)))
+    }
+
+    /// Acknowledgement of a status sent to another proxy
+    fn post(url: &str, status: &TopoStatus) -> Result<ApiResponse, ProxyErr> {
+        Auth::post(url, status, TOPOLOGY_TIMEOUT)
+            .and_then(|r| Ok(r.send()?.json::<ApiResponse>()?))
+            .map_err(|e| ProxyErr::new(format!("Failed to query {} : {}", url, e)))
+    }
+
+    fn query(url: &str) -> Result<ApiResponse, ProxyErr> {
+        let resp = Topology::get(url)?;
+
+        if !resp.success {
+            return Err(ProxyErr::new(format!(
+                "{} failed : {}",
+                url, resp.operation
+            )));
+        }
+
+        Ok(resp)
+    }
+
+    /********
+     * ROOT *
+     ********/
+
+    /// Depth of a node, None if it is not attached to the root
+    fn depth(&self, tree: &HashMap<String, TopoNode>, node: &str) -> Option<usize> {
+        let mut depth = 0;
+        let mut current = node;
+
+        while current != self.url {
+            current = tree.get(current)?.parent.as_ref()?;
+            depth += 1;
+            /* Guard against cycles */
+            if depth > tree.len() {
+                return None;
+            }
+        }
+
+        Some(depth)
+    }
+
+    /// Depth of the subtree below a node
+    fn height(tree: &HashMap<String, TopoNode>, node: &str) -> usize {
+        let mut height = 0;
+        let mut level: Vec<&str> = vec![node];
+
+        while !level.is_empty() && height <= tree.len() {
+            level = level
+                .iter()
+                .filter_map(|n| tree.get(*n))
+                .flat_map(|n| n.children.iter().map(|c| c.as_str()))
+                .collect();
+
+            if !level.is_empty() {
+                height += 1;
+            }
+        }
+
+        height
+    }
+
+    fn detach(tree: &mut HashMap<String, TopoNode>, node: &str) {
+        let parent = tree.get_mut(node).and_then(|n| n.parent.take());
+
+        if let Some(parent) = parent.and_then(|p| tree.get_mut(&p)) {
+            parent.children.retain(|c| c != node);
+        }
+    }
+
+    /// Remove a proxy from the tree, its children become orphans
+    /// until they pivot again with their subtrees
+    fn remove_node(&self, node: &str) {
+        if node == self.url {
+            return;
+        }
+
+        let mut tree = self.tree.lock().unwrap();
+
+        Topology::detach(&mut tree, node);
+
+        if let Some(removed) = tree.remove(node) {
+            log::info!("Proxy {} left the tree", node);
+            for c in removed.children {
+                if let Some(child) = tree.get_mut(&c) {
+                    child.parent = None;
+                }
+            }
+        }
+    }
+
+    /// Choose the parent of a proxy (possibly moving it with its subtree)
+    /// filling the shallowest proxies first within the fan-out and the depth
+    pub(crate) fn assign(&self, from: &str, lost: Option<&str>) -> Result<String, ProxyErr> {
+        if let Some(lost) = lost {
+            self.remove_node(lost);
+        }
+
+        let mut tree = self.tree.lock().unwrap();
+
+        /* Once detached the subtree of the proxy has no depth and is not a candidate */
+        Topology::detach(&mut tree, from);
+
+        let height = Topology::height(&tree, from);
+
+        let mut candidates: Vec<(usize, usize, String)> = tree
+            .iter()
+            .filter(|(name, _)| name.as_str() != from)
+            .filter_map(|(name, node)| {
+                let depth = self.depth(&tree, name)?;
+                Some((depth, node.children.len(), name.to_string()))
+            })
+            .filter(|(depth, _, _)| self.max_depth == 0 || depth + 1 + height <= self.max_depth)
+            .collect();
+
+        candidates.sort();
+
+        let parent = match candidates.iter().find(|(_, count, _)| *count < self.fanout) {
+            Some((_, _, p)) => p.to_string(),
+            None => {
+                /* The tree is full at the maximum depth, overload the least loaded proxy */
+                let least = candidates
+                    .iter()
+                    .min_by_key(|(_, count, _)| *count)
+                    .ok_or(ProxyErr::new("Did not match any server"))?;
+                log::warn!(
+                    "Reduction tree is full, {} gets {} children",
+                    least.2,
+                    least.1 + 1
+                );
+                least.2.to_string()
+            }
+        };
+
+        if let Some(p) = tree.get_mut(&parent) {
+            p.children.push(from.to_string());
+        }
+
+        tree.entry(from.to_string())
+            .or_insert(TopoNode {
+                parent: None,
+                children: Vec::new(),
+            })
+            .parent = Some(parent.to_string());
+
+        log::info!("Pivot response to {} is {}", from, parent);
+
+        Ok(parent)
+    }
+
+    /// A parent reported that one of its children is gone
+    pub(crate) fn leave(&self, node: &str) {
+        self.remove_node(node);
+    }
+
+    /// Edges (parent, child) of the live tree
+    pub(crate) fn edges(&self) -> Vec<(String, String)> {
+        let tree = self.tree.lock().unwrap();
+
+        let mut ret: Vec<(String, String)> = tree
+            .iter()
+            .filter(|(name, _)| self.depth(&tree, name).is_some())
+            .flat_map(|(name, node)| {
+                node.children
+                    .iter()
+                    .map(move |c| (name.to_string(), c.to_string()))
+            })
+            .collect();
+
+        ret.sort();
+
+        /* Scraped children of a proxy which is not the root */
+        if ret.is_empty() {
+            ret = self
+                .children
+                .lock()
+                .unwrap()
+                .keys()
+                .map(|c| (self.url.to_string(), c.to_string()))
+                .collect();
+        }
+
+        if ret.is_empty() {
+            ret.push((self.url.to_string(), self.url.to_string()));
+        }
+
+        ret
+    }
+
+    /**********
+     * PARENT *
+     **********/
+
+    /// Watch a child which joined this proxy with heartbeats
+    pub(crate) fn adopt(&self, child: &str, scrape: Option<String>, period: u64) {
+        self.children.lock().unwrap().insert(
+            child.to_string(),
+            Child {
+                scrape,
+                period,
+                last_seen: unix_ts(),
+                report: None,
+            },
+        );
+    }
+
+    /// Returns false for an unknown child which must then join again
+    pub(crate) fn heartbeat(&self, child: &str, report: Option<TopoStatus>) -> bool {
+        match self.children.lock().unwrap().get_mut(child) {
+            Some(c) => {
+                c.last_seen = unix_ts();
+                if report.is_some() {
+                    c.report = report;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Status of a child as reported by itself and as seen by this proxy
+    fn child_status(&self, name: &str, child: &Child, now: u64) -> TopoStatus {
+        let mut status = child.report.clone().unwrap_or(TopoStatus {
+            url: name.to_string(),
+            version: String::new(),
+            uptime: 0,
+            jobs: 0,
+            push: child.scrape.is_none(),
+            last_heartbeat: None,
+            last_success: None,
+            lag: None,
+            healthy: false,
+            error: None,
+            children: Vec::new(),
+        });
+
+        let mut error: Option<String> = None;
+
+        /* Timestamp in ms of the last data received from the child */
+        let last_success = match &child.scrape {
+            Some(key) => match self.factory.scrape_snapshot(key) {
+                Some(snap) => {
+                    error = snap.last_error().cloned();
+                    Some(snap.last_success() * 1000).filter(|ts| *ts > 0)
+                }
+                None => None,
+            },
+            None => self.factory.last_forwarded(name),
+        };
+
+        /* Missed at least one heartbeat */
+        if now.saturating_sub(child.last_seen) > 2 * HEARTBEAT_PERIOD {
+            error = Some("Late heartbeats".to_string());
+        }
+
+        let lag = last_success.map(|ts| now.saturating_sub(ts));
+
+        match lag {
+            Some(lag) if lag > child.period.max(HEARTBEAT_PERIOD) * HEARTBEAT_MISSES => {
+                error = error.or(Some(format!("No data for {} s", lag / 1000)));
+            }
+            None => error = error.or(Some("No data received yet".to_string())),
+            _ => {}
+        }
+
+        if child.report.is_none() {
+            error = error.or(Some("No status reported".to_string()));
+        }
+
+        status.url = name.to_string();
+        status.last_heartbeat = Some(child.last_seen / 1000);
+        status.last_success = last_success.map(|ts| ts / 1000);
+        status.lag = lag.map(|l| l / 1000);
+        status.healthy = error.is_none();
+        status.error = error;
+
+        status
+    }
+
+    /// Status of this proxy with the subtree of its children
+    pub(crate) fn status(&self) -> TopoStatus {
+        let now = unix_ts();
+
+        let mut children: Vec<TopoStatus> = self
+            .children
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, child)| self.child_status(name, child, now))
+            .collect();
+
+        children.sort_by(|a, b| a.url.cmp(&b.url));
+
+        TopoStatus {
+            url: self.url.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime: now.saturating_sub(self.start) / 1000,
+            jobs: self.factory.job_count(),
+            push: self.push,
+            last_heartbeat: None,
+            last_success: None,
+            lag: None,
+            healthy: true,
+            error: None,
+            children,
+        }
+    }
+
+    /// Drop the children which stopped sending heartbeats
+    fn check_children(&self) {
+        let deadline = unix_ts().saturating_sub(HEARTBEAT_PERIOD * HEARTBEAT_MISSES);
+
+        let mut gone: Vec<(String, Option<String>)> = Vec::new();
+
+        self.children.lock().unwrap().retain(|name, c| {
+            if c.last_seen < deadline {
+                gone.push((name.to_string(), c.scrape.clone()));
+                false
+            } else {
+                true
+            }
+        });
+
+        let root = self
+            .upstream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|u| u.root.to_string());
+
+        for (name, scrape) in gone {
+            log::warn!("Child proxy {} stopped sending heartbeats", name);
+
+            /* Releases the jobs of the child */
+            match scrape {
+                Some(scrape) => self.factory.remove_scrape(&scrape),
+                None => self.factory.remove_forwarded(&name),
+            }
+
+            match &root {
+                Some(root) => {
+                    if let Err(e) = Topology::query(&format!("{}/topo/leave?node={}", root, name)) {
+                        log::debug!("Failed to report the departure of {} : {}", name, e);
+                    }
+                }
+                None => self.leave(&name),
+            }
+        }
+    }
+
+    /*********
+     * CHILD *
+     *********/
+
+    /// Pivot on the root and register in the parent it assigns
+    fn pivot(&self, root: &str, period: u64, lost: Option<&str>) -> Result<String, ProxyErr> {
+        let mut pivot_url = format!("{}/pivot?from={}", root, self.url);
+
+        if let Some(lost) = lost {
+            pivot_url += format!("&lost={}", lost).as_str();
+        }
+
+        let parent = Topology::query(&pivot_url)?.operation;
+
+        Topology::query(&format!(
+            "{}/join?to={}&period={}&heartbeat=true&push={}",
+            parent, self.url, period, self.push
+        ))?;
+
+        Ok(parent)
+    }
+
+    /// This function is called when joining another proxy
+    ///
+    /// It will first request the target address from the root server
+    /// and then it will register itself in the returned address
+    /// This function is used to dynamically build the reduction tree
+    pub(crate) fn join(&self, root: &str, period: u64) -> Result<(), ProxyErr> {
+        let mut attempts = 0;
+
+        /* We add some delay as the root server may get smashed */
+        let parent = loop {
+            match self.pivot(root, period, None) {
+                Ok(p) => break p,
+                Err(e) => {
+                    attempts += 1;
+                    if attempts == 5 {
+                        return Err(e);
+                    }
+                    sleep(Duration::from_millis(2000));
+                }
+            }
+        };
+
+        log::info!(
+            "Joining aggregating proxy {} with period {}",
+            parent,
+            period
+        );
+
+        *self.upstream.lock().unwrap() = Some(Upstream {
+            root: root.to_string(),
+            parent,
+            period,
+            missed: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Current parent and period of this proxy in the tree
+    pub(crate) fn parent(&self) -> Option<(String, u64)> {
+        self.upstream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|u| (u.parent.to_string(), u.period))
+    }
+
+    /// Send a heartbeat to the parent and pivot again when it is gone
+    fn check_upstream(&self) {
+        let (root, parent, period) = match self.upstream.lock().unwrap().as_ref() {
+            Some(u) => (u.root.to_string(), u.parent.to_string(), u.period),
+            None => return,
+        };
+
+        /* The subtree of this proxy goes up the tree with the heartbeats */
+        let heartbeat = Topology::post(
+            &format!("{}/topo/heartbeat?from={}", parent, self.url),
+            &self.status(),
+        );
+
+        let lost = {
+            let mut upstream = self.upstream.lock().unwrap();
+            let upstream = match upstream.as_mut() {
+                Some(u) => u,
+                None => return,
+            };
+
+            match heartbeat {
+                Ok(r) if r.success => {
+                    upstream.missed = 0;
+                    return;
+                }
+                /* The parent is alive but no longer scrapes us (it restarted) */
+                Ok(_) => None,
+                Err(e) => {
+                    log::debug!("Heartbeat to {} failed : {}", parent, e);
+                    upstream.missed += 1;
+                    if upstream.missed < HEARTBEAT_MISSES {
+                        return;
+                    }
+                    Some(parent.to_string())
+                }
+            }
+        };
+
+        log::warn!("Lost parent proxy {}, pivoting again on {}", parent, root);
+
+        match self.pivot(&root, period, lost.as_deref()) {
+            Ok(new_parent) => {
+                log::info!("Re-parented from {} to {}", parent, new_parent);
+                if let Some(u) = self.upstream.lock().unwrap().as_mut() {
+                    u.parent = new_parent;
+                    u.missed = 0;
+                }
+            }
+            Err(e) => log::error!("Failed to pivot again on {} : {}", root, e),
+        }
+    }
+}
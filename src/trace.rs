@@ -3,9 +3,6 @@ use std::process::{Command, ExitStatus, Stdio};
 use std::{
     collections::HashMap,
     error::Error,
-    fs::{remove_file, File, OpenOptions},
-    io::Seek,
-    os::unix::prelude::FileExt,
     path::{Path, PathBuf},
     sync::{Arc, Mutex, RwLock},
 };
@@ -15,7 +12,6 @@ use rayon::{
     slice::ParallelSlice,
 };
 use serde::{Deserialize, Serialize};
-use serde_binary::binary_stream;
 
 use crate::{
     exporter::ExporterFactory,
@@ -25,6 +21,7 @@ use crate::{
 
 use crate::proxy_common::derivate_time_serie;
 use crate::proxy_common::offset_time_serie;
+use crate::tracefile::{TraceFile, TraceFormat};
 
 /**********************
  * JSON TRACE SUPPORT *
@@ -150,7 +147,7 @@ pub(crate) enum TraceFrame {
 }
 
 impl TraceFrame {
-    fn ts(&self) -> f64 {
+    pub(crate) fn ts(&self) -> f64 {
         match *self {
             TraceFrame::Desc { ts, desc: _ } => ts,
             TraceFrame::CounterMetadata { ts, metadata: _ } => ts,
@@ -229,7 +226,7 @@ impl TraceFrame {
         }
     }
 
-    fn is_counters(&self) -> bool {
+    pub(crate) fn is_counters(&self) -> bool {
         matches!(self, TraceFrame::Counters { .. })
     }
 
@@ -323,6 +320,8 @@ struct TraceState {
     lastwrite: f64,
     /// Path of the trace
     path: PathBuf,
+    /// Frames on disk
+    file: TraceFile,
 
     /// Current counter identifier of the trace
     current_counter_id: u64,
@@ -332,28 +331,8 @@ struct TraceState {
 }
 
 impl TraceState {
-    fn open(&self, create: bool) -> Result<File, std::io::Error> {
-        if create {
-            OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(&self.path)
-        } else {
-            OpenOptions::new().read(true).append(true).open(&self.path)
-        }
-    }
-
-    fn len(&self) -> Result<u64, Box<dyn Error>> {
-        let fd = self.open(false)?;
-        Ok(fd.metadata()?.len())
-    }
-
-    fn desc_from_file(path: &PathBuf) -> Result<JobDesc, Box<dyn Error>> {
-        let mut fd = File::open(path)?;
-        let (data, _) = Self::read_frame_at(&mut fd, 0)?;
-
-        if let Some(frame) = data {
+    fn desc(&mut self) -> Result<JobDesc, Box<dyn Error>> {
+        if let Some(frame) = self.file.read_first()? {
             return Ok(frame.desc()?);
         }
 
@@ -362,86 +341,6 @@ impl TraceState {
         ))
     }
 
-    fn desc(&mut self) -> Result<JobDesc, Box<dyn Error>> {
-        TraceState::desc_from_file(&self.path)
-    }
-
-    fn offset_of_last_frame_start(fd: &mut File) -> Result<u64, Box<dyn Error>> {
-        let total_size = fd.metadata()?.len();
-        let mut offset = 0;
-        loop {
-            let mut size: [u8; 8] = [0; 8];
-            fd.read_exact_at(&mut size, offset).unwrap();
-            let size = u64::from_le_bytes(size);
-
-            offset += 8;
-
-            match (offset + size).cmp(&total_size) {
-                std::cmp::Ordering::Equal => {
-                    return Ok(offset - 8);
-                }
-                std::cmp::Ordering::Greater => {
-                    return Err(ProxyErr::newboxed(
-                        "Overrun of the file when scanning for EOF",
-                    ));
-                }
-                _ => {}
-            }
-
-            offset += size;
-        }
-    }
-
-    fn read_last(&mut self) -> Result<Option<TraceFrame>, Box<dyn Error>> {
-        let mut fd = self.open(false)?;
-        let off = Self::offset_of_last_frame_start(&mut fd)?;
-        if off == (fd.metadata()?.len() - 1) {
-            /* This is an empty frame */
-            return Ok(None);
-        }
-        let (data, _) = Self::read_frame_at(&mut fd, off)?;
-        Ok(data)
-    }
-
-    fn read_frame_at(fd: &mut File, off: u64) -> Result<(Option<TraceFrame>, u64), Box<dyn Error>> {
-        let mut data: Vec<u8> = Vec::new();
-        let mut current_offset = off;
-
-        /* We expect an 8 bytes integer at the start */
-        let mut len_data: [u8; 8] = [0; 8];
-        if fd.read_at(&mut len_data, current_offset)? == 0 {
-            /* EOF */
-            return Ok((None, current_offset));
-        }
-        current_offset += 8;
-
-        let mut left_to_read = u64::from_le_bytes(len_data);
-
-        loop {
-            let block_size = if left_to_read < 1024 {
-                left_to_read as usize
-            } else {
-                1024
-            };
-
-            let mut buff: [u8; 1024] = [0; 1024];
-            let len = fd.read_at(&mut buff[..block_size], current_offset)?;
-
-            for c in buff.iter().take(len) {
-                current_offset += 1;
-                left_to_read -= 1;
-
-                data.push(*c);
-
-                if left_to_read == 0 {
-                    let frame: TraceFrame =
-                        serde_binary::from_slice(&data, binary_stream::Endian::Little)?;
-                    return Ok((Some(frame), current_offset));
-                }
-            }
-        }
-    }
-
     fn check_counter(&mut self, counters: &[CounterSnapshot]) -> Vec<TraceFrame> {
         let mut ret: Vec<TraceFrame> = Vec::new();
 
@@ -478,30 +377,8 @@ impl TraceState {
         None
     }
 
-    fn do_write_frame(fd: &mut File, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
-        let buff: Vec<u8> = serde_binary::to_vec(&frame, binary_stream::Endian::Little)?;
-
-        // First write length
-        let len: u64 = buff.len() as u64;
-        let len = len.to_le_bytes();
-
-        let endoff = fd.stream_position()?;
-        fd.write_all_at(&len, endoff)?;
-
-        // And then write buff
-        let endoff = fd.stream_position()?;
-        fd.write_at(&buff, endoff)?;
-
-        Ok(())
-    }
-
     fn write_frame(&mut self, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
-        let mut fd = self.open(false)?;
-
-        Self::do_write_frame(&mut fd, frame)?;
-
-        self.size = fd.metadata()?.len();
-
+        self.size = self.file.append(std::slice::from_ref(frame))?;
         Ok(())
     }
 
@@ -510,14 +387,8 @@ impl TraceState {
             return Ok(());
         }
 
-        let mut fd = self.open(false)?;
-
-        for f in frames.iter() {
-            Self::do_write_frame(&mut fd, f)?;
-        }
-
+        self.size = self.file.append(frames)?;
         self.lastwrite = unix_ts() as f64 / 1000.0;
-        self.size = fd.metadata()?.len();
 
         Ok(())
     }
@@ -553,25 +424,15 @@ impl TraceState {
             })
             .collect();
 
-        /* Now rewrite it all */
-        remove_file(&self.path)?;
-
-        /* Just recreate the file */
-        let fd = self.open(true);
-        drop(fd);
-
-        /* Desc first */
-        self.write_frame(&desc)?;
+        /* Now rewrite it all, older traces are converted to the current format */
+        self.file = TraceFile::create(&self.path)?;
 
-        /* Then all metadata */
-        for v in meta.iter() {
-            self.write_frame(v)?;
-        }
+        /* Desc first, then all metadata and counters */
+        let mut frames: Vec<TraceFrame> = vec![desc];
+        frames.extend(meta.iter().cloned());
+        frames.extend(newcounters.iter().cloned());
 
-        /* And counters */
-        for v in newcounters.iter() {
-            self.write_frame(v)?;
-        }
+        self.size = self.file.append(&frames)?;
 
         /* Update in memory state */
         self.trace_data.clear();
@@ -613,27 +474,6 @@ impl TraceState {
         Ok(false)
     }
 
-    fn read_all(&mut self) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
-        let mut frames = Vec::new();
-
-        let mut fd = self.open(false)?;
-
-        /* First frame is the desc */
-        let mut current_offset: u64 = 0;
-        let mut frame: Option<TraceFrame>;
-
-        loop {
-            (frame, current_offset) = Self::read_frame_at(&mut fd, current_offset)?;
-
-            if frame.is_none() {
-                return Ok(frames);
-            }
-
-            /* Full frame */
-            frames.push(frame.unwrap());
-        }
-    }
-
     fn new(path: &Path, job: &JobDesc, max_size: usize) -> Result<TraceState, Box<dyn Error>> {
         // First thing save the jobdesc
         let desc = TraceFrame::Desc {
@@ -641,25 +481,33 @@ impl TraceState {
             desc: job.clone(),
         };
 
-        let ret = TraceState {
+        let mut ret = TraceState {
             loaded: true, // Trace is new thus already loaded
             size: 0,
             max_size,
             lastwrite: 0.0,
             path: path.to_path_buf(),
+            file: TraceFile::create(path)?,
             current_counter_id: 0,
             trace_data: TraceData::empty(&desc),
         };
 
-        let mut fd = ret.open(true)?;
-
-        TraceState::do_write_frame(&mut fd, &desc)?;
+        ret.write_frame(&desc)?;
 
         Ok(ret)
     }
 
     fn from(path: &Path, max_size: usize) -> Result<TraceState, Box<dyn Error>> {
-        let desc = TraceState::desc_from_file(&path.to_path_buf())?;
+        let file = TraceFile::open(path)?;
+
+        let desc = match file.read_first()? {
+            Some(frame) => frame.desc()?,
+            None => {
+                return Err(ProxyErr::newboxed(
+                    "First frame of the trace is not a trace description",
+                ))
+            }
+        };
 
         let desc = TraceFrame::Desc {
             ts: unix_ts() as f64,
@@ -668,20 +516,17 @@ impl TraceState {
 
         let mut ret = TraceState {
             loaded: false, // Trace is not loaded already
-            size: 0,
+            size: file.len()?,
             max_size,
             lastwrite: 0.0,
             path: path.to_path_buf(),
+            file,
             current_counter_id: 0,
             trace_data: TraceData::empty(&desc),
         };
 
-        let lastframe = ret.read_last()?;
-
-        ret.size = ret.len()?;
-
-        if let Some(f) = lastframe {
-            ret.lastwrite = f.ts();
+        if let Some(ts) = ret.file.last_ts()? {
+            ret.lastwrite = ts;
         }
 
         Ok(ret)
@@ -689,7 +534,7 @@ impl TraceState {
 
     fn load(&mut self) -> Result<(), Box<dyn Error>> {
         if !self.loaded {
-            let mut frames = self.read_all()?;
+            let mut frames = self.file.read_all()?;
             self.trace_data.clear();
             self.trace_data.append_data(&mut frames);
             self.loaded = true;
@@ -701,6 +546,57 @@ impl TraceState {
         self.load()?;
         Ok(self.trace_data.counters.keys().cloned().collect())
     }
+
+    /// Values of a metric between two timestamps in seconds
+    #[allow(unused)]
+    fn serie_between(
+        data: &TraceData,
+        metric: &str,
+        from: f64,
+        to: f64,
+    ) -> Result<Vec<(f64, CounterType)>, ProxyErr> {
+        let counter = data
+            .counters
+            .get(metric)
+            .ok_or(ProxyErr::new(format!("No such metric {}", metric)))?;
+
+        let serie = data.series.get(&counter.id).ok_or(ProxyErr::new(format!(
+            "Failed to retrieve metric data {}",
+            metric
+        )))?;
+
+        Ok(serie
+            .iter()
+            .filter(|(ts, _)| *ts >= from && *ts <= to)
+            .cloned()
+            .collect())
+    }
+
+    /// Values of a metric in a time window, only the blocks of the
+    /// window are read when the trace is not loaded already
+    #[allow(unused)]
+    fn window(
+        &mut self,
+        metric: &str,
+        from: f64,
+        to: f64,
+    ) -> Result<Vec<(f64, CounterType)>, Box<dyn Error>> {
+        if !self.loaded && self.file.format() == TraceFormat::V2 {
+            let mut frames = self.file.read_window(from, to)?;
+            let mut data = TraceData::empty(&self.trace_data.desc);
+            data.append_data(&mut frames);
+            return Ok(TraceState::serie_between(&data, metric, from, to)?);
+        }
+
+        /* V1 traces have no index and are read whole */
+        self.load()?;
+        Ok(TraceState::serie_between(
+            &self.trace_data,
+            metric,
+            from,
+            to,
+        )?)
+    }
 }
 
 pub(crate) struct Trace {
@@ -896,7 +792,7 @@ impl TraceView {
         let path = Trace::name(&self.prefix, desc);
         if path.is_file() {
             log::error!("Removing {}", path.to_string_lossy());
-            remove_file(path)?;
+            TraceFile::remove(&path)?;
         }
 
         if let Ok(tr) = self.traces.write().as_mut() {
@@ -1007,11 +903,31 @@ impl TraceView {
         ret
     }
 
+    /// Values of a metric, within a window in seconds if given
     #[allow(unused)]
-    pub(crate) fn plot(&self, jobid: &String, filter: String) -> Result<Vec<(f64, f64)>, ProxyErr> {
-        let trace = self.read(jobid, Some(filter))?;
-        let ret = TraceView::to_time_serie(&trace.time_serie);
-        Ok(ret)
+    pub(crate) fn plot(
+        &self,
+        jobid: &String,
+        filter: String,
+        window: Option<(f64, f64)>,
+    ) -> Result<Vec<(f64, f64)>, ProxyErr> {
+        let (from, to) = match window {
+            Some(w) => w,
+            None => {
+                let trace = self.read(jobid, Some(filter))?;
+                return Ok(TraceView::to_time_serie(&trace.time_serie));
+            }
+        };
+
+        let ht = self.traces.read().unwrap();
+
+        let trace = ht
+            .get(jobid)
+            .ok_or(ProxyErr::new(format!("No such trace id {}", jobid)))?;
+
+        let time_serie = trace.state.lock().unwrap().window(&filter, from, to)?;
+
+        Ok(TraceView::to_time_serie(&time_serie))
     }
 
     pub(crate) fn get(
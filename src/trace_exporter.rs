@@ -12,6 +12,7 @@ use std::sync::Arc;
 
 mod proxywireprotocol;
 mod trace;
+mod tracefile;
 
 use trace::TraceExport;
 
@@ -23,9 +24,19 @@ use colored::Colorize;
 use std::fs::File;
 use std::io::Write;
 
+mod alarms;
+mod auth;
+mod cgroupmetrics;
+mod config;
+mod counterfiles;
+mod discovery;
 mod exporter;
 mod extrap;
+mod filetail;
+mod forwarded;
+mod procmetrics;
 mod profiles;
+mod sacct;
 mod scrapper;
 mod systemmetrics;
 use exporter::ExporterFactory;
@@ -65,7 +76,7 @@ struct TraceExporter {
 
 impl TraceExporter {
     fn new(path: &Path) -> Result<TraceExporter, ProxyErr> {
-        let factory = ExporterFactory::new(path.to_path_buf(), false, 1024 * 1024 * 32)?;
+        let factory = ExporterFactory::new(path.to_path_buf(), false, 1024 * 1024 * 32, None)?;
         Ok(TraceExporter { factory })
     }
 
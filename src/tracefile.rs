@@ -0,0 +1,777 @@
+use std::error::Error;
+use std::fs::{self, remove_file, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+
+use serde_binary::binary_stream;
+
+use crate::proxy_common::ProxyErr;
+use crate::trace::TraceFrame;
+
+/// First bytes of v2 traces, v1 traces start with the length of their first frame
+const TRACE_MAGIC: &[u8; 8] = b"PXTRACE\0";
+const TRACE_VERSION: u32 = 2;
+/// Magic, version and reserved bytes
+const HEADER_SIZE: u64 = 16;
+
+/// "BLK2" starting each block header
+const BLOCK_MAGIC: u32 = 0x324b_4c42;
+const BLOCK_HEADER_SIZE: u64 = 40;
+/// Blocks are indexed and a new one is started once they hold this many bytes of frames
+const BLOCK_SIZE: u64 = 256 * 1024;
+/// The block holds the description of the job and metadata of counters
+const BLOCK_HAS_METADATA: u32 = 1;
+
+/// Entries of the sidecar index are the offset of a block followed by its header
+const INDEX_ENTRY_SIZE: usize = 8 + BLOCK_HEADER_SIZE as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TraceFormat {
+    /// Length prefixed frames without index
+    V1,
+    /// Header followed by checksummed blocks of length prefixed frames,
+    /// the blocks are listed with their time range in a sidecar index
+    V2,
+}
+
+/// Header of a block of frames in a v2 trace
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    /// Offset of the header in the trace
+    offset: u64,
+    flags: u32,
+    /// Size of the frames of the block
+    len: u64,
+    frames: u32,
+    /// CRC32 of the frames of the block
+    crc: u32,
+    /// Range of the timestamps of the frames in seconds
+    start: f64,
+    end: f64,
+}
+
+impl Block {
+    fn encode(&self) -> Vec<u8> {
+        let mut ret: Vec<u8> = Vec::with_capacity(BLOCK_HEADER_SIZE as usize);
+        ret.extend_from_slice(&BLOCK_MAGIC.to_le_bytes());
+        ret.extend_from_slice(&self.flags.to_le_bytes());
+        ret.extend_from_slice(&self.len.to_le_bytes());
+        ret.extend_from_slice(&self.frames.to_le_bytes());
+        ret.extend_from_slice(&self.crc.to_le_bytes());
+        ret.extend_from_slice(&self.start.to_le_bytes());
+        ret.extend_from_slice(&self.end.to_le_bytes());
+        ret
+    }
+
+    /// None if the bytes are not a block header
+    fn decode(offset: u64, buf: &[u8]) -> Option<Block> {
+        if buf.len() < BLOCK_HEADER_SIZE as usize {
+            return None;
+        }
+
+        let u32_at = |o: usize| u32::from_le_bytes(buf[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(buf[o..o + 8].try_into().unwrap());
+
+        if u32_at(0) != BLOCK_MAGIC {
+            return None;
+        }
+
+        Some(Block {
+            offset,
+            flags: u32_at(4),
+            len: u64_at(8),
+            frames: u32_at(16),
+            crc: u32_at(20),
+            start: f64::from_bits(u64_at(24)),
+            end: f64::from_bits(u64_at(32)),
+        })
+    }
+
+    fn data(&self) -> u64 {
+        self.offset + BLOCK_HEADER_SIZE
+    }
+
+    /// Offset of the following block
+    fn next(&self) -> u64 {
+        self.data() + self.len
+    }
+
+    #[allow(unused)]
+    fn overlaps(&self, from: f64, to: f64) -> bool {
+        self.end >= from && self.start <= to
+    }
+}
+
+/// On-disk storage of the frames of a trace
+pub(crate) struct TraceFile {
+    path: PathBuf,
+    format: TraceFormat,
+    /// Blocks of a v2 trace in file order
+    blocks: Vec<Block>,
+    /// Number of blocks in the sidecar index, only the last block may be missing
+    indexed: usize,
+    /// Checksum of the last block while this process appends to it,
+    /// blocks of a previous run are left as they are
+    crc: Option<crc32fast::Hasher>,
+}
+
+impl TraceFile {
+    fn index_path(path: &Path) -> PathBuf {
+        let mut ret = path.as_os_str().to_owned();
+        ret.push(".idx");
+        PathBuf::from(ret)
+    }
+
+    /// Create an empty v2 trace, replacing any existing one
+    pub(crate) fn create(path: &Path) -> Result<TraceFile, Box<dyn Error>> {
+        TraceFile::remove(path)?;
+
+        let mut header: Vec<u8> = TRACE_MAGIC.to_vec();
+        header.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut fd = File::create(path)?;
+        fd.write_all(&header)?;
+
+        Ok(TraceFile {
+            path: path.to_path_buf(),
+            format: TraceFormat::V2,
+            blocks: Vec::new(),
+            indexed: 0,
+            crc: None,
+        })
+    }
+
+    /// Open an existing trace of any version
+    pub(crate) fn open(path: &Path) -> Result<TraceFile, Box<dyn Error>> {
+        let fd = File::open(path)?;
+
+        let mut ret = TraceFile {
+            path: path.to_path_buf(),
+            format: TraceFormat::V1,
+            blocks: Vec::new(),
+            indexed: 0,
+            crc: None,
+        };
+
+        let mut magic: [u8; 8] = [0; 8];
+        if fd.read_at(&mut magic, 0)? < magic.len() || &magic != TRACE_MAGIC {
+            return Ok(ret);
+        }
+
+        let mut version: [u8; 4] = [0; 4];
+        fd.read_exact_at(&mut version, 8)?;
+        let version = u32::from_le_bytes(version);
+
+        if version != TRACE_VERSION {
+            return Err(ProxyErr::newboxed(format!(
+                "Unsupported version {} of trace {}",
+                version,
+                path.to_string_lossy()
+            )));
+        }
+
+        ret.format = TraceFormat::V2;
+        ret.load_index(&fd)?;
+
+        Ok(ret)
+    }
+
+    /// Remove a trace and its index
+    pub(crate) fn remove(path: &Path) -> Result<(), io::Error> {
+        for p in [path.to_path_buf(), TraceFile::index_path(path)] {
+            match remove_file(&p) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub(crate) fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub(crate) fn len(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
+    /*********
+     * INDEX *
+     *********/
+
+    /// Blocks listed in the sidecar index then the ones written after them,
+    /// the index is rebuilt from the block headers if it does not match the trace
+    fn load_index(&mut self, fd: &File) -> Result<(), Box<dyn Error>> {
+        let file_len = fd.metadata()?.len();
+        let index_path = TraceFile::index_path(&self.path);
+
+        let mut rebuild = false;
+
+        if let Ok(index) = fs::read(&index_path) {
+            for entry in index.chunks_exact(INDEX_ENTRY_SIZE) {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let expected = self.blocks.last().map_or(HEADER_SIZE, |b| b.next());
+
+                match Block::decode(offset, &entry[8..]) {
+                    Some(b) if offset == expected && b.next() <= file_len => self.blocks.push(b),
+                    _ => {
+                        log::warn!(
+                            "Index of {} does not match the trace, rebuilding it",
+                            self.path.to_string_lossy()
+                        );
+                        self.blocks.clear();
+                        rebuild = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.indexed = self.blocks.len();
+
+        /* Blocks written after the last indexed one */
+        let mut offset = self.blocks.last().map_or(HEADER_SIZE, |b| b.next());
+        let mut header: [u8; BLOCK_HEADER_SIZE as usize] = [0; BLOCK_HEADER_SIZE as usize];
+
+        while offset + BLOCK_HEADER_SIZE <= file_len {
+            fd.read_exact_at(&mut header, offset)?;
+            match Block::decode(offset, &header) {
+                Some(b) if b.next() <= file_len => {
+                    offset = b.next();
+                    self.blocks.push(b);
+                }
+                /* Torn write of the last block */
+                _ => break,
+            }
+        }
+
+        if rebuild || self.blocks.len() > self.indexed + 1 {
+            self.write_index()?;
+        }
+
+        Ok(())
+    }
+
+    /// Index all the blocks but the last one which may still grow
+    fn write_index(&mut self) -> Result<(), Box<dyn Error>> {
+        let count = self.blocks.len().saturating_sub(1);
+
+        let mut index: Vec<u8> = Vec::with_capacity(count * INDEX_ENTRY_SIZE);
+        for b in self.blocks.iter().take(count) {
+            index.extend_from_slice(&b.offset.to_le_bytes());
+            index.extend(b.encode());
+        }
+
+        fs::write(TraceFile::index_path(&self.path), index)?;
+        self.indexed = count;
+
+        Ok(())
+    }
+
+    /// Add the last block to the index before starting a new one
+    fn index_last(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.indexed >= self.blocks.len() {
+            return Ok(());
+        }
+
+        let last = self.blocks[self.blocks.len() - 1];
+
+        let mut entry: Vec<u8> = last.offset.to_le_bytes().to_vec();
+        entry.extend(last.encode());
+
+        let mut fd = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(TraceFile::index_path(&self.path))?;
+        fd.write_all(&entry)?;
+
+        self.indexed = self.blocks.len();
+
+        Ok(())
+    }
+
+    /**********
+     * FRAMES *
+     **********/
+
+    fn encode_frame(frame: &TraceFrame) -> Result<Vec<u8>, Box<dyn Error>> {
+        let buff: Vec<u8> = serde_binary::to_vec(frame, binary_stream::Endian::Little)?;
+
+        let mut ret: Vec<u8> = (buff.len() as u64).to_le_bytes().to_vec();
+        ret.extend(buff);
+
+        Ok(ret)
+    }
+
+    fn decode_frames(data: &[u8]) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
+        let mut ret: Vec<TraceFrame> = Vec::new();
+        let mut offset: usize = 0;
+
+        while offset < data.len() {
+            let len = data
+                .get(offset..offset + 8)
+                .ok_or(ProxyErr::new("Truncated frame length"))?;
+            let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+            offset += 8;
+
+            let frame = offset
+                .checked_add(len)
+                .and_then(|end| data.get(offset..end))
+                .ok_or(ProxyErr::new("Truncated frame"))?;
+            ret.push(serde_binary::from_slice(
+                frame,
+                binary_stream::Endian::Little,
+            )?);
+            offset += len;
+        }
+
+        Ok(ret)
+    }
+
+    /// Frame at an offset of a v1 trace and the offset of the next one
+    fn read_frame_at(fd: &File, off: u64) -> Result<(Option<TraceFrame>, u64), Box<dyn Error>> {
+        let mut len_data: [u8; 8] = [0; 8];
+        if fd.read_at(&mut len_data, off)? == 0 {
+            /* EOF */
+            return Ok((None, off));
+        }
+
+        let len = u64::from_le_bytes(len_data);
+
+        if off + 8 + len > fd.metadata()?.len() {
+            return Err(ProxyErr::newboxed(format!(
+                "Frame at offset {} overruns the trace",
+                off
+            )));
+        }
+
+        let mut data: Vec<u8> = vec![0; len as usize];
+        fd.read_exact_at(&mut data, off + 8)?;
+
+        let frame: TraceFrame = serde_binary::from_slice(&data, binary_stream::Endian::Little)?;
+
+        Ok((Some(frame), off + 8 + len))
+    }
+
+    fn offset_of_last_frame_start(fd: &File) -> Result<u64, Box<dyn Error>> {
+        let total_size = fd.metadata()?.len();
+        let mut offset = 0;
+        loop {
+            let mut size: [u8; 8] = [0; 8];
+            fd.read_exact_at(&mut size, offset)?;
+            let size = u64::from_le_bytes(size);
+
+            offset += 8;
+
+            match (offset + size).cmp(&total_size) {
+                std::cmp::Ordering::Equal => {
+                    return Ok(offset - 8);
+                }
+                std::cmp::Ordering::Greater => {
+                    return Err(ProxyErr::newboxed(
+                        "Overrun of the file when scanning for EOF",
+                    ));
+                }
+                _ => {}
+            }
+
+            offset += size;
+        }
+    }
+
+    /// Frames of a block after checking its checksum
+    fn read_block(fd: &File, block: &Block) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
+        let mut data: Vec<u8> = vec![0; block.len as usize];
+        fd.read_exact_at(&mut data, block.data())?;
+
+        if crc32fast::hash(&data) != block.crc {
+            return Err(ProxyErr::newboxed(format!(
+                "Checksum mismatch in block at offset {}",
+                block.offset
+            )));
+        }
+
+        TraceFile::decode_frames(&data)
+    }
+
+    /// Frames of the selected blocks, corrupted blocks are skipped
+    fn read_blocks<F>(&self, filter: F) -> Result<Vec<TraceFrame>, Box<dyn Error>>
+    where
+        F: Fn(&Block) -> bool,
+    {
+        let fd = File::open(&self.path)?;
+        let mut ret: Vec<TraceFrame> = Vec::new();
+
+        for b in self.blocks.iter().filter(|b| filter(b)) {
+            match TraceFile::read_block(&fd, b) {
+                Ok(mut frames) => ret.append(&mut frames),
+                Err(e) => log::error!("Skipping block of {} : {}", self.path.to_string_lossy(), e),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// First frame of the trace which describes the job
+    pub(crate) fn read_first(&self) -> Result<Option<TraceFrame>, Box<dyn Error>> {
+        let fd = File::open(&self.path)?;
+
+        let offset = match self.format {
+            TraceFormat::V1 => 0,
+            TraceFormat::V2 => match self.blocks.first() {
+                Some(b) => b.data(),
+                None => return Ok(None),
+            },
+        };
+
+        let (frame, _) = TraceFile::read_frame_at(&fd, offset)?;
+        Ok(frame)
+    }
+
+    /// Timestamp of the last frame, v1 traces are scanned
+    pub(crate) fn last_ts(&self) -> Result<Option<f64>, Box<dyn Error>> {
+        match self.format {
+            TraceFormat::V1 => {
+                let fd = File::open(&self.path)?;
+                let off = TraceFile::offset_of_last_frame_start(&fd)?;
+                let (frame, _) = TraceFile::read_frame_at(&fd, off)?;
+                Ok(frame.map(|f| f.ts()))
+            }
+            TraceFormat::V2 => Ok(self.blocks.last().map(|b| b.end)),
+        }
+    }
+
+    pub(crate) fn read_all(&self) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
+        match self.format {
+            TraceFormat::V1 => {
+                let fd = File::open(&self.path)?;
+                let mut frames = Vec::new();
+                let mut offset: u64 = 0;
+
+                loop {
+                    match TraceFile::read_frame_at(&fd, offset)? {
+                        (Some(frame), next) => {
+                            frames.push(frame);
+                            offset = next;
+                        }
+                        (None, _) => return Ok(frames),
+                    }
+                }
+            }
+            TraceFormat::V2 => self.read_blocks(|_| true),
+        }
+    }
+
+    /// Frames of the blocks overlapping a window in seconds along with
+    /// the ones describing the job and the counters, v1 traces are read whole
+    #[allow(unused)]
+    pub(crate) fn read_window(
+        &self,
+        from: f64,
+        to: f64,
+    ) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
+        match self.format {
+            TraceFormat::V1 => self.read_all(),
+            TraceFormat::V2 => {
+                self.read_blocks(|b| b.flags & BLOCK_HAS_METADATA != 0 || b.overlaps(from, to))
+            }
+        }
+    }
+
+    /***********
+     * WRITING *
+     ***********/
+
+    /// The next frame goes to a new block, blocks hold either metadata or
+    /// counters so that reading a window does not drag the other kind along
+    fn needs_block(&self, pending: usize, frame: &TraceFrame) -> bool {
+        match (self.blocks.last(), &self.crc) {
+            (Some(b), Some(_)) => {
+                b.len + pending as u64 >= BLOCK_SIZE
+                    || (b.flags & BLOCK_HAS_METADATA != 0) == frame.is_counters()
+            }
+            _ => true,
+        }
+    }
+
+    /// Write pending frames at the end of the last block and update its header
+    fn flush(&mut self, fd: &File, pending: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let (block, crc) = match (self.blocks.last_mut(), self.crc.as_mut()) {
+            (Some(b), Some(c)) => (b, c),
+            _ => return Err(ProxyErr::newboxed("No block to write frames to")),
+        };
+
+        /* The header is written after the frames so that it only covers complete ones */
+        fd.write_all_at(pending, block.next())?;
+        crc.update(pending);
+        block.len += pending.len() as u64;
+        block.crc = crc.clone().finalize();
+        fd.write_all_at(&block.encode(), block.offset)?;
+
+        pending.clear();
+
+        Ok(())
+    }
+
+    fn start_block(&mut self, fd: &File, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
+        let offset = self.blocks.last().map_or(HEADER_SIZE, |b| b.next());
+
+        /* Drop what an interrupted write may have left after the last block */
+        if fd.metadata()?.len() > offset {
+            fd.set_len(offset)?;
+        }
+
+        self.index_last()?;
+
+        self.blocks.push(Block {
+            offset,
+            flags: if frame.is_counters() {
+                0
+            } else {
+                BLOCK_HAS_METADATA
+            },
+            len: 0,
+            frames: 0,
+            crc: 0,
+            start: frame.ts(),
+            end: frame.ts(),
+        });
+        self.crc = Some(crc32fast::Hasher::new());
+
+        Ok(())
+    }
+
+    fn append_v2(&mut self, frames: &[TraceFrame]) -> Result<(), Box<dyn Error>> {
+        let fd = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut pending: Vec<u8> = Vec::new();
+
+        for f in frames.iter() {
+            if self.needs_block(pending.len(), f) {
+                self.flush(&fd, &mut pending)?;
+                self.start_block(&fd, f)?;
+            }
+
+            pending.extend(TraceFile::encode_frame(f)?);
+
+            if let Some(b) = self.blocks.last_mut() {
+                b.frames += 1;
+                b.start = b.start.min(f.ts());
+                b.end = b.end.max(f.ts());
+            }
+        }
+
+        self.flush(&fd, &mut pending)
+    }
+
+    /// Append frames to the trace, returns its new size
+    pub(crate) fn append(&mut self, frames: &[TraceFrame]) -> Result<u64, Box<dyn Error>> {
+        match self.format {
+            TraceFormat::V1 => {
+                let mut fd = OpenOptions::new().append(true).open(&self.path)?;
+                for f in frames.iter() {
+                    fd.write_all(&TraceFile::encode_frame(f)?)?;
+                }
+            }
+            TraceFormat::V2 => self.append_v2(frames)?,
+        }
+
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxywireprotocol::JobDesc;
+
+    /// Counter frames per trace, enough for several blocks
+    const FRAMES: usize = 30000;
+
+    fn trace_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("proxy-tracefile-{}-{}", std::process::id(), name));
+        TraceFile::remove(&path).unwrap();
+        path
+    }
+
+    fn frames() -> Vec<TraceFrame> {
+        let desc = JobDesc {
+            jobid: "1234".to_string(),
+            command: "test".to_string(),
+            size: 1,
+            nodelist: "node0".to_string(),
+            partition: String::new(),
+            cluster: String::new(),
+            run_dir: String::new(),
+            start_time: 0,
+            end_time: 0,
+            user: String::new(),
+            pid: 0,
+            accounting: None,
+        };
+
+        let mut ret = vec![TraceFrame::Desc { ts: 0.0, desc }];
+        ret.extend((1..=FRAMES).map(|i| TraceFrame::Counters {
+            ts: i as f64,
+            counters: Vec::new(),
+        }));
+        ret
+    }
+
+    fn counters(frames: &[TraceFrame]) -> usize {
+        frames.iter().filter(|f| f.is_counters()).count()
+    }
+
+    fn v2_trace(name: &str) -> (PathBuf, TraceFile) {
+        let path = trace_path(name);
+        let mut trace = TraceFile::create(&path).unwrap();
+        trace.append(&frames()).unwrap();
+
+        assert!(trace.blocks.len() >= 4);
+        assert_eq!(trace.blocks[0].flags, BLOCK_HAS_METADATA);
+
+        (path, trace)
+    }
+
+    #[test]
+    fn read_v1() {
+        let path = trace_path("v1");
+
+        let mut data: Vec<u8> = Vec::new();
+        for f in frames().iter() {
+            data.extend(TraceFile::encode_frame(f).unwrap());
+        }
+        fs::write(&path, data).unwrap();
+
+        let mut trace = TraceFile::open(&path).unwrap();
+        assert_eq!(trace.format(), TraceFormat::V1);
+        assert!(matches!(
+            trace.read_first().unwrap(),
+            Some(TraceFrame::Desc { .. })
+        ));
+        assert_eq!(trace.last_ts().unwrap(), Some(FRAMES as f64));
+        assert_eq!(counters(&trace.read_all().unwrap()), FRAMES);
+
+        /* Windows of v1 traces are the whole trace */
+        assert_eq!(counters(&trace.read_window(10.0, 20.0).unwrap()), FRAMES);
+
+        trace.append(&frames()[1..2]).unwrap();
+        assert_eq!(counters(&trace.read_all().unwrap()), FRAMES + 1);
+
+        TraceFile::remove(&path).unwrap();
+    }
+
+    #[test]
+    fn rebuild_corrupted_index() {
+        let (path, trace) = v2_trace("index");
+        let blocks = trace.blocks.len();
+        drop(trace);
+
+        fs::write(TraceFile::index_path(&path), b"not an index at all").unwrap();
+
+        let trace = TraceFile::open(&path).unwrap();
+        assert_eq!(trace.blocks.len(), blocks);
+        assert_eq!(counters(&trace.read_all().unwrap()), FRAMES);
+
+        /* The rebuilt index lists all the blocks but the last */
+        let index = fs::read(TraceFile::index_path(&path)).unwrap();
+        assert_eq!(index.len(), (blocks - 1) * INDEX_ENTRY_SIZE);
+
+        TraceFile::remove(&path).unwrap();
+    }
+
+    #[test]
+    fn rebuild_index_after_truncation() {
+        let (path, trace) = v2_trace("truncated");
+        let kept = trace.blocks[1];
+        drop(trace);
+
+        /* Leave a torn header after the second block */
+        let fd = OpenOptions::new().write(true).open(&path).unwrap();
+        fd.set_len(kept.next() + BLOCK_HEADER_SIZE / 2).unwrap();
+        drop(fd);
+
+        let mut trace = TraceFile::open(&path).unwrap();
+        assert_eq!(trace.blocks.len(), 2);
+        assert_eq!(counters(&trace.read_all().unwrap()), kept.frames as usize);
+
+        /* Appending replaces the torn block */
+        trace.append(&frames()[1..11]).unwrap();
+        assert_eq!(trace.len().unwrap(), trace.blocks[2].next());
+        assert_eq!(
+            counters(&trace.read_all().unwrap()),
+            kept.frames as usize + 10
+        );
+
+        TraceFile::remove(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_bad_checksum() {
+        let (path, trace) = v2_trace("checksum");
+        let bad = trace.blocks[2];
+        drop(trace);
+
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut byte: [u8; 1] = [0];
+        fd.read_exact_at(&mut byte, bad.data() + 4).unwrap();
+        byte[0] ^= 0xff;
+        fd.write_all_at(&byte, bad.data() + 4).unwrap();
+        drop(fd);
+
+        let trace = TraceFile::open(&path).unwrap();
+        assert_eq!(
+            counters(&trace.read_all().unwrap()),
+            FRAMES - bad.frames as usize
+        );
+        assert!(trace
+            .read_window(bad.start, bad.end)
+            .unwrap()
+            .iter()
+            .all(|f| !f.is_counters()));
+
+        TraceFile::remove(&path).unwrap();
+    }
+
+    #[test]
+    fn read_window_blocks() {
+        let (path, trace) = v2_trace("window");
+        let block = trace.blocks[2];
+        drop(trace);
+
+        let trace = TraceFile::open(&path).unwrap();
+
+        /* A window inside a block only reads it along with the metadata */
+        let frames = trace
+            .read_window(block.start + 1.0, block.end - 1.0)
+            .unwrap();
+        assert!(matches!(frames[0], TraceFrame::Desc { .. }));
+        assert_eq!(counters(&frames), block.frames as usize);
+        assert!(frames
+            .iter()
+            .filter(|f| f.is_counters())
+            .all(|f| block.start <= f.ts() && f.ts() <= block.end));
+
+        /* Windows across blocks read all of them */
+        let next = trace.blocks[3];
+        let frames = trace.read_window(block.end, next.start).unwrap();
+        assert_eq!(counters(&frames), (block.frames + next.frames) as usize);
+
+        /* Windows past the end only get the metadata */
+        let frames = trace.read_window(2.0 * FRAMES as f64, f64::MAX).unwrap();
+        assert_eq!(frames.len(), 1);
+
+        TraceFile::remove(&path).unwrap();
+    }
+}
@@ -1,5 +1,11 @@
+use crate::alarms::{
+    deserialize_duration, AlarmHistoryEntry, AlarmHistoryFilter, AlarmSeverity, AlarmSpec,
+    AlarmTemplate,
+};
 use crate::proxy_common::{self, gen_range, ProxyErr};
-use crate::proxywireprotocol::{self, ApiResponse, CounterSnapshot, CounterType, JobProfile};
+use crate::proxywireprotocol::{
+    self, ApiResponse, CounterSnapshot, CounterType, DeltaBatch, JobProfile, TopoStatus,
+};
 use crate::{
     exporter::{Exporter, ExporterFactory},
     proxy_common::{concat_slices, derivate_time_serie, hostname, parse_bool},
@@ -7,15 +13,20 @@ use crate::{
 
 use colored::Colorize;
 use rouille::input::json::JsonError;
-use rouille::{Request, Response};
+use rouille::{Request, Response, ResponseBody};
 use serde::Deserialize;
 use static_files::Resource;
 use std::collections::HashMap;
+use std::io::{self, Read};
 use std::path::Path;
+use std::time::Duration;
 
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
 
+use crate::auth::{Auth, Scope};
 use crate::squeue;
+use crate::topology::Topology;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
@@ -23,40 +34,120 @@ include!(concat!(env!("OUT_DIR"), "/generated.rs"));
  * WEBSERVER *
  *************/
 
-struct ClientPivot {
-    url: String,
-    refcount: u32,
-    child: Vec<String>,
+/// Interval of the comments keeping idle event streams open
+const EVENT_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Size of the chunks of the chunked encoder of tiny_http which only sends
+/// a chunk once the next one starts
+const EVENT_STREAM_CHUNK: usize = 8192;
+
+/// Alarm transitions as Server-Sent Events read as the chunked body of the
+/// response, each event is padded to the end of a chunk and followed by the
+/// first byte of the next one so that it is sent right away
+struct AlarmEventStream {
+    rx: Receiver<AlarmHistoryEntry>,
+    filter: AlarmHistoryFilter,
+    keepalive: Duration,
+    /// Current event and how much of it was read
+    pending: Vec<u8>,
+    offset: usize,
+    /// Bytes of the body so far
+    sent: usize,
 }
 
-impl ClientPivot {
-    fn new(url: String) -> ClientPivot {
-        ClientPivot {
-            url,
-            refcount: 0,
-            child: Vec::new(),
-        }
+impl AlarmEventStream {
+    fn new(
+        rx: Receiver<AlarmHistoryEntry>,
+        filter: AlarmHistoryFilter,
+        keepalive: Duration,
+    ) -> AlarmEventStream {
+        let mut stream = AlarmEventStream {
+            rx,
+            filter,
+            keepalive,
+            pending: Vec::new(),
+            offset: 0,
+            sent: 0,
+        };
+
+        /* Tell the client how long to wait before reconnecting */
+        stream.queue(b"retry: 5000\n\n");
+
+        stream
     }
 
-    fn mapto(&mut self, child_url: String) {
-        self.refcount += 1;
-        self.child.push(child_url);
+    /// Stream the events until the client disconnects and writes fail
+    fn into_response(self) -> Response {
+        Response {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".into(), "text/event-stream".into()),
+                ("Cache-Control".into(), "no-cache".into()),
+                ("X-Accel-Buffering".into(), "no".into()),
+            ],
+            data: ResponseBody::from_reader(self),
+            upgrade: None,
+        }
     }
 
-    fn is_partial(&self) -> bool {
-        if (self.refcount < 2) && (1 < self.refcount) {
-            return true;
+    /// Queue an event padded with a comment up to the end of its chunk
+    fn queue(&mut self, event: &[u8]) {
+        let mut data = event.to_vec();
+
+        let fill = (EVENT_STREAM_CHUNK - (self.sent + data.len()) % EVENT_STREAM_CHUNK)
+            % EVENT_STREAM_CHUNK;
+
+        match fill {
+            0 => (),
+            1 => data.push(b'\n'),
+            _ => {
+                data.push(b':');
+                data.resize(data.len() + fill - 2, b' ');
+                data.push(b'\n');
+            }
         }
 
-        false
+        /* An empty line, it dispatches nothing as the event was already */
+        data.push(b'\n');
+
+        self.sent += data.len();
+        self.pending = data;
+        self.offset = 0;
     }
 
-    fn is_free(&self) -> bool {
-        if self.refcount < 2 {
-            return true;
+    /// Wait for the next event, None when alarms are not published anymore
+    fn next_event(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.rx.recv_timeout(self.keepalive) {
+                Ok(e) if !self.filter.matches(&e) => continue,
+                Ok(e) => {
+                    return Ok(Some(
+                        format!("event: alarm\ndata: {}\n\n", serde_json::to_string(&e)?)
+                            .into_bytes(),
+                    ))
+                }
+                /* Comments keep the connection alive and detect closed ones */
+                Err(RecvTimeoutError::Timeout) => return Ok(Some(b": keepalive\n\n".to_vec())),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Read for AlarmEventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.pending.len() {
+            match self.next_event()? {
+                Some(e) => self.queue(&e),
+                None => return Ok(0),
+            }
         }
 
-        false
+        let len = buf.len().min(self.pending.len() - self.offset);
+        buf[..len].copy_from_slice(&self.pending[self.offset..self.offset + len]);
+        self.offset += len;
+
+        Ok(len)
     }
 }
 
@@ -64,7 +155,7 @@ pub(crate) struct Web {
     port: u32,
     factory: Arc<ExporterFactory>,
     static_files: HashMap<String, Resource>,
-    known_client: Mutex<Vec<ClientPivot>>,
+    topology: Arc<Topology>,
 }
 
 enum WebResponse {
@@ -78,6 +169,8 @@ enum WebResponse {
     Redirect302(String),
     Native(Response),
     NoSuchDoc(),
+    /// Missing (401) or insufficient (403) credentials
+    Denied(u16, String),
 }
 
 impl WebResponse {
@@ -104,6 +197,18 @@ impl WebResponse {
                 Response::json(&r)
             }
             WebResponse::NoSuchDoc() => Response::empty_404(),
+            WebResponse::Denied(code, operation) => {
+                let r = ApiResponse {
+                    operation,
+                    success: false,
+                };
+                let resp = Response::json(&r).with_status_code(code);
+                if code == 401 {
+                    resp.with_additional_header("WWW-Authenticate", "Bearer")
+                } else {
+                    resp
+                }
+            }
             WebResponse::Redirect302(url) => Response::redirect_302(url),
             WebResponse::Native(response) => response,
         }
@@ -111,26 +216,16 @@ impl WebResponse {
 }
 
 impl Web {
-    pub(crate) fn new(port: u32, factory: Arc<ExporterFactory>) -> Web {
-        let web = Web {
+    pub(crate) fn new(port: u32, factory: Arc<ExporterFactory>, topology: Arc<Topology>) -> Web {
+        Web {
             port,
             factory,
             static_files: generate()
                 .into_iter()
                 .map(|(k, v)| (k.to_string(), v))
                 .collect(),
-            known_client: Mutex::new(Vec::new()),
-        };
-        /* Add myself in the URLs */
-        web.known_client
-            .lock()
-            .unwrap()
-            .push(ClientPivot::new(web.url()));
-        web
-    }
-
-    pub(crate) fn url(&self) -> String {
-        format!("{}:{}", hostname(), self.port)
+            topology,
+        }
     }
 
     fn default_doc() -> String {
@@ -383,9 +478,14 @@ impl Web {
             jobid: String,
             filter: String,
             derivate: bool,
+            /// Time window in seconds
+            #[serde(default)]
+            from: Option<f64>,
+            #[serde(default)]
+            to: Option<f64>,
         }
 
-        let (jobid, filter, derivate) = match req.method() {
+        let (jobid, filter, derivate, from, to) = match req.method() {
             "GET" => (
                 req.get_param("jobid"),
                 req.get_param("filter"),
@@ -393,11 +493,13 @@ impl Web {
                     Some(e) => parse_bool(e.as_str()),
                     None => false,
                 },
+                req.get_param("from").and_then(|v| v.parse::<f64>().ok()),
+                req.get_param("to").and_then(|v| v.parse::<f64>().ok()),
             ),
             "POST" => {
                 let sel: Result<Plotdef, JsonError> = rouille::input::json_input(req);
                 match sel {
-                    Ok(e) => (Some(e.jobid), Some(e.filter), e.derivate),
+                    Ok(e) => (Some(e.jobid), Some(e.filter), e.derivate, e.from, e.to),
                     Err(_) => {
                         return WebResponse::BadReq(
                             "Failed to parse plot POST request".to_string(),
@@ -405,7 +507,12 @@ impl Web {
                     }
                 }
             }
-            _ => (None, None, false),
+            _ => (None, None, false, None, None),
+        };
+
+        let window = match (from, to) {
+            (None, None) => None,
+            (from, to) => Some((from.unwrap_or(0.0), to.unwrap_or(f64::INFINITY))),
         };
 
         if filter.is_none() {
@@ -413,7 +520,11 @@ impl Web {
         }
 
         if let Some(jobid) = jobid {
-            match self.factory.trace_store.plot(&jobid, filter.unwrap()) {
+            match self
+                .factory
+                .trace_store
+                .plot(&jobid, filter.unwrap(), window)
+            {
                 Ok(data) => {
                     /* Do we need to derivate ? */
                     let fdata = if derivate {
@@ -456,8 +567,25 @@ impl Web {
             None => 1000,
         };
 
-        if let Err(e) = ExporterFactory::add_scrape(self.factory.clone(), &to, period) {
-            return WebResponse::BadReq(format!("Failed to add {} for scraping : {}", to, e));
+        let heartbeat = req.get_param("heartbeat").is_some_and(|v| parse_bool(&v));
+
+        /* The child pushes its changes to /topo/forward */
+        if heartbeat && req.get_param("push").is_some_and(|v| parse_bool(&v)) {
+            self.topology.adopt(&to, None, period);
+            return WebResponse::Success(format!("Accepting changes pushed by {}", to));
+        }
+
+        let key = match ExporterFactory::add_labeled_scrape(self.factory.clone(), &to, period, &[])
+        {
+            Ok(k) => k,
+            Err(e) => {
+                return WebResponse::BadReq(format!("Failed to add {} for scraping : {}", to, e))
+            }
+        };
+
+        /* Child proxies of the reduction tree send heartbeats */
+        if heartbeat {
+            self.topology.adopt(&to, Some(key), period);
         }
 
         WebResponse::Success(format!("Added {} for scraping", to))
@@ -478,50 +606,78 @@ impl Web {
             );
         }
 
-        let mut clients = self.known_client.lock().unwrap();
-
-        let mut target = clients.iter_mut().find(|v| v.is_partial());
+        /* Orphans report the parent they lost */
+        let lost = req.get_param("lost");
 
-        if target.is_none() {
-            target = clients.iter_mut().find(|v| v.is_free());
+        match self.topology.assign(&from, lost.as_deref()) {
+            Ok(parent) => WebResponse::Success(parent),
+            Err(e) => WebResponse::BadReq(e.to_string()),
         }
+    }
 
-        let resp: WebResponse;
-
-        if let Some(target) = target {
-            target.mapto(from.to_string());
+    fn handle_topo(&self, _req: &Request) -> WebResponse {
+        WebResponse::Native(Response::json(&self.topology.edges()))
+    }
 
-            log::info!(
-                "Pivot response to {} is {} with ref {}",
-                from,
-                target.url,
-                target.refcount
-            );
+    fn handle_topo_tree(&self, _req: &Request) -> WebResponse {
+        WebResponse::Native(Response::json(&self.topology.status()))
+    }
 
-            resp = WebResponse::Success(target.url.to_string());
+    fn handle_topo_heartbeat(&self, req: &Request) -> WebResponse {
+        /* Children POST the status of their subtree along with the heartbeat */
+        let report: Option<TopoStatus> = if req.method() == "POST" {
+            match rouille::input::json_input(req) {
+                Ok(r) => Some(r),
+                Err(e) => return WebResponse::BadReq(format!("Failed to parse status : {}", e)),
+            }
         } else {
-            resp = WebResponse::BadReq("Did not match any server".to_string());
-        }
-
-        clients.push(ClientPivot::new(from));
+            None
+        };
 
-        resp
+        match req.get_param("from") {
+            Some(from) => {
+                if self.topology.heartbeat(&from, report) {
+                    WebResponse::Success(format!("Heartbeat from {}", from))
+                } else {
+                    WebResponse::BadReq(format!("{} is not a child of this proxy", from))
+                }
+            }
+            None => WebResponse::BadReq("No from parameter passed".to_string()),
+        }
     }
 
-    fn handle_topo(&self, _req: &Request) -> WebResponse {
-        let mut resp: Vec<(String, String)> = Vec::new();
+    fn handle_topo_forward(&self, req: &Request) -> WebResponse {
+        let batch: Result<DeltaBatch, JsonError> = rouille::input::json_input(req);
 
-        for c in self.known_client.lock().unwrap().iter() {
-            for t in &c.child {
-                resp.push((c.url.clone(), t.clone()))
+        match batch {
+            Ok(batch) => {
+                let (source, seq) = (batch.source.to_string(), batch.seq);
+                match self.factory.receive_forwarded(batch) {
+                    Ok(true) => {
+                        WebResponse::Success(format!("Applied batch {} of {}", seq, source))
+                    }
+                    Ok(false) => WebResponse::BadReq(format!(
+                        "Batch {} of {} is out of sequence, send a snapshot",
+                        seq, source
+                    )),
+                    Err(e) => WebResponse::BadReq(format!(
+                        "Failed to apply batch {} of {} : {}",
+                        seq, source, e
+                    )),
+                }
             }
+            Err(e) => WebResponse::BadReq(format!("Failed to parse batch : {}", e)),
         }
+    }
 
-        if resp.is_empty() {
-            resp.push((self.url().to_string(), self.url().to_string()));
+    fn handle_topo_leave(&self, req: &Request) -> WebResponse {
+        match req.get_param("node") {
+            Some(node) => {
+                self.topology.leave(&node);
+                WebResponse::Success(format!("Removed {} from the tree", node))
+            }
+            None => WebResponse::BadReq("No node parameter passed".to_string()),
         }
-
-        WebResponse::Native(Response::json(&resp))
     }
 
     fn handle_job(&self, req: &Request) -> WebResponse {
@@ -537,6 +693,18 @@ impl Web {
         }
     }
 
+    fn handle_job_delta(&self, req: &Request) -> WebResponse {
+        let since = match req.get_param("since").map(|v| v.parse::<u64>()) {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => return WebResponse::BadReq(format!("Failed to parse since : {}", e)),
+            None => 0,
+        };
+
+        let epoch = req.get_param("epoch").and_then(|v| v.parse::<u64>().ok());
+
+        WebResponse::Native(Response::json(&self.factory.changes(since, epoch)))
+    }
+
     fn handle_joblist(&self, _req: &Request) -> WebResponse {
         let jobs = self.factory.list_jobs();
 
@@ -556,34 +724,75 @@ impl Web {
         struct AlarmDef {
             name: String,
             target: String,
-            metric: String,
-            operation: String,
-            value: f64,
+            /* Either a rule */
+            rule: Option<String>,
+            /* Or a single threshold on a metric */
+            metric: Option<String>,
+            operation: Option<String>,
+            value: Option<f64>,
+            #[serde(default, rename = "for", deserialize_with = "deserialize_duration")]
+            pending: f64,
+            clear: Option<String>,
+            #[serde(default)]
+            severity: AlarmSeverity,
+            /* Also register in all child proxies */
+            #[serde(default)]
+            propagate: bool,
         }
 
         let al: Result<AlarmDef, JsonError> = rouille::input::json_input(req);
 
-        match al {
-            Ok(def) => {
-                match self.factory.add_alarm(
-                    def.name,
-                    def.target,
-                    def.metric,
-                    def.operation,
-                    def.value,
-                ) {
-                    Ok(_) => WebResponse::Success("alarm registered".to_string()),
-                    Err(e) => WebResponse::BadReq(e.to_string()),
-                }
+        let def = match al {
+            Ok(def) => def,
+            Err(e) => {
+                return WebResponse::BadReq(e.to_string());
+            }
+        };
+
+        let rule = match (def.rule, def.metric, def.operation, def.value) {
+            (Some(rule), _, _, _) => rule,
+            (None, Some(metric), Some(operation), Some(value)) => {
+                AlarmSpec::threshold_rule(&metric, &operation, value)
+            }
+            _ => {
+                return WebResponse::BadReq(
+                    "An alarm needs either a 'rule' or a 'metric', 'operation' and 'value'"
+                        .to_string(),
+                );
             }
+        };
+
+        let spec = AlarmSpec {
+            name: def.name,
+            rule,
+            pending: def.pending,
+            clear: def.clear,
+            severity: def.severity,
+        };
+
+        if def.propagate {
+            return match self.factory.propagate_alarm(def.target, spec) {
+                Ok(pushed) => WebResponse::Success(format!(
+                    "alarm registered and pushed to {} child proxies",
+                    pushed
+                )),
+                Err(e) => WebResponse::BadReq(e.to_string()),
+            };
+        }
+
+        match self.factory.add_alarm(def.target, spec) {
+            Ok(true) => WebResponse::Success("alarm registered".to_string()),
+            Ok(false) => WebResponse::Success(
+                "alarm registered but unbound, it is evaluated once its metrics exist".to_string(),
+            ),
             Err(e) => WebResponse::BadReq(e.to_string()),
         }
     }
 
     fn handle_del_alarms(&self, req: &Request) -> WebResponse {
-        let (tjob, to_del) = match req.method() {
+        let (tjob, to_del, propagate) = match req.method() {
             "GET" => match (req.get_param("targetjob"), req.get_param("name")) {
-                (Some(t), Some(v)) => (t, v),
+                (Some(t), Some(v)) => (t, v, req.get_param("propagate").is_some()),
                 _ => {
                     return WebResponse::BadReq("Missing 'name' GET parameter".to_string());
                 }
@@ -593,10 +802,12 @@ impl Web {
                 struct ToDel {
                     target: String,
                     name: String,
+                    #[serde(default)]
+                    propagate: bool,
                 }
                 let al: Result<ToDel, JsonError> = rouille::input::json_input(req);
                 match al {
-                    Ok(v) => (v.target, v.name),
+                    Ok(v) => (v.target, v.name, v.propagate),
                     Err(e) => {
                         return WebResponse::BadReq(format!("Failed to parse json {}", e));
                     }
@@ -607,7 +818,13 @@ impl Web {
             }
         };
 
-        if let Err(e) = self.factory.delete_alarm(&tjob, &to_del) {
+        let ret = if propagate {
+            self.factory.delete_propagated_alarm(&tjob, &to_del)
+        } else {
+            self.factory.delete_alarm(&tjob, &to_del)
+        };
+
+        if let Err(e) = ret {
             WebResponse::BadReq(format!("Failed to delete {}", e))
         } else {
             WebResponse::Success(format!("Deleted {} from {}", to_del, tjob))
@@ -619,6 +836,93 @@ impl Web {
         WebResponse::Native(Response::json(&alarms))
     }
 
+    fn alarm_history_filter(req: &Request) -> AlarmHistoryFilter {
+        AlarmHistoryFilter {
+            job: req.get_param("job"),
+            name: req.get_param("name"),
+            since: req.get_param("since").and_then(|v| v.parse::<u64>().ok()),
+            until: req.get_param("until").and_then(|v| v.parse::<u64>().ok()),
+            limit: req.get_param("limit").and_then(|v| v.parse::<usize>().ok()),
+        }
+    }
+
+    fn handle_alarm_history(&self, req: &Request) -> WebResponse {
+        let filter = Web::alarm_history_filter(req);
+        let history = self.factory.alarm_history(&filter);
+        WebResponse::Native(Response::json(&history))
+    }
+
+    fn handle_alarm_stream(&self, req: &Request) -> WebResponse {
+        let stream = AlarmEventStream::new(
+            self.factory.subscribe_alarms(),
+            Web::alarm_history_filter(req),
+            EVENT_STREAM_KEEPALIVE,
+        );
+
+        WebResponse::Native(stream.into_response())
+    }
+
+    fn handle_list_alarm_templates(&self, _: &Request) -> WebResponse {
+        let templates = self.factory.list_alarm_templates();
+        WebResponse::Native(Response::json(&templates))
+    }
+
+    fn handle_add_alarm_template(&self, req: &Request) -> WebResponse {
+        let template: Result<AlarmTemplate, JsonError> = rouille::input::json_input(req);
+
+        let template = match template {
+            Ok(t) => t,
+            Err(e) => {
+                return WebResponse::BadReq(e.to_string());
+            }
+        };
+
+        match self.factory.add_alarm_template(template) {
+            Ok(_) => WebResponse::Success("alarm template registered".to_string()),
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    fn handle_del_alarm_template(&self, req: &Request) -> WebResponse {
+        let to_del = match req.method() {
+            "GET" => match req.get_param("name") {
+                Some(v) => v,
+                _ => {
+                    return WebResponse::BadReq("Missing 'name' GET parameter".to_string());
+                }
+            },
+            "POST" => {
+                #[derive(Deserialize)]
+                struct ToDel {
+                    name: String,
+                }
+                let al: Result<ToDel, JsonError> = rouille::input::json_input(req);
+                match al {
+                    Ok(v) => v.name,
+                    Err(e) => {
+                        return WebResponse::BadReq(format!("Failed to parse json {}", e));
+                    }
+                }
+            }
+            _ => {
+                return WebResponse::BadReq("No such request type".to_string());
+            }
+        };
+
+        if let Err(e) = self.factory.delete_alarm_template(&to_del) {
+            WebResponse::BadReq(format!("Failed to delete {}", e))
+        } else {
+            WebResponse::Success(format!("Deleted alarm template {}", to_del))
+        }
+    }
+
+    fn handle_config_reload(&self, _: &Request) -> WebResponse {
+        match ExporterFactory::reload_config(self.factory.clone()) {
+            Ok(report) => WebResponse::Native(Response::json(&report)),
+            Err(e) => WebResponse::BadReq(format!("Failed to reload configuration : {}", e)),
+        }
+    }
+
     fn handle_list_profiles(&self, _: &Request) -> WebResponse {
         let prof = self.factory.profile_store.get_profile_list();
         WebResponse::Native(Response::json(&prof))
@@ -907,15 +1211,63 @@ impl Web {
         (prefix, resource)
     }
 
+    /// Scope needed by an endpoint, None if it is public
+    fn required_scope(prefix: &str, resource: &str) -> Option<Scope> {
+        match (prefix, resource) {
+            /* Used to detect proxies before scraping them */
+            (_, "is_admire_proxy.html") => None,
+            ("set", _)
+            | ("accumulate", _)
+            | ("push", _)
+            | ("pivot", _)
+            | ("topo", "heartbeat")
+            | ("topo", "leave")
+            | ("topo", "forward")
+            | ("join", "")
+            | ("alarms", "add")
+            | ("alarms", "del")
+            | ("alarms/templates", "add")
+            | ("alarms/templates", "del")
+            | ("config", "reload") => Some(Scope::Admin),
+            _ => Some(Scope::Read),
+        }
+    }
+
+    fn check_scope(
+        request: &Request,
+        body: &[u8],
+        prefix: &str,
+        resource: &str,
+    ) -> Option<WebResponse> {
+        let required = Web::required_scope(prefix, resource)?;
+
+        match Auth::get().scope(request, body) {
+            Some(scope) if scope >= required => None,
+            Some(_) => Some(WebResponse::Denied(
+                403,
+                format!("{} requires the {:?} scope", request.url(), required),
+            )),
+            None => Some(WebResponse::Denied(
+                401,
+                format!("{} requires authentication", request.url()),
+            )),
+        }
+    }
+
     pub(crate) fn run_blocking(self) {
         let hostname = hostname();
+        let tls = Auth::get().tls().cloned();
+
         log::info!(
-            "Proxy webserver listening on http://{}:{}",
+            "Proxy webserver listening on {}://{}:{}",
+            if tls.is_some() { "https" } else { "http" },
             hostname,
             self.port
         );
 
-        rouille::start_server(format!("0.0.0.0:{}", self.port), move |request| {
+        let addr = format!("0.0.0.0:{}", self.port);
+
+        let handler = move |request: &Request| {
             let url = request.url();
 
             let (prefix, resource) = Web::parse_url(&url);
@@ -927,6 +1279,18 @@ impl Web {
                 resource.yellow()
             );
 
+            /* Signatures cover the body which can only be read once */
+            let signed = Auth::buffer_signed(request);
+            let (request, body) = match &signed {
+                Some((copy, body)) => (copy, body.as_slice()),
+                None => (request, &[][..]),
+            };
+
+            if let Some(denied) = Web::check_scope(request, body, &prefix, &resource) {
+                log::debug!("Denied {} {}", request.method(), request.raw_url());
+                return denied.serialize();
+            }
+
             let resp: WebResponse = match prefix.as_str() {
                 "/" => self.serve_static_file("/index.html"),
                 "set" => self.handle_set(request),
@@ -935,6 +1299,7 @@ impl Web {
                 "metrics" => self.handle_metrics(request),
                 "job" => match resource.as_str() {
                     "list" => self.handle_joblist(request),
+                    "delta" => self.handle_job_delta(request),
                     "" => self.handle_job(request),
                     _ => WebResponse::BadReq(url),
                 },
@@ -967,7 +1332,14 @@ impl Web {
                     _ => WebResponse::BadReq(url),
                 },
                 "pivot" => self.handle_pivot(request),
-                "topo" => self.handle_topo(request),
+                "topo" => match resource.as_str() {
+                    "" => self.handle_topo(request),
+                    "heartbeat" => self.handle_topo_heartbeat(request),
+                    "tree" => self.handle_topo_tree(request),
+                    "leave" => self.handle_topo_leave(request),
+                    "forward" => self.handle_topo_forward(request),
+                    _ => WebResponse::BadReq(url),
+                },
                 "join" => match resource.as_str() {
                     "" => self.handle_join(request),
                     "list" => self.handle_join_list(request),
@@ -978,12 +1350,158 @@ impl Web {
                     "add" => self.handle_add_alarms(request),
                     "del" => self.handle_del_alarms(request),
                     "list" => self.handle_list_alarms(request),
+                    "templates" => self.handle_list_alarm_templates(request),
+                    "history" => self.handle_alarm_history(request),
+                    "stream" => self.handle_alarm_stream(request),
+                    _ => WebResponse::BadReq(url),
+                },
+                "config" => match resource.as_str() {
+                    "reload" => self.handle_config_reload(request),
+                    _ => WebResponse::BadReq(url),
+                },
+                "alarms/templates" => match resource.as_str() {
+                    "add" => self.handle_add_alarm_template(request),
+                    "del" => self.handle_del_alarm_template(request),
+                    "list" => self.handle_list_alarm_templates(request),
                     _ => WebResponse::BadReq(url),
                 },
                 _ => self.serve_static_file(url.as_str()),
             };
 
             resp.serialize()
-        });
+        };
+
+        match tls {
+            Some((cert, key)) => match rouille::Server::new_ssl(addr, handler, cert, key) {
+                Ok(server) => server.run(),
+                Err(e) => {
+                    log::error!("Failed to start the HTTPS webserver : {}", e);
+                    std::process::exit(1);
+                }
+            },
+            None => rouille::start_server(addr, handler),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarms::AlarmState;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Instant;
+
+    fn firing(name: &str) -> AlarmHistoryEntry {
+        AlarmHistoryEntry {
+            ts: 1000,
+            job: "1234".to_string(),
+            host: "node0".to_string(),
+            name: name.to_string(),
+            previous: AlarmState::Inactive,
+            state: AlarmState::Firing,
+            duration: None,
+            value: Some(2.0),
+            rule: ">".to_string(),
+            severity: AlarmSeverity::default(),
+        }
+    }
+
+    /// Content of the next chunk of a chunked body
+    fn read_chunk<R: BufRead>(reader: &mut R) -> Vec<u8> {
+        let mut size = String::new();
+        reader.read_line(&mut size).unwrap();
+        let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+
+        let mut data = vec![0; size + 2];
+        reader.read_exact(&mut data).unwrap();
+        assert!(data.ends_with(b"\r\n"));
+        data.truncate(size);
+        data
+    }
+
+    #[test]
+    fn alarm_events_fill_whole_chunks() {
+        let (tx, rx) = channel();
+        let mut stream =
+            AlarmEventStream::new(rx, AlarmHistoryFilter::default(), EVENT_STREAM_KEEPALIVE);
+
+        tx.send(firing("high_load")).unwrap();
+        drop(tx);
+
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).unwrap();
+
+        /* Each event ends a chunk and the next starts with an empty line */
+        assert_eq!(body.len(), 2 * EVENT_STREAM_CHUNK + 1);
+        assert!(body.starts_with(b"retry: 5000\n\n:"));
+
+        let event = &body[EVENT_STREAM_CHUNK..];
+        assert!(event.starts_with(b"\nevent: alarm\ndata: "));
+        assert!(String::from_utf8_lossy(event).contains("high_load"));
+        assert!(event.ends_with(b"\n\n"));
+    }
+
+    #[test]
+    fn alarm_events_are_streamed_by_the_server() {
+        let (tx, rx) = channel();
+        let rx = Mutex::new(Some(rx));
+
+        let server =
+            rouille::Server::new("127.0.0.1:0", move |_| match rx.lock().unwrap().take() {
+                Some(rx) => {
+                    AlarmEventStream::new(rx, AlarmHistoryFilter::default(), EVENT_STREAM_KEEPALIVE)
+                        .into_response()
+                }
+                None => Response::empty_404(),
+            })
+            .unwrap();
+        let addr = server.server_addr();
+        thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /alarms/stream HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        client
+            .set_read_timeout(Some(EVENT_STREAM_KEEPALIVE / 2))
+            .unwrap();
+
+        let mut reader = BufReader::new(client);
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            headers.push(line.trim_end().to_lowercase());
+        }
+
+        assert!(headers[0].starts_with("http/1.1 200"));
+        assert!(headers.contains(&"transfer-encoding: chunked".to_string()));
+        assert!(headers.contains(&"content-type: text/event-stream".to_string()));
+        assert!(!headers
+            .iter()
+            .any(|h| h.starts_with("upgrade") || h.starts_with("connection: upgrade")));
+
+        assert!(read_chunk(&mut reader).starts_with(b"retry: 5000\n\n"));
+
+        let start = Instant::now();
+        tx.send(firing("high_load")).unwrap();
+
+        let event = read_chunk(&mut reader);
+        assert!(event.starts_with(b"\nevent: alarm\ndata: "));
+        assert!(String::from_utf8_lossy(&event).contains("high_load"));
+        assert!(start.elapsed() < EVENT_STREAM_KEEPALIVE);
+
+        /* The body ends with the channel */
+        drop(tx);
+        assert_eq!(read_chunk(&mut reader), b"\n");
+        assert!(read_chunk(&mut reader).is_empty());
     }
 }